@@ -0,0 +1,152 @@
+use crate::errors::TeleportError;
+use crate::sandbox::{Dir, Location};
+use crate::sparse;
+use std::ffi::{OsStr, OsString};
+use std::fs::File;
+use std::io;
+use std::io::{Read, Seek, SeekFrom};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::io::AsRawFd;
+
+/// Longest file name most filesystems accept, in bytes
+const NAME_MAX: usize = 255;
+
+/// Hidden name for a temporary file next to `base`. Long names are cut so
+/// that the temporary name still fits in NAME_MAX, without splitting the
+/// characters of UTF-8 names.
+fn temp_name(base: &OsStr) -> OsString {
+    let suffix = format!(".teleport-{:08x}", rand::random::<u32>());
+    let base = base.as_bytes();
+    let mut keep = base.len().min(NAME_MAX - 1 - suffix.len());
+    if let Ok(s) = std::str::from_utf8(base) {
+        while !s.is_char_boundary(keep) {
+            keep -= 1;
+        }
+    }
+
+    let mut name = Vec::with_capacity(NAME_MAX);
+    name.push(b'.');
+    name.extend_from_slice(&base[..keep]);
+    name.extend_from_slice(suffix.as_bytes());
+    OsString::from_vec(name)
+}
+
+/// A temporary file next to its destination that replaces the destination
+/// only once it is complete. The temporary file is removed if it is dropped
+/// without being persisted.
+#[derive(Debug)]
+pub struct AtomicFile {
+    pub file: File,
+    dir: Dir,
+    name: OsString,
+    dest: OsString,
+    persisted: bool,
+}
+
+impl AtomicFile {
+    pub fn create(dest: &Location) -> io::Result<Self> {
+        loop {
+            let name = temp_name(&dest.name);
+            match dest
+                .dir
+                .open_file(&name, libc::O_RDWR | libc::O_CREAT | libc::O_EXCL, 0o600)
+            {
+                Ok(file) => {
+                    return Ok(AtomicFile {
+                        file,
+                        dir: dest.dir.try_clone()?,
+                        name,
+                        dest: dest.name.clone(),
+                        persisted: false,
+                    })
+                }
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Fill the temporary file with the contents of `orig`, so that a delta
+    /// transfer only has to write the changed chunks. The data is reflinked
+    /// if the filesystem supports it, otherwise the data extents are copied.
+    pub fn clone_from(&mut self, orig: &File) -> Result<(), TeleportError> {
+        let ret = unsafe { libc::ioctl(self.file.as_raw_fd(), libc::FICLONE, orig.as_raw_fd()) };
+        if ret == 0 {
+            return Ok(());
+        }
+
+        let mut src = orig;
+        for (offset, len) in sparse::data_extents(orig)? {
+            src.seek(SeekFrom::Start(offset))?;
+            self.file.seek(SeekFrom::Start(offset))?;
+            io::copy(&mut src.take(len), &mut self.file)?;
+        }
+        self.file.set_len(orig.metadata()?.len())?;
+
+        Ok(())
+    }
+
+    /// Flush the temporary file to disk and move it over the destination.
+    /// The destination is replaced rather than written to, so other hard
+    /// links to it keep the previous contents, and its owner and attributes
+    /// are those of the temporary file.
+    pub fn persist(mut self) -> io::Result<()> {
+        self.file.sync_all()?;
+        self.dir.rename(&self.name, &self.dest)?;
+        self.persisted = true;
+
+        // Make sure the rename itself is durable
+        self.dir.file().sync_all()
+    }
+}
+
+impl AtomicFile {
+    /// Like `persist`, but fail with AlreadyExists instead of replacing an
+    /// existing destination
+    pub fn persist_new(mut self) -> io::Result<()> {
+        self.file.sync_all()?;
+        self.dir.move_new(&self.name, &self.dir, &self.dest)?;
+        self.persisted = true;
+
+        // Make sure the rename itself is durable
+        self.dir.file().sync_all()
+    }
+}
+
+impl Drop for AtomicFile {
+    fn drop(&mut self) {
+        if !self.persisted {
+            let _ = self.dir.remove(&self.name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::TestDir;
+
+    #[test]
+    fn test_temp_name() {
+        let name = temp_name(OsStr::new("report.pdf"));
+        let name = name.to_str().expect("Test should never fail");
+        assert!(name.starts_with(".report.pdf.teleport-"));
+        assert_eq!(name.len(), ".report.pdf.teleport-".len() + 8);
+
+        // A destination of the longest allowed name still gets a temporary file
+        let root = TestDir::create();
+        let long = "x".to_string() + &"é".repeat(NAME_MAX / 2);
+        let dir = Dir::open(&root).expect("Test should never fail");
+        let dest = Location {
+            dir,
+            name: OsString::from(&long),
+            path: long.clone().into(),
+            real: long.into(),
+        };
+        let tmp = AtomicFile::create(&dest).expect("Test should never fail");
+        assert_eq!(tmp.name.len(), NAME_MAX - 1);
+        assert!(tmp.name.to_str().is_some());
+        tmp.persist().expect("Test should never fail");
+        assert!(root.join(&dest.name).exists());
+    }
+}
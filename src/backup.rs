@@ -0,0 +1,230 @@
+use crate::atomic::AtomicFile;
+use crate::errors::TeleportError;
+use crate::sandbox::{Dir, Location};
+use crate::utils;
+use std::ffi::{OsStr, OsString};
+use std::io;
+
+/// How many backups of a file are kept
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Retention {
+    /// Number of generations to keep, unlimited if 0
+    pub keep: usize,
+    /// Age in seconds after which generations are removed
+    pub max_age: Option<u64>,
+}
+
+/// A backup of a file, stored next to it as `<name>.bak.<timestamp>` with a
+/// `-<seq>` suffix if there are several within the same second
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Generation {
+    pub name: OsString,
+    pub time: u64,
+    seq: u32,
+}
+
+/// Back up the file at `loc` as a new generation and remove the generations
+/// that are no longer retained. Returns the name of the backup, or None if
+/// there is no file to back up.
+pub fn create(loc: &Location, retention: &Retention) -> Result<Option<OsString>, TeleportError> {
+    let orig = match loc.dir.open_file(&loc.name, libc::O_RDONLY, 0) {
+        Ok(f) => f,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    // Names are claimed by creating them, so that concurrent backups within
+    // the same second never replace each other
+    let stamp = utils::format_timestamp(utils::now());
+    let mut seq = 0;
+    let name = loop {
+        let name = match seq {
+            0 => backup_name(&loc.name, &stamp),
+            _ => backup_name(&loc.name, &format!("{stamp}-{seq}")),
+        };
+        seq += 1;
+
+        // Files are only ever replaced by renaming a new file over them, so
+        // a hard link keeps the old data without copying it. Otherwise fall
+        // back to a reflink, or a copy.
+        match loc.dir.link(&loc.dir, &loc.name, &name) {
+            Ok(_) => break name,
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(_) => (),
+        }
+        let mut tmp = AtomicFile::create(&Location {
+            dir: loc.dir.try_clone()?,
+            name: name.clone(),
+            path: loc.path.with_file_name(&name),
+            real: loc.real.with_file_name(&name),
+        })?;
+        tmp.clone_from(&orig)?;
+        tmp.file.set_permissions(orig.metadata()?.permissions())?;
+        match tmp.persist_new() {
+            Ok(_) => break name,
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e.into()),
+        }
+    };
+
+    // The backup is complete, old ones that remain only take up space
+    if let Err(e) = prune(&loc.dir, &loc.name, retention) {
+        println!(
+            "Error: unable to remove old backups of {} ({e})",
+            loc.path.display()
+        );
+    }
+
+    Ok(Some(name))
+}
+
+/// List the backups of a file, newest first
+pub fn list(dir: &Dir, name: &OsStr) -> io::Result<Vec<Generation>> {
+    let mut prefix = name.to_os_string();
+    prefix.push(".bak.");
+    let prefix = prefix.to_string_lossy().to_string();
+
+    let mut generations = Vec::new();
+    for entry in dir.list()? {
+        let suffix = match entry.to_str().and_then(|e| e.strip_prefix(&prefix)) {
+            Some(s) => s,
+            None => continue,
+        };
+        if let Some((time, seq)) = parse_suffix(suffix) {
+            generations.push(Generation {
+                name: entry,
+                time,
+                seq,
+            });
+        }
+    }
+    generations.sort_by_key(|g| std::cmp::Reverse((g.time, g.seq)));
+
+    Ok(generations)
+}
+
+/// Remove the backups of a file that are no longer retained
+fn prune(dir: &Dir, name: &OsStr, retention: &Retention) -> io::Result<()> {
+    let now = utils::now();
+    for (i, generation) in list(dir, name)?.iter().enumerate() {
+        let too_many = retention.keep > 0 && i >= retention.keep;
+        let too_old = retention
+            .max_age
+            .map_or(false, |age| now.saturating_sub(generation.time) > age);
+        if too_many || too_old {
+            dir.remove(&generation.name)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether a file name has the form of a backup of some file. Received files
+/// may not be named like this, so that only backups are ever pruned.
+pub fn is_backup(name: &OsStr) -> bool {
+    name.to_str()
+        .and_then(|n| n.rsplit_once(".bak."))
+        .map_or(false, |(base, suffix)| {
+            !base.is_empty() && parse_suffix(suffix).is_some()
+        })
+}
+
+/// Parse the timestamp and sequence number following `.bak.`
+fn parse_suffix(suffix: &str) -> Option<(u64, u32)> {
+    // The timestamp has a fixed length, followed by an optional sequence
+    let (stamp, seq) = match suffix.get(15..) {
+        Some("") | None => (suffix, Some(0)),
+        Some(rest) => (
+            &suffix[..15],
+            rest.strip_prefix('-').and_then(|s| s.parse().ok()),
+        ),
+    };
+
+    Some((utils::parse_timestamp(stamp)?, seq?))
+}
+
+fn backup_name(name: &OsStr, stamp: &str) -> OsString {
+    let mut out = name.to_os_string();
+    out.push(format!(".bak.{stamp}"));
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::TestDir;
+    use std::fs;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_backup_generations() {
+        let root = TestDir::create();
+        fs::write(root.join("file"), "v1").expect("Test should never fail");
+        for name in [
+            "file.bak.20240101-000000",
+            "file.bak.20240101-000000-1",
+            "file.bak.20230101-000000",
+            "file.bak.garbage",
+            "other.bak.20240101-000000",
+        ] {
+            fs::write(root.join(name), "old").expect("Test should never fail");
+        }
+
+        let dir = Dir::open(&root).expect("Test should never fail");
+        let generations = list(&dir, OsStr::new("file")).expect("Test should never fail");
+        let names: Vec<_> = generations.iter().map(|g| g.name.clone()).collect();
+        assert_eq!(
+            names,
+            [
+                "file.bak.20240101-000000-1",
+                "file.bak.20240101-000000",
+                "file.bak.20230101-000000"
+            ]
+        );
+
+        let loc = Location {
+            dir: dir.try_clone().expect("Test should never fail"),
+            name: OsString::from("file"),
+            path: PathBuf::from("file"),
+            real: PathBuf::from("file"),
+        };
+        let retention = Retention {
+            keep: 2,
+            max_age: None,
+        };
+        let name = create(&loc, &retention)
+            .expect("Test should never fail")
+            .expect("Test should never fail");
+        assert_eq!(
+            fs::read(root.join(&name)).expect("Test should never fail"),
+            b"v1"
+        );
+
+        let generations = list(&dir, OsStr::new("file")).expect("Test should never fail");
+        assert_eq!(generations.len(), 2);
+        assert_eq!(generations[0].name, name);
+
+        // A second backup within the same second gets its own name
+        let unlimited = Retention {
+            keep: 0,
+            max_age: None,
+        };
+        let again = create(&loc, &unlimited)
+            .expect("Test should never fail")
+            .expect("Test should never fail");
+        assert_ne!(again, name);
+        assert!(root.join(&name).exists());
+    }
+
+    #[test]
+    fn test_is_backup() {
+        assert!(is_backup(OsStr::new("file.bak.20240101-000000")));
+        assert!(is_backup(OsStr::new("file.bak.20240101-000000-3")));
+        assert!(is_backup(OsStr::new("a.bak.b.bak.20240101-000000")));
+        assert!(!is_backup(OsStr::new("file.bak.garbage")));
+        assert!(!is_backup(OsStr::new("file.bak.20240101-000000-x")));
+        assert!(!is_backup(OsStr::new(".bak.20240101-000000")));
+        assert!(!is_backup(OsStr::new("file.bak")));
+        assert!(!is_backup(OsStr::new("file")));
+    }
+}
@@ -0,0 +1,296 @@
+use crate::errors::TeleportError;
+use crate::teleport::{TeleportBeacon, TeleportBeaconFlags, TeleportVersion};
+use crate::{signals, utils, SendOpt, VERSION};
+use ipnetwork::IpNetwork;
+use pnet_datalink::interfaces;
+use semver::Version;
+use std::collections::HashSet;
+use std::fs;
+use std::hash::Hasher;
+use std::io;
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use xxhash_rust::xxh3;
+
+/// Multicast group and UDP port that listeners announce themselves on
+pub const BEACON_GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 84, 80);
+pub const BEACON_PORT: u16 = 9001;
+
+/// How often listeners announce themselves
+pub const BEACON_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How often the announcing thread checks for shutdown
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A listener that announced itself
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Heard {
+    /// Address the listener accepts transfers on
+    pub addr: SocketAddr,
+    pub beacon: TeleportBeacon,
+}
+
+impl Heard {
+    pub fn name(&self) -> String {
+        String::from_utf8_lossy(&self.beacon.name).to_string()
+    }
+}
+
+/// Describe this listener. The fingerprint stays the same across restarts
+/// and address changes of the machine, but it is not a cryptographic
+/// identity. Machines without a machine ID fall back to the hostname.
+pub fn local(port: u16, must_encrypt: bool, name: Option<&str>) -> TeleportBeacon {
+    let v = Version::parse(VERSION).expect("Fatal version error");
+
+    let mut hasher = xxh3::Xxh3::new();
+    match fs::read("/etc/machine-id").or_else(|_| fs::read("/var/lib/dbus/machine-id")) {
+        Ok(id) => hasher.write(&id),
+        Err(_) => hasher.write(utils::hostname().as_bytes()),
+    }
+    hasher.write_u16(port);
+
+    let name = match name {
+        Some(n) => n.to_string(),
+        None => utils::hostname(),
+    };
+
+    TeleportBeacon {
+        version: TeleportVersion {
+            major: v.major as u16,
+            minor: v.minor as u16,
+            patch: v.patch as u16,
+        },
+        port,
+        flags: match must_encrypt {
+            true => TeleportBeaconFlags::MustEncrypt as u8,
+            false => 0,
+        },
+        fingerprint: hasher.finish(),
+        name: name.into_bytes(),
+    }
+}
+
+/// Addresses of the interfaces beacons are sent and received on
+fn multicast_addrs() -> Vec<Ipv4Addr> {
+    interfaces()
+        .iter()
+        .filter(|i| i.is_up() && i.is_multicast() && !i.is_loopback())
+        .flat_map(|i| i.ips.iter())
+        .filter_map(|ip| match ip.ip() {
+            IpAddr::V4(v4) => Some(v4),
+            IpAddr::V6(_) => None,
+        })
+        .collect()
+}
+
+/// Broadcast addresses of the interfaces that cannot send multicast
+fn broadcast_addrs() -> Vec<Ipv4Addr> {
+    interfaces()
+        .iter()
+        .filter(|i| i.is_up() && i.is_broadcast() && !i.is_multicast() && !i.is_loopback())
+        .flat_map(|i| i.ips.iter())
+        .filter_map(|ip| match ip {
+            IpNetwork::V4(n) => Some(n.broadcast()),
+            IpNetwork::V6(_) => None,
+        })
+        .collect()
+}
+
+/// Announce the listener on every interface until the listener shuts down.
+/// Interfaces that cannot send multicast get a broadcast to the beacon port
+/// instead, which the sockets joined to the group receive as well.
+pub fn announce(beacon: TeleportBeacon) -> io::Result<JoinHandle<()>> {
+    let data = beacon
+        .serialize()
+        .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+    let sock = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    sock.set_broadcast(true)?;
+
+    Ok(thread::spawn(move || {
+        let mut next = Instant::now();
+        while signals::shutdown_count() == 0 {
+            if Instant::now() < next {
+                thread::sleep(POLL_INTERVAL);
+                continue;
+            }
+            next = Instant::now() + BEACON_INTERVAL;
+
+            // Interfaces may come and go while the listener runs
+            for addr in multicast_addrs() {
+                if set_multicast_if(&sock, addr).is_ok() {
+                    let _ = sock.send_to(&data, (BEACON_GROUP, BEACON_PORT));
+                }
+            }
+            for broadcast in broadcast_addrs() {
+                let _ = sock.send_to(&data, (broadcast, BEACON_PORT));
+            }
+        }
+    }))
+}
+
+fn set_multicast_if(sock: &UdpSocket, addr: Ipv4Addr) -> io::Result<()> {
+    let addr = libc::in_addr {
+        s_addr: u32::from(addr).to_be(),
+    };
+    utils::cvt(unsafe {
+        libc::setsockopt(
+            sock.as_raw_fd(),
+            libc::IPPROTO_IP,
+            libc::IP_MULTICAST_IF,
+            &addr as *const libc::in_addr as *const libc::c_void,
+            mem::size_of::<libc::in_addr>() as libc::socklen_t,
+        )
+    })?;
+
+    Ok(())
+}
+
+/// Listen for beacons for up to `duration`, calling `found` once for each
+/// listener heard. Stops early when `found` returns true.
+pub fn collect<F>(duration: Duration, mut found: F) -> io::Result<()>
+where
+    F: FnMut(&Heard) -> bool,
+{
+    let sock = shared_socket()?;
+    let mut joined = false;
+    for addr in multicast_addrs() {
+        joined |= sock.join_multicast_v4(&BEACON_GROUP, &addr).is_ok();
+    }
+    if !joined {
+        sock.join_multicast_v4(&BEACON_GROUP, &Ipv4Addr::UNSPECIFIED)?;
+    }
+
+    let deadline = Instant::now() + duration;
+    let mut seen = HashSet::new();
+    let mut buf = [0u8; 1500];
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Ok(());
+        }
+        sock.set_read_timeout(Some(left))?;
+
+        let (len, from) = match sock.recv_from(&mut buf) {
+            Ok(r) => r,
+            Err(e)
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+            {
+                return Ok(())
+            }
+            Err(e) => return Err(e),
+        };
+
+        let mut beacon = TeleportBeacon::default();
+        if beacon.deserialize(&buf[..len]).is_err() {
+            continue;
+        }
+        let heard = Heard {
+            addr: SocketAddr::new(from.ip(), beacon.port),
+            beacon,
+        };
+        if seen.insert(heard.addr) && found(&heard) {
+            return Ok(());
+        }
+    }
+}
+
+/// A UDP socket on the beacon port that other processes can bind as well
+fn shared_socket() -> io::Result<UdpSocket> {
+    let fd = utils::cvt(unsafe {
+        libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0)
+    })?;
+    let sock = unsafe { UdpSocket::from_raw_fd(fd) };
+
+    let one: libc::c_int = 1;
+    for opt in [libc::SO_REUSEADDR, libc::SO_REUSEPORT] {
+        utils::cvt(unsafe {
+            libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                opt,
+                &one as *const libc::c_int as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        })?;
+    }
+
+    let mut addr: libc::sockaddr_in = unsafe { mem::zeroed() };
+    addr.sin_family = libc::AF_INET as libc::sa_family_t;
+    addr.sin_port = BEACON_PORT.to_be();
+    utils::cvt(unsafe {
+        libc::bind(
+            fd,
+            &addr as *const libc::sockaddr_in as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+        )
+    })?;
+
+    Ok(sock)
+}
+
+/// Resolve a destination of the form `@name` or `@name#fingerprint` to the
+/// listener announcing that name, enabling encryption if the listener
+/// requires it. Beacons are not authenticated, so without a fingerprint the
+/// name is refused if listeners with different fingerprints announce it.
+pub fn resolve_dest(opt: &mut SendOpt) -> Result<(), TeleportError> {
+    let dest = match opt.dest.strip_prefix('@') {
+        Some(d) => d.to_string(),
+        None => return Ok(()),
+    };
+    let (name, pinned) = match dest.split_once('#') {
+        Some((name, fp)) => match u64::from_str_radix(fp, 16) {
+            Ok(fp) => (name.to_string(), Some(fp)),
+            Err(_) => {
+                println!("Invalid fingerprint: {fp}");
+                return Err(TeleportError::InvalidDest);
+            }
+        },
+        None => (dest, None),
+    };
+
+    // Listen for the whole window to notice others announcing the name,
+    // unless the listener is pinned by its fingerprint
+    let mut listeners = Vec::<Heard>::new();
+    collect(BEACON_INTERVAL * 2, |heard| {
+        if heard.name() == name && pinned.map_or(true, |fp| fp == heard.beacon.fingerprint) {
+            listeners.push(heard.clone());
+        }
+        pinned.is_some() && !listeners.is_empty()
+    })?;
+
+    let heard = match listeners.first() {
+        Some(l) => l.clone(),
+        None => {
+            println!("No listener named {name} announced itself on the local network");
+            return Err(TeleportError::InvalidDest);
+        }
+    };
+    if listeners
+        .iter()
+        .any(|l| l.beacon.fingerprint != heard.beacon.fingerprint)
+    {
+        println!("Several listeners announce the name {name}:");
+        for l in &listeners {
+            println!("  {} [{:016x}]", l.addr, l.beacon.fingerprint);
+        }
+        println!("Pick one with -d @{name}#<fingerprint>");
+        return Err(TeleportError::InvalidDest);
+    }
+    println!(
+        "Found {name} at {} (fingerprint {:016x})",
+        heard.addr, heard.beacon.fingerprint
+    );
+
+    opt.dest = heard.addr.ip().to_string();
+    opt.port = heard.addr.port();
+    if heard.beacon.must_encrypt() && !opt.encrypt {
+        println!("Enabling encryption as required by {name}");
+        opt.encrypt = true;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,143 @@
+use crate::errors::TeleportError;
+use std::fs;
+use std::path::Path;
+
+/// A `[name]` section of a config file and its `key = value` entries
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Section {
+    pub name: String,
+    pub line: usize,
+    pub entries: Vec<Entry>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Entry {
+    pub key: String,
+    pub value: String,
+    pub line: usize,
+}
+
+impl Entry {
+    /// Build an error pointing at this entry
+    pub fn error(&self, msg: &str) -> TeleportError {
+        TeleportError::InvalidConfig(format!("line {}: {} `{}`", self.line, msg, self.key))
+    }
+
+    pub fn as_bool(&self) -> Result<bool, TeleportError> {
+        match self.value.to_ascii_lowercase().as_str() {
+            "true" | "yes" | "on" | "1" => Ok(true),
+            "false" | "no" | "off" | "0" => Ok(false),
+            _ => Err(self.error("expected true or false for")),
+        }
+    }
+}
+
+/// Read and parse an INI style config file
+pub fn load(path: &Path) -> Result<Vec<Section>, TeleportError> {
+    let text = fs::read_to_string(path)?;
+    parse(&text)
+}
+
+/// Parse INI style text. Comments start with `#` or `;` at the start of a
+/// line or after whitespace, every `key = value` entry must belong to a
+/// section.
+pub fn parse(text: &str) -> Result<Vec<Section>, TeleportError> {
+    let mut out = Vec::<Section>::new();
+
+    for (num, line) in text.lines().enumerate() {
+        let num = num + 1;
+        let line = strip_comment(line).trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+
+        if let Some(name) = line.strip_prefix('[') {
+            let name = match name.strip_suffix(']').map(str::trim) {
+                Some(n) if !n.is_empty() => n,
+                _ => {
+                    return Err(TeleportError::InvalidConfig(format!(
+                        "line {num}: invalid section name"
+                    )))
+                }
+            };
+            if out.iter().any(|s| s.name == name) {
+                return Err(TeleportError::InvalidConfig(format!(
+                    "line {num}: duplicate section [{name}]"
+                )));
+            }
+            out.push(Section {
+                name: name.to_string(),
+                line: num,
+                entries: Vec::new(),
+            });
+            continue;
+        }
+
+        let (key, value) = match line.split_once('=') {
+            Some((k, v)) if !k.trim().is_empty() => (k.trim(), v.trim()),
+            _ => {
+                return Err(TeleportError::InvalidConfig(format!(
+                    "line {num}: expected `key = value`"
+                )))
+            }
+        };
+        let section = match out.last_mut() {
+            Some(s) => s,
+            None => {
+                return Err(TeleportError::InvalidConfig(format!(
+                    "line {num}: `{key}` is not in a section"
+                )))
+            }
+        };
+        section.entries.push(Entry {
+            key: key.to_string(),
+            value: value.to_string(),
+            line: num,
+        });
+    }
+
+    Ok(out)
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut prev = ' ';
+    for (i, c) in line.char_indices() {
+        if (c == '#' || c == ';') && prev.is_whitespace() {
+            return &line[..i];
+        }
+        prev = c;
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_parse() {
+        let text =
+            "# comment\n[builds]\npath = /srv/a#b  # note\n\n; other\n[ docs ]\nread_only=yes ;x\n";
+        let out = parse(text).expect("Test should never fail");
+
+        assert_eq!(out.len(), 2);
+        assert_eq!(out[0].name, "builds");
+        assert_eq!(out[0].entries[0].key, "path");
+        assert_eq!(out[0].entries[0].value, "/srv/a#b");
+        assert_eq!(out[1].name, "docs");
+        assert!(out[1].entries[0].as_bool().expect("Test should never fail"));
+    }
+
+    #[test]
+    fn test_config_invalid() {
+        for text in [
+            "key = value\n",
+            "[a]\nnovalue\n",
+            "[]\n",
+            "[a\n",
+            "[a]\n[a]\n",
+        ] {
+            assert!(parse(text).is_err(), "{text:?} should be rejected");
+        }
+    }
+}
@@ -0,0 +1,309 @@
+use crate::utils;
+use ipnetwork::Ipv4Network;
+use pnet::packet::arp::{ArpHardwareTypes, ArpOperations, ArpPacket, MutableArpPacket};
+use pnet::packet::ethernet::{EtherTypes, EthernetPacket, MutableEthernetPacket};
+use pnet::packet::{MutablePacket, Packet};
+use pnet_datalink::{Channel, MacAddr, NetworkInterface};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+use std::mem;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV6};
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Size of an ARP request in an Ethernet frame
+const ARP_FRAME_LEN: usize = 42;
+
+/// How long a read on the raw socket blocks, so that the receiver notices
+/// when to stop
+const READ_TIMEOUT: Duration = Duration::from_millis(50);
+
+const ICMPV6_ECHO_REQUEST: u8 = 128;
+const ICMPV6_ECHO_REPLY: u8 = 129;
+
+/// Find the live hosts of an IPv4 network attached to an interface by asking
+/// every address for its MAC address, from the `source` address of the
+/// interface. Hosts that answer within `timeout` after the last request are
+/// returned. No requests are sent and no replies awaited past `deadline`, so
+/// the hosts found so far are returned then. Requires raw socket privileges,
+/// failing with PermissionDenied otherwise.
+pub fn arp_sweep(
+    iface: &NetworkInterface,
+    source: Ipv4Addr,
+    network: Ipv4Network,
+    timeout: Duration,
+    deadline: Option<Instant>,
+) -> io::Result<BTreeMap<Ipv4Addr, MacAddr>> {
+    let mac = match iface.mac {
+        Some(m) if !m.is_zero() => m,
+        _ => return Err(io::Error::from(io::ErrorKind::Unsupported)),
+    };
+
+    let config = pnet_datalink::Config {
+        read_timeout: Some(READ_TIMEOUT),
+        ..Default::default()
+    };
+    let (mut tx, mut rx) = match pnet_datalink::channel(iface, config)? {
+        Channel::Ethernet(tx, rx) => (tx, rx),
+        _ => return Err(io::Error::from(io::ErrorKind::Unsupported)),
+    };
+
+    // Collect replies until all requests had time to be answered
+    let stop = Arc::new(AtomicBool::new(false));
+    let receiver = {
+        let stop = Arc::clone(&stop);
+        thread::spawn(move || {
+            let mut hosts = BTreeMap::new();
+            while !stop.load(Ordering::SeqCst) {
+                // Reads time out regularly to check whether to stop
+                if let Some((ip, mac)) = rx.next().ok().and_then(parse_reply) {
+                    if network.contains(ip) {
+                        hosts.insert(ip, mac);
+                    }
+                }
+            }
+            hosts
+        })
+    };
+
+    let mut frame = [0u8; ARP_FRAME_LEN];
+    for (i, target) in network.iter().enumerate() {
+        if deadline.map_or(false, |d| Instant::now() >= d) {
+            break;
+        }
+        // Point-to-point networks have no network and broadcast addresses
+        let reserved =
+            network.prefix() < 31 && (target == network.network() || target == network.broadcast());
+        if target == source || reserved {
+            continue;
+        }
+        build_request(&mut frame, mac, source, target);
+        if let Some(Err(e)) = tx.send_to(&frame, None) {
+            // The send buffer is full, give it time to drain
+            if e.raw_os_error() == Some(libc::ENOBUFS) {
+                thread::sleep(Duration::from_millis(10));
+                let _ = tx.send_to(&frame, None);
+            } else {
+                stop.store(true, Ordering::SeqCst);
+                let _ = receiver.join();
+                return Err(e);
+            }
+        }
+        // Pace large sweeps a little
+        if i % 256 == 255 {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    // Never wait past the deadline
+    let timeout = match deadline {
+        Some(d) => timeout.min(d.saturating_duration_since(Instant::now())),
+        None => timeout,
+    };
+    thread::sleep(timeout);
+    stop.store(true, Ordering::SeqCst);
+
+    receiver
+        .join()
+        .map_err(|_| io::Error::new(io::ErrorKind::Other, "ARP receiver failed"))
+}
+
+/// Find the IPv6 hosts on the link of an interface by sending an ICMPv6 echo
+/// request to the all-nodes multicast address. Hosts that answer within
+/// `timeout`, and before `deadline`, are returned with the scope of the
+/// interface, so that link-local addresses can be connected to. Uses an
+/// unprivileged ICMP socket where the system allows it, otherwise a raw
+/// socket.
+pub fn icmpv6_sweep(
+    iface: &NetworkInterface,
+    timeout: Duration,
+    deadline: Option<Instant>,
+) -> io::Result<BTreeSet<SocketAddrV6>> {
+    let (sock, raw) = icmpv6_socket()?;
+    let fd = sock.as_raw_fd();
+
+    // Send the request out of this interface only
+    let index = iface.index;
+    utils::cvt(unsafe {
+        libc::setsockopt(
+            fd,
+            libc::IPPROTO_IPV6,
+            libc::IPV6_MULTICAST_IF,
+            &index as *const u32 as *const libc::c_void,
+            mem::size_of::<u32>() as libc::socklen_t,
+        )
+    })?;
+
+    let id = (std::process::id() & 0xffff) as u16;
+    let request = echo_request(id);
+    let mut dest: libc::sockaddr_in6 = unsafe { mem::zeroed() };
+    dest.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+    dest.sin6_addr.s6_addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1).octets();
+    dest.sin6_scope_id = index;
+    utils::cvt(unsafe {
+        libc::sendto(
+            fd,
+            request.as_ptr() as *const libc::c_void,
+            request.len(),
+            0,
+            &dest as *const libc::sockaddr_in6 as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t,
+        ) as libc::c_int
+    })?;
+
+    let mut hosts = BTreeSet::new();
+    let deadline = match deadline {
+        Some(d) => d.min(Instant::now() + timeout),
+        None => Instant::now() + timeout,
+    };
+    let mut buf = [0u8; 1500];
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        let mut pfd = libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let ready =
+            match utils::cvt(unsafe { libc::poll(&mut pfd, 1, left.as_millis() as libc::c_int) }) {
+                Ok(r) => r,
+                // A signal arrived, keep waiting for the rest of the time
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+        if ready == 0 {
+            break;
+        }
+
+        let mut from: libc::sockaddr_in6 = unsafe { mem::zeroed() };
+        let mut len = mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t;
+        let n = match utils::cvt(unsafe {
+            libc::recvfrom(
+                fd,
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+                0,
+                &mut from as *mut libc::sockaddr_in6 as *mut libc::sockaddr,
+                &mut len,
+            ) as libc::c_int
+        }) {
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+
+        // Raw sockets see all ICMPv6 traffic, only count replies to our
+        // request. ICMP sockets only receive their own replies, with the
+        // identifier chosen by the kernel.
+        let reply = &buf[..n as usize];
+        let ours = !raw || reply.get(4..6) == Some(&id.to_be_bytes()[..]);
+        if reply.first() == Some(&ICMPV6_ECHO_REPLY) && ours {
+            let ip = Ipv6Addr::from(from.sin6_addr.s6_addr);
+            let scope = match ip.segments()[0] & 0xffc0 == 0xfe80 {
+                true => index,
+                false => 0,
+            };
+            hosts.insert(SocketAddrV6::new(ip, 0, 0, scope));
+        }
+    }
+
+    Ok(hosts)
+}
+
+/// Open an ICMPv6 socket, returning whether it is a raw socket
+fn icmpv6_socket() -> io::Result<(OwnedFd, bool)> {
+    let flags = libc::SOCK_CLOEXEC;
+    let (fd, raw) = match unsafe {
+        libc::socket(
+            libc::AF_INET6,
+            libc::SOCK_DGRAM | flags,
+            libc::IPPROTO_ICMPV6,
+        )
+    } {
+        fd if fd >= 0 => (fd, false),
+        _ => (
+            utils::cvt(unsafe {
+                libc::socket(libc::AF_INET6, libc::SOCK_RAW | flags, libc::IPPROTO_ICMPV6)
+            })?,
+            true,
+        ),
+    };
+
+    Ok((unsafe { OwnedFd::from_raw_fd(fd) }, raw))
+}
+
+/// An ICMPv6 echo request. The kernel fills in the checksum.
+fn echo_request(id: u16) -> Vec<u8> {
+    let mut out = vec![ICMPV6_ECHO_REQUEST, 0, 0, 0];
+
+    // Add identifier and sequence number
+    out.append(&mut id.to_be_bytes().to_vec());
+    out.append(&mut 1u16.to_be_bytes().to_vec());
+
+    out
+}
+
+fn build_request(frame: &mut [u8], mac: MacAddr, source: Ipv4Addr, target: Ipv4Addr) {
+    let mut eth = MutableEthernetPacket::new(frame).expect("Fatal error building ARP request");
+    eth.set_destination(MacAddr::broadcast());
+    eth.set_source(mac);
+    eth.set_ethertype(EtherTypes::Arp);
+
+    let mut arp =
+        MutableArpPacket::new(eth.payload_mut()).expect("Fatal error building ARP request");
+    arp.set_hardware_type(ArpHardwareTypes::Ethernet);
+    arp.set_protocol_type(EtherTypes::Ipv4);
+    arp.set_hw_addr_len(6);
+    arp.set_proto_addr_len(4);
+    arp.set_operation(ArpOperations::Request);
+    arp.set_sender_hw_addr(mac);
+    arp.set_sender_proto_addr(source);
+    arp.set_target_hw_addr(MacAddr::zero());
+    arp.set_target_proto_addr(target);
+}
+
+fn parse_reply(frame: &[u8]) -> Option<(Ipv4Addr, MacAddr)> {
+    let eth = EthernetPacket::new(frame)?;
+    if eth.get_ethertype() != EtherTypes::Arp {
+        return None;
+    }
+    let arp = ArpPacket::new(eth.payload())?;
+    if arp.get_operation() != ArpOperations::Reply {
+        return None;
+    }
+
+    Some((arp.get_sender_proto_addr(), arp.get_sender_hw_addr()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_arp_roundtrip() {
+        let mac = MacAddr::new(0x02, 0, 0, 0, 0, 0x01);
+        let mut frame = [0u8; ARP_FRAME_LEN];
+        build_request(
+            &mut frame,
+            mac,
+            Ipv4Addr::new(192, 168, 1, 2),
+            Ipv4Addr::new(192, 168, 1, 20),
+        );
+        // A request is not a reply
+        assert_eq!(parse_reply(&frame), None);
+
+        // Turn the request into the reply of the target
+        let mut eth = MutableEthernetPacket::new(&mut frame).expect("Test should never fail");
+        let mut arp = MutableArpPacket::new(eth.payload_mut()).expect("Test should never fail");
+        arp.set_operation(ArpOperations::Reply);
+        arp.set_sender_proto_addr(Ipv4Addr::new(192, 168, 1, 20));
+
+        assert_eq!(
+            parse_reply(&frame),
+            Some((Ipv4Addr::new(192, 168, 1, 20), mac))
+        );
+    }
+}
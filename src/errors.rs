@@ -25,12 +25,18 @@ pub enum TeleportError {
     #[error("Error with destination address")]
     InvalidDest,
 
+    #[error("The server has no share named: {0}")]
+    UnknownShare(String),
+
     #[error("Invalid Protocol header received")]
     InvalidProtocol,
 
     #[error("Invalid file name")]
     InvalidFileName,
 
+    #[error("Path resolves outside of the receive root")]
+    UnsafePath,
+
     #[error("Error reading protcool header")]
     InvalidHeaderRead,
 
@@ -49,6 +55,52 @@ pub enum TeleportError {
     #[error("Cannot deserialize delta data")]
     InvalidDelta,
 
+    #[error("Cannot deserialize file metadata")]
+    InvalidMetadata,
+
+    #[error("Cannot deserialize extension area")]
+    InvalidExtension,
+
+    #[error("Cannot deserialize abort message")]
+    InvalidAbort,
+
+    #[error("The remote side ran out of space: {0}")]
+    RemoteNoSpace(String),
+
+    #[error("The remote side was denied permission: {0}")]
+    RemoteNoPermission(String),
+
+    #[error("The remote side cancelled the transfer: {0}")]
+    RemoteCancelled(String),
+
+    #[error("The remote side aborted the transfer: {0}")]
+    RemoteAborted(String),
+
     #[error("Encryption failed")]
     EncryptionFailure,
+
+    #[error("File has changed since it was received")]
+    FileChanged,
+
+    #[error("Invalid config file: {0}")]
+    InvalidConfig(String),
+
+    #[error("Invalid scan target: {0}")]
+    InvalidTarget(String),
+
+    #[error("Refusing to scan {0} addresses, more than --max-hosts allows")]
+    TooManyHosts(u64),
+}
+
+impl TeleportError {
+    /// Whether the peer aborted the transfer with an `Abort` message
+    pub fn is_abort(&self) -> bool {
+        matches!(
+            self,
+            TeleportError::RemoteNoSpace(_)
+                | TeleportError::RemoteNoPermission(_)
+                | TeleportError::RemoteCancelled(_)
+                | TeleportError::RemoteAborted(_)
+        )
+    }
 }
@@ -0,0 +1,205 @@
+use crate::teleport::{TeleportFeatures, TeleportStatus};
+use crate::utils;
+use std::fs::File;
+use std::io;
+use std::os::unix::process::CommandExt;
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Environment variables passed to a hook
+pub type Env = Vec<(&'static str, String)>;
+
+/// External commands run by the listener before accepting and after
+/// receiving a file
+#[derive(Debug)]
+pub struct Hooks {
+    pre_accept: Option<String>,
+    post_receive: Option<String>,
+    timeout: Duration,
+    running: Arc<AtomicUsize>,
+}
+
+impl Hooks {
+    pub fn new(
+        pre_accept: Option<String>,
+        post_receive: Option<String>,
+        timeout: Duration,
+    ) -> Self {
+        Hooks {
+            pre_accept,
+            post_receive,
+            timeout,
+            running: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Ask the pre-accept hook whether a transfer may proceed. Exit code 0
+    /// accepts it, an exit code matching a refusing TeleportStatus refuses it
+    /// with that status, and anything else refuses it with AccessDenied.
+    pub fn pre_accept(&self, env: &Env) -> TeleportStatus {
+        let cmd = match &self.pre_accept {
+            Some(c) => c,
+            None => return TeleportStatus::Proceed,
+        };
+
+        match run(cmd, env, self.timeout) {
+            Ok(Some(status)) => match status.code() {
+                Some(code) => exit_status(code),
+                None => {
+                    println!(" => Pre-accept hook killed ({status})");
+                    TeleportStatus::AccessDenied
+                }
+            },
+            Ok(None) => {
+                println!(" => Pre-accept hook timed out");
+                TeleportStatus::AccessDenied
+            }
+            Err(e) => {
+                println!("Error: unable to run pre-accept hook: {e}");
+                TeleportStatus::AccessDenied
+            }
+        }
+    }
+
+    /// Run the post-receive hook for a received file in the background. The
+    /// hash of the file is added to the environment as TELEPORTER_HASH.
+    pub fn post_receive(&self, file: File, mut env: Env) {
+        let cmd = match &self.post_receive {
+            Some(c) => c.clone(),
+            None => return,
+        };
+        let timeout = self.timeout;
+        let running = Arc::clone(&self.running);
+
+        running.fetch_add(1, Ordering::SeqCst);
+        thread::spawn(move || {
+            match utils::hash_file(&file) {
+                Ok(h) => env.push(("TELEPORTER_HASH", format!("{h:016x}"))),
+                Err(e) => println!("Error: unable to hash received file: {e}"),
+            }
+            match run(&cmd, &env, timeout) {
+                Ok(Some(status)) if !status.success() => {
+                    println!(" => Post-receive hook failed ({status})")
+                }
+                Ok(Some(_)) => (),
+                Ok(None) => println!(" => Post-receive hook timed out"),
+                Err(e) => println!("Error: unable to run post-receive hook: {e}"),
+            }
+            running.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+
+    /// Number of post-receive hooks still running
+    pub fn running(&self) -> usize {
+        self.running.load(Ordering::SeqCst)
+    }
+}
+
+/// List the names of the features set in `features`
+pub fn features(features: u32) -> String {
+    TeleportFeatures::ALL
+        .iter()
+        .filter(|f| f.check_u32(features))
+        .map(TeleportFeatures::name)
+        .collect::<Vec<&str>>()
+        .join(",")
+}
+
+/// Map the exit code of a pre-accept hook to the status sent to the client
+fn exit_status(code: i32) -> TeleportStatus {
+    if code == 0 {
+        return TeleportStatus::Proceed;
+    }
+
+    match u8::try_from(code).map(TeleportStatus::try_from) {
+        Ok(Ok(TeleportStatus::Proceed))
+        | Ok(Ok(TeleportStatus::Pong))
+        | Ok(Ok(TeleportStatus::Pending)) => TeleportStatus::AccessDenied,
+        Ok(Ok(status)) => status,
+        _ => TeleportStatus::AccessDenied,
+    }
+}
+
+/// Run a command with `sh -c`, killing it and anything it started if it
+/// runs longer than `timeout`. Returns None on timeout.
+fn run(cmd: &str, env: &Env, timeout: Duration) -> io::Result<Option<ExitStatus>> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(cmd)
+        .envs(env.iter().map(|(k, v)| (k, v)))
+        .stdin(Stdio::null())
+        .process_group(0)
+        .spawn()?;
+
+    let deadline = Instant::now() + timeout;
+    let mut wait = Duration::from_millis(1);
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if Instant::now() >= deadline {
+            break;
+        }
+        thread::sleep(wait);
+        wait = (wait * 2).min(Duration::from_millis(50));
+    }
+
+    // The hook runs in its own process group, which is killed as a whole
+    if let Ok(pgid) = libc::pid_t::try_from(child.id()) {
+        unsafe { libc::kill(-pgid, libc::SIGKILL) };
+    }
+    child.wait()?;
+
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pre_accept_status() {
+        let env: Env = vec![("TELEPORTER_SIZE", "10".to_string())];
+        let timeout = Duration::from_secs(10);
+
+        let hooks = Hooks::new(
+            Some("test $TELEPORTER_SIZE = 10".to_string()),
+            None,
+            timeout,
+        );
+        assert_eq!(hooks.pre_accept(&env), TeleportStatus::Proceed);
+
+        let hooks = Hooks::new(Some("exit 12".to_string()), None, timeout);
+        assert_eq!(hooks.pre_accept(&env), TeleportStatus::TooLarge);
+
+        let hooks = Hooks::new(Some("exit 8".to_string()), None, timeout);
+        assert_eq!(hooks.pre_accept(&env), TeleportStatus::AccessDenied);
+
+        let hooks = Hooks::new(Some("exit 100".to_string()), None, timeout);
+        assert_eq!(hooks.pre_accept(&env), TeleportStatus::AccessDenied);
+    }
+
+    #[test]
+    fn test_pre_accept_timeout() {
+        let env: Env = Vec::new();
+        let hooks = Hooks::new(
+            Some("sleep 10".to_string()),
+            None,
+            Duration::from_millis(100),
+        );
+
+        let start = Instant::now();
+        assert_eq!(hooks.pre_accept(&env), TeleportStatus::AccessDenied);
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_features() {
+        let f = TeleportFeatures::NewFile as u32 | TeleportFeatures::Overwrite as u32;
+        assert_eq!(features(f), "newfile,overwrite");
+        assert_eq!(features(0), "");
+    }
+}
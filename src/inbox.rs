@@ -0,0 +1,380 @@
+use crate::atomic::AtomicFile;
+use crate::backup;
+use crate::backup::Retention;
+use crate::errors::TeleportError;
+use crate::metadata;
+use crate::metadata::Trust;
+use crate::sandbox::{Dir, Location, Sandbox};
+use crate::utils;
+use crate::{InboxAction, InboxOpt};
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io;
+use std::io::{Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+/// Extension of the sidecar file describing a quarantined file
+const META_EXT: &str = ".meta";
+
+/// A received file held in the quarantine directory until it is approved.
+/// The data is stored as `<id>` and its description as `<id>.meta`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Entry {
+    pub id: String,
+    pub peer: String,
+    /// Seconds since the epoch
+    pub time: u64,
+    pub size: u64,
+    pub hash: u64,
+    pub share: String,
+    /// Receive root of the share the file was sent to
+    pub root: PathBuf,
+    /// Path of the file relative to the receive root
+    pub filename: String,
+    pub overwrite: bool,
+    /// Backups to keep of the file it replaces, if a backup was requested
+    pub backup: Option<Retention>,
+    pub dangerous: bool,
+}
+
+impl Entry {
+    /// Pick an unused id in the quarantine directory
+    pub fn new_id(dir: &Dir) -> String {
+        loop {
+            let id = format!("{:08x}", rand::random::<u32>());
+            if !dir.exists(OsStr::new(&id)) && !dir.exists(&meta_name(&id)) {
+                return id;
+            }
+        }
+    }
+
+    /// Write the sidecar file once the data is in place. The data is removed
+    /// if this fails, so that it does not linger without a description.
+    pub fn save(&self, dir: &Dir) -> io::Result<()> {
+        let write = || {
+            let loc = Location {
+                dir: dir.try_clone()?,
+                name: meta_name(&self.id),
+                path: PathBuf::from(meta_name(&self.id)),
+                real: PathBuf::from(meta_name(&self.id)),
+            };
+            let mut tmp = AtomicFile::create(&loc)?;
+            tmp.file.write_all(self.serialize().as_bytes())?;
+            tmp.persist()
+        };
+
+        write().map_err(|e| {
+            let _ = dir.remove(OsStr::new(&self.id));
+            e
+        })
+    }
+
+    fn load(dir: &Dir, id: &str) -> Result<Entry, TeleportError> {
+        if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err(TeleportError::InvalidFileName);
+        }
+
+        let mut buf = String::new();
+        dir.open_file(&meta_name(id), libc::O_RDONLY, 0)?
+            .read_to_string(&mut buf)?;
+        Entry::parse(id, &buf)
+    }
+
+    fn serialize(&self) -> String {
+        let mut out = String::new();
+        for (key, value) in [
+            ("peer", self.peer.clone()),
+            ("time", self.time.to_string()),
+            ("size", self.size.to_string()),
+            ("hash", format!("{:016x}", self.hash)),
+            ("share", self.share.clone()),
+            ("root", self.root.to_string_lossy().to_string()),
+            ("filename", self.filename.clone()),
+            ("overwrite", self.overwrite.to_string()),
+            ("backup", self.backup.is_some().to_string()),
+            (
+                "backup_keep",
+                self.backup.map_or(String::new(), |r| r.keep.to_string()),
+            ),
+            (
+                "backup_max_age",
+                self.backup
+                    .and_then(|r| r.max_age)
+                    .map_or(String::new(), |a| a.to_string()),
+            ),
+            ("dangerous", self.dangerous.to_string()),
+        ] {
+            out.push_str(&format!("{key}={}\n", escape(&value)));
+        }
+
+        out
+    }
+
+    fn parse(id: &str, input: &str) -> Result<Entry, TeleportError> {
+        let invalid =
+            |what: &str| TeleportError::InvalidConfig(format!("{id}{META_EXT}: invalid {what}"));
+
+        let mut entry = Entry {
+            id: id.to_string(),
+            ..Default::default()
+        };
+        let mut backup = false;
+        let mut retention = Retention {
+            keep: 0,
+            max_age: None,
+        };
+        for line in input.lines() {
+            let (key, value) = line.split_once('=').ok_or_else(|| invalid("line"))?;
+            let value = unescape(value);
+            match key {
+                "peer" => entry.peer = value,
+                "time" => entry.time = value.parse().map_err(|_| invalid(key))?,
+                "size" => entry.size = value.parse().map_err(|_| invalid(key))?,
+                "hash" => entry.hash = u64::from_str_radix(&value, 16).map_err(|_| invalid(key))?,
+                "share" => entry.share = value,
+                "root" => entry.root = PathBuf::from(value),
+                "filename" => entry.filename = value,
+                "overwrite" => entry.overwrite = value.parse().map_err(|_| invalid(key))?,
+                "backup" => backup = value.parse().map_err(|_| invalid(key))?,
+                "backup_keep" if value.is_empty() => (),
+                "backup_keep" => retention.keep = value.parse().map_err(|_| invalid(key))?,
+                "backup_max_age" if value.is_empty() => retention.max_age = None,
+                "backup_max_age" => {
+                    retention.max_age = Some(value.parse().map_err(|_| invalid(key))?)
+                }
+                "dangerous" => entry.dangerous = value.parse().map_err(|_| invalid(key))?,
+                // Ignore anything added by newer versions
+                _ => (),
+            }
+        }
+
+        if backup {
+            entry.backup = Some(retention);
+        }
+
+        if entry.filename.is_empty() || entry.root.as_os_str().is_empty() {
+            return Err(invalid("destination"));
+        }
+
+        Ok(entry)
+    }
+
+    fn destination(&self) -> String {
+        if self.share.is_empty() {
+            self.filename.clone()
+        } else {
+            format!("[{}] {}", self.share, self.filename)
+        }
+    }
+}
+
+fn meta_name(id: &str) -> OsString {
+    OsString::from(format!("{id}{META_EXT}"))
+}
+
+/// Escape backslashes and line breaks, so that each value fits on one line
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
+
+fn unescape(value: &str) -> String {
+    let mut out = String::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some('r') => out.push('\r'),
+            Some(c) => out.push(c),
+            None => out.push('\\'),
+        }
+    }
+
+    out
+}
+
+/// Open the quarantine directory, creating it if required
+pub fn open(path: &Path) -> io::Result<Dir> {
+    if !path.exists() {
+        fs::create_dir_all(path)?;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o700))?;
+    }
+    Dir::open(path)
+}
+
+/// Manage the files held in a quarantine directory
+pub fn run(opt: InboxOpt) -> Result<(), TeleportError> {
+    let dir = Dir::open(&opt.quarantine)?;
+
+    match opt.action {
+        InboxAction::List => list(&dir, &opt.quarantine)?,
+        InboxAction::Approve { ids } => {
+            for id in ids {
+                match Entry::load(&dir, &id).and_then(|e| approve(&dir, &e)) {
+                    Ok(path) => println!("Approved {id} => {}", path.display()),
+                    Err(e) => println!("Error: unable to approve {id}: {e}"),
+                }
+            }
+        }
+        InboxAction::Reject { ids } => {
+            for id in ids {
+                match Entry::load(&dir, &id).and_then(|e| reject(&dir, &e)) {
+                    Ok(_) => println!("Rejected {id}"),
+                    Err(e) => println!("Error: unable to reject {id}: {e}"),
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn list(dir: &Dir, path: &Path) -> Result<(), TeleportError> {
+    let mut entries = Vec::<Entry>::new();
+    for file in fs::read_dir(path)? {
+        let name = file?.file_name().to_string_lossy().to_string();
+        if let Some(id) = name.strip_suffix(META_EXT) {
+            match Entry::load(dir, id) {
+                Ok(e) => entries.push(e),
+                Err(e) => println!("Error: {e}"),
+            }
+        }
+    }
+    entries.sort_by(|a, b| a.time.cmp(&b.time).then(a.id.cmp(&b.id)));
+
+    if entries.is_empty() {
+        println!("No files in quarantine");
+        return Ok(());
+    }
+
+    println!(
+        "{:<8}  {:<19}  {:<15}  {:>12}  {:<16}  DESTINATION",
+        "ID", "RECEIVED (UTC)", "PEER", "SIZE", "HASH"
+    );
+    for e in entries {
+        println!(
+            "{:<8}  {:<19}  {:<15}  {:>12}  {:016x}  {}",
+            e.id,
+            utils::format_time(e.time),
+            e.peer,
+            e.size,
+            e.hash,
+            e.destination()
+        );
+    }
+
+    Ok(())
+}
+
+/// Move a quarantined file to the destination it was sent to, resolving it
+/// within the receive root like the listener does
+fn approve(dir: &Dir, entry: &Entry) -> Result<PathBuf, TeleportError> {
+    let id = OsStr::new(&entry.id);
+    let data = dir.open_file(id, libc::O_RDONLY, 0)?;
+    if utils::hash_file(&data)? != entry.hash {
+        return Err(TeleportError::FileChanged);
+    }
+
+    let sandbox = Sandbox::new(&entry.root, entry.dangerous)?;
+    let loc = sandbox.resolve(&entry.filename, true)?;
+
+    if let Ok(orig) = loc.dir.open_file(&loc.name, libc::O_RDONLY, 0) {
+        if !entry.overwrite || orig.metadata()?.is_dir() {
+            return Err(io::Error::from(io::ErrorKind::AlreadyExists).into());
+        }
+        if let Some(retention) = &entry.backup {
+            backup::create(&loc, retention)?;
+        }
+        // Keep the owner and attributes of the replaced file, as the
+        // listener does for files it receives directly
+        metadata::inherit(&data, &orig, &Trust::default(), &entry.filename);
+    }
+
+    // Move the file if possible, otherwise copy it over. A file that
+    // appeared at the destination since is only replaced on overwrite.
+    let moved = match entry.overwrite {
+        true => dir.move_to(id, &loc.dir, &loc.name),
+        false => dir.move_new(id, &loc.dir, &loc.name),
+    };
+    match moved {
+        Ok(_) => loc.dir.file().sync_all()?,
+        Err(e) if e.raw_os_error() == Some(libc::EXDEV) => {
+            let mut tmp = AtomicFile::create(&loc)?;
+            tmp.clone_from(&data)?;
+            let all = Trust {
+                owner: true,
+                xattrs: true,
+            };
+            metadata::inherit(&tmp.file, &data, &all, &entry.filename);
+            match entry.overwrite {
+                true => tmp.persist()?,
+                false => tmp.persist_new()?,
+            }
+            dir.remove(id)?;
+        }
+        Err(e) => return Err(e.into()),
+    }
+    dir.remove(&meta_name(&entry.id))?;
+
+    Ok(sandbox.path(&loc))
+}
+
+fn reject(dir: &Dir, entry: &Entry) -> Result<(), TeleportError> {
+    match dir.remove(OsStr::new(&entry.id)) {
+        Ok(_) => (),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+        Err(e) => return Err(e.into()),
+    }
+    dir.remove(&meta_name(&entry.id))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entry_roundtrip() {
+        let entry = Entry {
+            id: "0badf00d".to_string(),
+            peer: "192.168.1.20".to_string(),
+            time: 1_700_000_000,
+            size: 4096,
+            hash: 0x0123456789abcdef,
+            share: "builds".to_string(),
+            root: PathBuf::from("/srv/builds"),
+            filename: "odd\\name\nwith=breaks".to_string(),
+            overwrite: true,
+            backup: Some(Retention {
+                keep: 5,
+                max_age: None,
+            }),
+            dangerous: false,
+        };
+
+        let out = Entry::parse("0badf00d", &entry.serialize()).expect("Test should never fail");
+        assert_eq!(out, entry);
+
+        // Entries without a backup leave the retention empty
+        let entry = Entry {
+            backup: None,
+            ..entry
+        };
+        let out = Entry::parse("0badf00d", &entry.serialize()).expect("Test should never fail");
+        assert_eq!(out, entry);
+    }
+
+    #[test]
+    fn test_entry_invalid() {
+        assert!(Entry::parse("id", "time=soon\n").is_err());
+        assert!(Entry::parse("id", "peer=10.0.0.1\n").is_err());
+    }
+}
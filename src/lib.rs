@@ -1,25 +1,43 @@
-use clap::Parser;
+use clap::{Parser, ValueEnum};
+use ipnetwork::IpNetwork;
 use std::path::PathBuf;
 
 pub mod errors;
+pub mod inbox;
 pub mod listen;
+pub mod remote;
 pub mod scan;
 pub mod send;
 
+mod atomic;
+mod backup;
+mod beacon;
+mod config;
 mod crypto;
+mod discover;
+mod hooks;
+mod metadata;
+mod place;
+mod policy;
+mod pool;
+mod quota;
+mod sandbox;
+mod share;
+mod signals;
+mod sparse;
 mod teleport;
 mod utils;
 
 pub const PROTOCOL: u64 = 0x54524f50454c4554;
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
 
-#[derive(Clone, Debug, Parser, PartialEq, Eq)]
+#[derive(Clone, Debug, Default, Parser, PartialEq, Eq)]
 pub struct SendOpt {
     /// List of filepaths to files that will be teleported
     #[arg(short, long, num_args = ..)]
     input: Vec<PathBuf>,
 
-    /// Destination teleporter host
+    /// Destination teleporter host, or @name (or @name#fingerprint) of a listener announcing itself with `--beacon`
     #[arg(short, long, default_value = "localhost")]
     dest: String,
 
@@ -47,13 +65,48 @@ pub struct SendOpt {
     #[arg(short, long)]
     keep_path: bool,
 
-    /// Backup the destination file to a ".bak" extension if it exists and is being overwritten (consecutive runs will replace the *.bak file)
+    /// Backup the destination file if it exists and is being overwritten (the server keeps several timestamped ".bak.*" generations)
     #[arg(short, long)]
     backup: bool,
 
     /// If the destination file exists, append a ".1" (or next available number) to the filename instead of overwriting
     #[arg(short, long)]
     filename_append: bool,
+
+    /// Preserve file metadata on the remote server (comma separated list)
+    #[arg(long, value_enum, value_delimiter = ',')]
+    preserve: Vec<Preserve>,
+
+    /// Name of the share on the server to send files to
+    #[arg(long)]
+    share: Option<String>,
+
+    /// How to send symlinks that are absolute or point outside of a recursively sent directory
+    #[arg(long, value_enum, default_value = "skip")]
+    unsafe_links: LinkPolicy,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum LinkPolicy {
+    /// Do not send the symlink
+    #[default]
+    Skip,
+    /// Recreate the symlink with its target unchanged
+    Keep,
+    /// Send the file or directory the symlink points to
+    Follow,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Preserve {
+    /// Access and modification times
+    Times,
+    /// User and group ownership (by name, falling back to uid/gid)
+    Owner,
+    /// Extended attributes
+    Xattrs,
+    /// POSIX ACLs
+    Acls,
 }
 
 #[derive(Clone, Debug, Parser, PartialEq, Eq)]
@@ -69,6 +122,165 @@ pub struct ListenOpt {
     /// Port to listen on
     #[arg(short, long, default_value = "9001")]
     port: u16,
+
+    /// Directory to receive files into. Received paths cannot escape it
+    #[arg(short, long, default_value = ".")]
+    root: PathBuf,
+
+    /// Config file defining named shares that clients can send files to
+    #[arg(long)]
+    shares: Option<PathBuf>,
+
+    /// Policy file with access rules for clients by IP address or network
+    #[arg(long)]
+    policy: Option<PathBuf>,
+
+    /// Apply the owner sent with `--preserve owner`, and keep setuid/setgid bits (which are cleared otherwise)
+    #[arg(long)]
+    preserve_owner: bool,
+
+    /// Apply extended attributes of all namespaces, including ACLs and capabilities (only `user.*` otherwise)
+    #[arg(long)]
+    preserve_xattrs: bool,
+
+    /// Disk space to always keep free on the receiving filesystem (e.g. 512M, 2G)
+    #[arg(long, default_value = "0", value_parser = parse_size)]
+    reserve: u64,
+
+    /// Maximum bytes each client may send within the quota window (e.g. 10G)
+    #[arg(long, value_parser = parse_size)]
+    quota_bytes: Option<u64>,
+
+    /// Maximum number of files each client may send within the quota window
+    #[arg(long)]
+    quota_files: Option<u64>,
+
+    /// Length of the rolling quota window in seconds
+    #[arg(long, default_value = "3600")]
+    quota_window: u64,
+
+    /// Maximum number of connections handled at once. Further connections wait until one finishes
+    #[arg(long, default_value = "64")]
+    max_connections: usize,
+
+    /// Maximum number of concurrent connections from a single client address (0 for unlimited)
+    #[arg(long, default_value = "8")]
+    max_per_client: usize,
+
+    /// Seconds to let active transfers finish when shutting down before aborting them
+    #[arg(long, default_value = "30")]
+    shutdown_timeout: u64,
+
+    /// Command run before accepting a transfer. A non-zero exit code refuses it
+    #[arg(long)]
+    pre_accept: Option<String>,
+
+    /// Command run in the background after a file has been received
+    #[arg(long)]
+    post_receive: Option<String>,
+
+    /// Seconds a hook may run before it is killed (a pre-accept hook that times out refuses the transfer)
+    #[arg(long, default_value = "30")]
+    hook_timeout: u64,
+
+    /// Number of backups kept of each file (0 for unlimited)
+    #[arg(long, default_value = "5")]
+    backup_keep: usize,
+
+    /// Remove backups older than this many days
+    #[arg(long)]
+    backup_max_age: Option<u64>,
+
+    /// Ask on the terminal before accepting each incoming transfer
+    #[arg(short, long)]
+    interactive: bool,
+
+    /// Seconds to wait for an answer to `--interactive` prompts before rejecting the transfer
+    #[arg(long, default_value = "60")]
+    prompt_timeout: u64,
+
+    /// Hold received files in this directory until they are approved with `teleporter inbox`
+    #[arg(long)]
+    quarantine: Option<PathBuf>,
+
+    /// Template for where received files are stored, e.g. `{identity}/{date}/{filename}`, where `{identity}` is the name of the matching policy rule (see README)
+    #[arg(long, value_parser = place::Template::parse)]
+    place: Option<place::Template>,
+
+    /// Announce the listener on the local network, so that clients can find it with `scan --passive` or `send -d @name`
+    #[arg(long)]
+    beacon: bool,
+
+    /// Name announced by `--beacon` and in replies to pings (defaults to the hostname)
+    #[arg(long)]
+    name: Option<String>,
+}
+
+#[derive(Clone, Debug, Parser, PartialEq, Eq)]
+pub struct RemoteOpt {
+    /// Destination teleporter host, or @name (or @name#fingerprint) of a listener announcing itself with `--beacon`
+    #[arg(short, long, default_value = "localhost")]
+    dest: String,
+
+    /// Destination teleporter port
+    #[arg(short, long, default_value = "9001")]
+    port: u16,
+
+    /// Encrypt the connection using ECDH key-exchange and random keys
+    #[arg(short, long)]
+    encrypt: bool,
+
+    /// Name of the share on the server the file is in
+    #[arg(long)]
+    share: Option<String>,
+
+    #[command(subcommand)]
+    action: RemoteAction,
+}
+
+#[derive(Clone, Debug, Parser, PartialEq, Eq)]
+pub enum RemoteAction {
+    /// Roll a file back to one of its backups
+    Restore {
+        /// Path of the file on the server
+        file: String,
+
+        /// Backup to restore, counting back from the most recent one
+        #[arg(short, long, default_value = "1", value_parser = clap::value_parser!(u16).range(1..))]
+        generation: u16,
+    },
+}
+
+#[derive(Clone, Debug, Parser, PartialEq, Eq)]
+pub struct InboxOpt {
+    /// Quarantine directory of the listener
+    #[arg(short, long)]
+    quarantine: PathBuf,
+
+    #[command(subcommand)]
+    action: InboxAction,
+}
+
+#[derive(Clone, Debug, Parser, PartialEq, Eq)]
+pub enum InboxAction {
+    /// List the files waiting for approval
+    List,
+    /// Move files to their destination
+    Approve {
+        /// IDs of the files, as shown by `list`
+        #[arg(required = true)]
+        ids: Vec<String>,
+    },
+    /// Delete files without moving them to their destination
+    Reject {
+        /// IDs of the files, as shown by `list`
+        #[arg(required = true)]
+        ids: Vec<String>,
+    },
+}
+
+fn parse_size(size: &str) -> Result<u64, String> {
+    utils::parse_size(size).ok_or_else(|| format!("invalid size: {size}"))
 }
 
 #[derive(Clone, Debug, Parser, PartialEq, Eq)]
@@ -76,4 +288,56 @@ pub struct ScanOpt {
     /// Port to scan for
     #[arg(short, long, default_value = "9001")]
     port: u16,
+
+    /// Number of hosts probed at once
+    #[arg(short, long, default_value = "256", value_parser = clap::value_parser!(u16).range(1..))]
+    concurrency: u16,
+
+    /// Milliseconds to wait for each host to answer
+    #[arg(short, long, default_value = "500")]
+    timeout: u64,
+
+    /// Stop scanning after this many seconds
+    #[arg(long)]
+    deadline: Option<u64>,
+
+    /// Probe every address over TCP instead of only the hosts that answer ARP
+    #[arg(long)]
+    no_arp: bool,
+
+    /// Listen for listeners announcing themselves with `--beacon` instead of probing
+    #[arg(long)]
+    passive: bool,
+
+    /// Only scan the networks of this interface (may be repeated)
+    #[arg(long)]
+    interface: Vec<String>,
+
+    /// Scan this network, e.g. 192.168.1.0/24 (may be repeated)
+    #[arg(long)]
+    cidr: Vec<IpNetwork>,
+
+    /// Scan the addresses, networks and host names listed in this file, one per line
+    #[arg(long)]
+    hosts_file: Option<PathBuf>,
+
+    /// Refuse to scan more addresses than this
+    #[arg(long, default_value = "65536")]
+    max_hosts: u64,
+
+    /// Print results as JSON, one object per line
+    #[arg(long)]
+    json: bool,
+
+    /// Keep scanning, printing listeners as they appear, disappear or change
+    #[arg(long, conflicts_with = "passive")]
+    watch: bool,
+
+    /// Seconds between the scans of `--watch`
+    #[arg(long, default_value = "30", value_parser = clap::value_parser!(u64).range(1..))]
+    interval: u64,
+
+    /// Keep the listeners found by `--watch` in this file, as JSON
+    #[arg(long, requires = "watch")]
+    state_file: Option<PathBuf>,
 }
@@ -1,20 +1,53 @@
+use crate::atomic::AtomicFile;
+use crate::backup::Retention;
 use crate::errors::TeleportError;
+use crate::hooks::Hooks;
+use crate::place::{Template, Vars};
+use crate::policy::{Policy, Rule};
+use crate::pool::Pool;
+use crate::quota::Quota;
+use crate::sandbox::{Dir, Location, Sandbox};
+use crate::share::Share;
+use crate::teleport::TeleportHole;
+use crate::teleport::TeleportRestore;
+use crate::teleport::{TeleportAbort, TeleportAbortCode};
 use crate::teleport::{TeleportAction, TeleportEnc, TeleportFeatures, TeleportStatus};
-use crate::teleport::{TeleportData, TeleportDelta, TeleportInit, TeleportInitAck};
+use crate::teleport::{TeleportCapabilities, TeleportCapabilityFlags};
+use crate::teleport::{TeleportData, TeleportDelta, TeleportInit, TeleportInitAck, TeleportLink};
+use crate::teleport::{ABORT_REVISION, CONFIRM_REVISION, LEGACY_REVISION, PROTOCOL_REVISION};
 use crate::ListenOpt;
 use crate::VERSION;
-use crate::{crypto, utils};
-use semver::Version;
+use crate::{
+    backup, beacon, crypto, hooks, inbox, metadata, sandbox, share, signals, sparse, utils,
+};
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsStr;
 use std::fs;
-use std::fs::{File, OpenOptions};
 use std::io;
 use std::io::{Seek, SeekFrom, Write};
-use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
 use std::os::unix::fs::PermissionsExt;
 use std::path::Path;
-use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
 use std::thread;
-use std::time::Instant;
+use std::time::{Duration, Instant};
+
+/// How often the accept loop checks for signals while idle
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long cancelled transfers get to tell their clients on shutdown
+const CANCEL_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// How long a connection may stall before it is dropped, so that idle
+/// clients cannot hold on to workers. Generous, as clients hash large files
+/// before sending their data.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Workers answering clients that are over their connection limit, and how
+/// long those clients get to send their request
+const TURN_AWAY_WORKERS: usize = 2;
+const TURN_AWAY_TIMEOUT: Duration = Duration::from_secs(5);
 
 /// Server function sets up a listening socket for any incoming connnections
 pub fn run(opt: ListenOpt) -> Result<(), TeleportError> {
@@ -33,30 +66,112 @@ pub fn run(opt: ListenOpt) -> Result<(), TeleportError> {
         },
     };
 
+    // Poll for connections so that signals are noticed
+    listener.set_nonblocking(true)?;
+    signals::install()?;
+
+    // All received paths are resolved relative to the root of a share
+    let server = Arc::new(Server::new(&opt)?);
+
     // Print welcome banner
     println!(
         "Teleporter Server {} listening for connections on 0.0.0.0:{}",
         VERSION, &opt.port
     );
+    println!("Receiving files into: {}", opt.root.display());
+    server.config().print();
+    if let Some(path) = &opt.quarantine {
+        println!("Holding received files for approval in: {}", path.display());
+    }
+    if opt.interactive && unsafe { libc::isatty(libc::STDIN_FILENO) } == 0 {
+        println!("Warning: `--interactive` without a terminal, transfers are rejected unless answered on stdin");
+    }
 
     // Print warning banner for dangerous options
     if opt.allow_dangerous_filepath {
         println!("Warning: `--allow-dangerous-filepath` is ENABLED. This is a potentially dangerous option, use at your own risk!");
     }
 
+    // Let clients find the listener without probing
+    let announcer = match opt.beacon {
+        true => {
+            let beacon = beacon::local(opt.port, opt.must_encrypt, opt.name.as_deref());
+            println!(
+                "Announcing as {} on the local network (fingerprint {:016x})",
+                String::from_utf8_lossy(&beacon.name),
+                beacon.fingerprint
+            );
+            match beacon::announce(beacon) {
+                Ok(h) => Some(h),
+                Err(e) => {
+                    println!("Error: unable to announce the listener: {e}");
+                    None
+                }
+            }
+        }
+        false => None,
+    };
+
     let recv_list = Arc::new(Mutex::new(Vec::<String>::new()));
 
-    // Listen for incoming connections
-    for stream in listener.incoming() {
-        let args = opt.clone();
-        let s = match stream {
-            Ok(s) => s,
+    // Connections are handled by a fixed number of workers. While all of them
+    // are busy, new connections wait in the kernel backlog.
+    let pool = Pool::new(opt.max_connections, 0);
+    let turn_away_pool = Pool::new(TURN_AWAY_WORKERS, 0);
+
+    // Listen for incoming connections until asked to shut down
+    while signals::shutdown_count() == 0 {
+        if signals::take_reload() {
+            server.reload(&opt);
+        }
+
+        if !pool.has_idle_worker() {
+            thread::sleep(POLL_INTERVAL);
+            continue;
+        }
+
+        let (s, addr) = match listener.accept() {
+            Ok(a) => a,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(POLL_INTERVAL);
+                continue;
+            }
             _ => continue,
         };
+        if s.set_nonblocking(false).is_err()
+            || s.set_read_timeout(Some(IDLE_TIMEOUT)).is_err()
+            || s.set_write_timeout(Some(IDLE_TIMEOUT)).is_err()
+        {
+            continue;
+        }
+
+        // Limit the concurrent connections of each client before it gets a
+        // worker, so that a single client cannot occupy all of them
+        let slot = match server.clients.enter(addr.ip(), opt.max_per_client) {
+            Some(s) => s,
+            None => {
+                println!(
+                    "\r => Too many connections from {}",
+                    utils::canonical_ip(addr.ip())
+                );
+                // Ask the client to retry later if a worker is free for it
+                if turn_away_pool.has_idle_worker() {
+                    turn_away_pool.execute(move || {
+                        let _ = turn_away(s);
+                    });
+                }
+                continue;
+            }
+        };
+
+        let args = opt.clone();
         // Receive connections in recv function
         let recv_list_clone = Arc::clone(&recv_list);
-        thread::spawn(move || {
-            if let Err(e) = handle_connection(s, &recv_list_clone, args) {
+        let server = Arc::clone(&server);
+        pool.execute(move || {
+            let _slot = slot;
+            let _stream = server.streams.add(&s);
+            if let Err(e) = handle_connection(s, &recv_list_clone, &server, args) {
                 println!("Error: {e:?}");
             }
             let recv_list = recv_list_clone
@@ -66,9 +181,413 @@ pub fn run(opt: ListenOpt) -> Result<(), TeleportError> {
         });
     }
 
+    // Stop accepting, then give active transfers time to finish
+    drop(listener);
+    let deadline = Instant::now() + Duration::from_secs(opt.shutdown_timeout);
+    let active = server.streams.len();
+    if active > 0 {
+        println!(
+            "\nShutting down: waiting up to {}s for {} active connections (signal again to abort them)",
+            opt.shutdown_timeout, active
+        );
+    }
+    while (server.streams.len() > 0 || server.hooks.running() > 0)
+        && Instant::now() < deadline
+        && signals::shutdown_count() < 2
+    {
+        thread::sleep(POLL_INTERVAL);
+    }
+
+    // Abort what is left, which removes any partially received files.
+    // Transfers get a moment to tell their clients first.
+    let aborted = server.streams.len();
+    server.streams.cancel();
+    let deadline = Instant::now() + CANCEL_TIMEOUT;
+    while server.streams.len() > 0 && Instant::now() < deadline {
+        thread::sleep(POLL_INTERVAL);
+    }
+    server.streams.shutdown();
+    drop(pool);
+    drop(turn_away_pool);
+    if let Some(announcer) = announcer {
+        let _ = announcer.join();
+    }
+
+    println!(
+        "\nShutdown complete: received {} files ({} bytes), {} failed, {} aborted",
+        server.stats.files.load(Ordering::SeqCst),
+        server.stats.bytes.load(Ordering::SeqCst),
+        server.stats.failed.load(Ordering::SeqCst),
+        aborted
+    );
+
     Ok(())
 }
 
+/// Receive roots and rules, reloaded on SIGHUP
+struct Config {
+    root: Sandbox,
+    shares: BTreeMap<String, (Share, Sandbox)>,
+    policy: Option<Policy>,
+}
+
+impl Config {
+    fn load(opt: &ListenOpt) -> Result<Config, TeleportError> {
+        let mut shares = BTreeMap::new();
+        if let Some(file) = &opt.shares {
+            for share in share::load(file)? {
+                let sandbox = open_root(&share.path, opt)?;
+                shares.insert(share.name.clone(), (share, sandbox));
+            }
+        }
+
+        let policy = match &opt.policy {
+            Some(file) => Some(Policy::load(file)?),
+            None => None,
+        };
+
+        Ok(Config {
+            root: open_root(&opt.root, opt)?,
+            shares,
+            policy,
+        })
+    }
+
+    fn print(&self) {
+        for (share, _) in self.shares.values() {
+            println!(
+                "Share [{}]: {}{}",
+                share.name,
+                share.path.display(),
+                if share.read_only { " (read-only)" } else { "" }
+            );
+        }
+
+        if let Some(policy) = &self.policy {
+            println!("Access policy loaded with {} rules", policy.rules.len());
+        }
+    }
+}
+
+/// Files received since startup
+#[derive(Debug, Default)]
+struct Stats {
+    files: AtomicU64,
+    bytes: AtomicU64,
+    failed: AtomicU64,
+}
+
+/// State shared by all connections
+struct Server {
+    config: RwLock<Arc<Config>>,
+    quota: Quota,
+    clients: Arc<Clients>,
+    streams: Streams,
+    stats: Stats,
+    hooks: Hooks,
+    quarantine: Option<Dir>,
+    retention: Retention,
+    trust: metadata::Trust,
+    /// Held while asking the operator, so that only one prompt is shown at a time
+    prompt: Mutex<()>,
+}
+
+impl Server {
+    fn new(opt: &ListenOpt) -> Result<Server, TeleportError> {
+        Ok(Server {
+            config: RwLock::new(Arc::new(Config::load(opt)?)),
+            quota: Quota::new(
+                opt.quota_bytes,
+                opt.quota_files,
+                Duration::from_secs(opt.quota_window),
+            ),
+            clients: Arc::default(),
+            streams: Streams::default(),
+            stats: Stats::default(),
+            hooks: Hooks::new(
+                opt.pre_accept.clone(),
+                opt.post_receive.clone(),
+                Duration::from_secs(opt.hook_timeout),
+            ),
+            quarantine: match &opt.quarantine {
+                Some(path) => Some(inbox::open(path)?),
+                None => None,
+            },
+            retention: Retention {
+                keep: opt.backup_keep,
+                max_age: opt.backup_max_age.map(|days| days * 86400),
+            },
+            trust: metadata::Trust {
+                owner: opt.preserve_owner,
+                xattrs: opt.preserve_xattrs,
+            },
+            prompt: Mutex::new(()),
+        })
+    }
+
+    /// The current configuration. Connections keep the configuration they
+    /// started with, even if it is reloaded meanwhile.
+    fn config(&self) -> Arc<Config> {
+        Arc::clone(&self.config.read().expect("Fatal error locking config"))
+    }
+
+    fn reload(&self, opt: &ListenOpt) {
+        println!("\rReloading configuration");
+        match Config::load(opt) {
+            Ok(config) => {
+                config.print();
+                *self.config.write().expect("Fatal error locking config") = Arc::new(config);
+            }
+            Err(e) => println!("Error reloading configuration, keeping the previous one: {e}"),
+        }
+    }
+}
+
+/// Active connections, so that they can be aborted on shutdown
+#[derive(Debug, Default)]
+struct Streams {
+    list: Mutex<HashMap<u64, TcpStream>>,
+    next_id: AtomicU64,
+    cancelled: AtomicBool,
+}
+
+/// A registered connection, removed when dropped
+struct StreamEntry<'a> {
+    streams: &'a Streams,
+    id: u64,
+}
+
+impl Streams {
+    fn add(&self, stream: &TcpStream) -> Option<StreamEntry<'_>> {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let clone = stream.try_clone().ok()?;
+        self.list
+            .lock()
+            .expect("Fatal error locking streams")
+            .insert(id, clone);
+
+        Some(StreamEntry { streams: self, id })
+    }
+
+    fn len(&self) -> usize {
+        self.list.lock().expect("Fatal error locking streams").len()
+    }
+
+    /// Ask active transfers to stop, telling their clients why
+    fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    fn cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Shut down all active connections
+    fn shutdown(&self) {
+        let list = self.list.lock().expect("Fatal error locking streams");
+        for stream in list.values() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+impl Drop for StreamEntry<'_> {
+    fn drop(&mut self) {
+        self.streams
+            .list
+            .lock()
+            .expect("Fatal error locking streams")
+            .remove(&self.id);
+    }
+}
+
+fn open_root(path: &Path, opt: &ListenOpt) -> Result<Sandbox, TeleportError> {
+    match Sandbox::new(path, opt.allow_dangerous_filepath) {
+        Ok(s) => Ok(s),
+        Err(e) => {
+            println!("Cannot open receive directory: {}", path.display());
+            Err(TeleportError::Io(e))
+        }
+    }
+}
+
+/// Describe what the listener offers a client, or nothing if the access
+/// policy has no rule for it
+fn capabilities(
+    opt: &ListenOpt,
+    server: &Server,
+    config: &Config,
+    ip: IpAddr,
+) -> Option<TeleportCapabilities> {
+    let mut features = TeleportFeatures::ALL
+        .iter()
+        .fold(0, |all, f| all | *f as u32);
+
+    let mut overwrite = true;
+    if let Some(policy) = &config.policy {
+        let rule = policy.rule(ip)?;
+        overwrite = rule.overwrite;
+        if !rule.backup {
+            features &= !(TeleportFeatures::Backup as u32);
+        }
+        if !rule.rename {
+            features &= !(TeleportFeatures::Rename as u32);
+        }
+    }
+
+    let mut flags = 0;
+    if opt.must_encrypt {
+        flags |= TeleportCapabilityFlags::MustEncrypt as u8;
+    }
+    if overwrite {
+        flags |= TeleportCapabilityFlags::Overwrite as u8;
+    }
+
+    let free_space = match &server.quarantine {
+        Some(q) => q.free_space(),
+        None => config.root.free_space(),
+    };
+    let name = opt.name.clone().unwrap_or_else(utils::hostname);
+
+    Some(TeleportCapabilities {
+        features,
+        flags,
+        free_space: free_space.unwrap_or(u64::MAX),
+        name: name.into_bytes(),
+        shares: config
+            .shares
+            .values()
+            .filter(|(share, _)| !share.read_only)
+            .map(|(share, _)| share.name.clone().into_bytes())
+            .collect(),
+    })
+}
+
+/// Build the path a transfer is stored at from the placement template
+fn place(
+    template: &Template,
+    path: &str,
+    ip: IpAddr,
+    header: &TeleportInit,
+    config: &Config,
+) -> Result<String, TeleportError> {
+    let path = sandbox::normalize(path)?;
+    let peer_ip = utils::canonical_ip(ip).to_string();
+    let identity = match config.policy.as_ref().and_then(|p| p.rule(ip)) {
+        Some(rule) => rule.name.clone(),
+        None => peer_ip.clone(),
+    };
+    let share = header
+        .share
+        .as_ref()
+        .map_or(String::new(), |s| String::from_utf8_lossy(s).to_string());
+
+    Ok(template.expand(&Vars {
+        peer_ip,
+        identity: &identity,
+        share: &share,
+        time: utils::now(),
+        path: &path,
+    }))
+}
+
+/// Check where a request really ends up against the policy rule for the
+/// client, since symlinks already in the root may lead out of the paths the
+/// rule allows. Link targets must be allowed as well.
+fn check_location(
+    rule: &Rule,
+    ip: IpAddr,
+    real: &Path,
+    action: u8,
+    target: Option<&[u8]>,
+    sandbox: &Sandbox,
+) -> TeleportStatus {
+    if !rule.allows(real) {
+        println!(
+            " => Denied {ip} writing to {} (rule [{}])",
+            real.display(),
+            rule.name
+        );
+        return TeleportStatus::PathNotAllowed;
+    }
+
+    let target = match target {
+        Some(t) => String::from_utf8_lossy(t).to_string(),
+        None => return TeleportStatus::Proceed,
+    };
+    let linked = if action == TeleportAction::Symlink as u8 {
+        // Symlink targets are relative to the directory of the link
+        let parent = real.parent().unwrap_or_else(|| Path::new(""));
+        utils::join_within(parent, Path::new(&target)).map(|t| {
+            sandbox
+                .resolve(&t.to_string_lossy(), false)
+                .map_or(t, |l| l.real)
+        })
+    } else {
+        sandbox.resolve(&target, false).ok().map(|l| l.real)
+    };
+    if !linked.map_or(rule.paths.is_empty(), |t| rule.allows(&t)) {
+        println!(
+            " => Denied {ip} linking {} to {target} (rule [{}])",
+            real.display(),
+            rule.name
+        );
+        return TeleportStatus::PathNotAllowed;
+    }
+
+    TeleportStatus::Proceed
+}
+
+/// Check a request against the policy rule for the client, dropping any
+/// features the rule does not allow
+fn check_policy(
+    policy: &Policy,
+    ip: IpAddr,
+    filename: &str,
+    header: &mut TeleportInit,
+) -> TeleportStatus {
+    let rule = match policy.rule(ip) {
+        Some(r) => r,
+        None => {
+            println!(" => Denied access to {ip}: no matching policy rule");
+            return TeleportStatus::AccessDenied;
+        }
+    };
+
+    if !rule.allows_path(filename) {
+        println!(
+            " => Denied {ip} writing to {filename} (rule [{}])",
+            rule.name
+        );
+        return TeleportStatus::PathNotAllowed;
+    }
+
+    if rule.max_size.map_or(false, |max| header.filesize > max) {
+        println!(
+            " => Denied {ip} sending {filename}: {} bytes is over the limit (rule [{}])",
+            header.filesize, rule.name
+        );
+        return TeleportStatus::TooLarge;
+    }
+
+    if (TeleportFeatures::Backup.check_u32(header.features) && !rule.backup)
+        || (TeleportFeatures::Rename.check_u32(header.features) && !rule.rename)
+    {
+        println!(
+            " => Denied {ip} backup or rename of {filename} (rule [{}])",
+            rule.name
+        );
+        return TeleportStatus::FeatureDenied;
+    }
+
+    // Existing files are then refused with NoOverwrite
+    if !rule.overwrite {
+        header.features &= !(TeleportFeatures::Overwrite as u32);
+    }
+
+    TeleportStatus::Proceed
+}
+
 fn send_ack(
     ack: TeleportInitAck,
     stream: &mut TcpStream,
@@ -78,8 +597,41 @@ fn send_ack(
     utils::send_packet(stream, TeleportAction::InitAck, enc, ack.serialize()?)
 }
 
+/// Answer the key exchange started by the client
+fn key_exchange(stream: &mut TcpStream, data: &[u8]) -> Result<TeleportEnc, TeleportError> {
+    let mut ctx = TeleportEnc::new();
+    let privkey = crypto::genkey(&mut ctx);
+    ctx.deserialize(data)?;
+    ctx.calc_secret(privkey);
+    utils::send_packet(stream, TeleportAction::EcdhAck, &None, ctx.serialize())?;
+    Ok(ctx)
+}
+
+/// Answer the request of a client that is over its connection limit with
+/// `Busy`, so that it retries later
+fn turn_away(mut stream: TcpStream) -> Result<(), TeleportError> {
+    stream.set_read_timeout(Some(TURN_AWAY_TIMEOUT))?;
+    stream.set_write_timeout(Some(TURN_AWAY_TIMEOUT))?;
+
+    let mut enc: Option<TeleportEnc> = None;
+    let mut packet = utils::recv_packet(&mut stream, &None)?;
+    if packet.action == TeleportAction::Ecdh as u8 {
+        enc = Some(key_exchange(&mut stream, &packet.data)?);
+        packet = utils::recv_packet(&mut stream, &enc)?;
+    }
+    if packet.action == TeleportAction::Ping as u8 {
+        return Ok(());
+    }
+
+    send_ack(
+        TeleportInitAck::new(TeleportStatus::Busy),
+        &mut stream,
+        &enc,
+    )
+}
+
 fn print_list(list: &MutexGuard<Vec<String>>) {
-    if list.len() == 0 {
+    if list.is_empty() {
         print!("\rListening...");
     } else {
         print!("\rReceiving: {list:?}");
@@ -87,18 +639,321 @@ fn print_list(list: &MutexGuard<Vec<String>>) {
     io::stdout().flush().expect("Fatal error flushing stdout");
 }
 
-fn rm_filename_from_list(filename: &str, list: &Arc<Mutex<Vec<String>>>) {
-    let mut recv_data = list.lock().expect("Fatal error locking file list");
-    recv_data.retain(|x| x != filename);
+/// An entry in the list of files being received, removed when dropped
+struct Receiving<'a> {
+    list: &'a Mutex<Vec<String>>,
+    filename: String,
+}
+
+impl<'a> Receiving<'a> {
+    fn add(list: &'a Mutex<Vec<String>>, filename: &str) -> Self {
+        let mut recv_data = list.lock().expect("Fatal error locking recv_list");
+        recv_data.push(filename.to_string());
+        print_list(&recv_data);
+
+        Receiving {
+            list,
+            filename: filename.to_string(),
+        }
+    }
+}
+
+impl Drop for Receiving<'_> {
+    fn drop(&mut self) {
+        // Only remove this transfer, others may be receiving the same name
+        let mut recv_data = self.list.lock().expect("Fatal error locking file list");
+        if let Some(pos) = recv_data.iter().position(|x| *x == self.filename) {
+            recv_data.remove(pos);
+        }
+    }
+}
+
+/// Number of active connections from each client address
+#[derive(Debug, Default)]
+struct Clients {
+    active: Mutex<HashMap<IpAddr, usize>>,
+}
+
+/// A connection counted against its client, released when dropped
+struct Slot {
+    clients: Arc<Clients>,
+    ip: IpAddr,
+}
+
+impl Clients {
+    /// Count a new connection, unless the client already has `max` active
+    /// connections. A `max` of 0 is unlimited.
+    fn enter(self: &Arc<Self>, ip: IpAddr, max: usize) -> Option<Slot> {
+        let ip = utils::canonical_ip(ip);
+        let mut active = self.active.lock().expect("Fatal error locking clients");
+        let count = active.entry(ip).or_insert(0);
+        if max > 0 && *count >= max {
+            return None;
+        }
+        *count += 1;
+
+        Some(Slot {
+            clients: Arc::clone(self),
+            ip,
+        })
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let mut active = self
+            .clients
+            .active
+            .lock()
+            .expect("Fatal error locking clients");
+        if let Some(count) = active.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                active.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// Answer of the operator to an incoming transfer
+enum Decision {
+    Accept,
+    Rename(String),
+    Reject,
+}
+
+/// Ask the operator whether to accept a transfer. Connections wait for their
+/// turn while another prompt is shown. Transfers that get no answer within
+/// `timeout` are rejected.
+fn ask_operator(server: &Server, question: &str, timeout: Duration) -> Decision {
+    let _prompt = server.prompt.lock().expect("Fatal error locking prompt");
+    println!("{question}");
+    let deadline = Instant::now() + timeout;
+
+    loop {
+        print!("Accept? [y]es / [n]o / [r]ename: ");
+        io::stdout().flush().expect("Fatal error flushing stdout");
+        let answer = match read_line(deadline) {
+            Some(a) => a,
+            None => return no_answer(deadline),
+        };
+
+        match answer.trim().to_ascii_lowercase().as_str() {
+            "y" | "yes" => return Decision::Accept,
+            "n" | "no" => return Decision::Reject,
+            "r" | "rename" => {
+                print!("New name: ");
+                io::stdout().flush().expect("Fatal error flushing stdout");
+                match read_line(deadline) {
+                    Some(n) if !n.trim().is_empty() => {
+                        return Decision::Rename(n.trim().to_string())
+                    }
+                    Some(_) => continue,
+                    None => return no_answer(deadline),
+                }
+            }
+            _ => continue,
+        }
+    }
+}
+
+fn no_answer(deadline: Instant) -> Decision {
+    if Instant::now() >= deadline {
+        println!("\n => No answer in time, rejecting");
+    }
+    Decision::Reject
+}
+
+/// Read a line from stdin, giving up at the end of input, on shutdown or
+/// once the deadline passed
+fn read_line(deadline: Instant) -> Option<String> {
+    let mut fds = libc::pollfd {
+        fd: libc::STDIN_FILENO,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    let timeout = POLL_INTERVAL.as_millis() as libc::c_int;
+
+    while signals::shutdown_count() == 0 && Instant::now() < deadline {
+        match unsafe { libc::poll(&mut fds, 1, timeout) } {
+            0 => continue,
+            n if n < 0 && io::Error::last_os_error().kind() == io::ErrorKind::Interrupted => {
+                continue
+            }
+            n if n < 0 => return None,
+            _ => {
+                let mut line = String::new();
+                return match io::stdin().read_line(&mut line) {
+                    Ok(0) | Err(_) => None,
+                    Ok(_) => Some(line),
+                };
+            }
+        }
+    }
+
+    None
+}
+
+fn make_dir(loc: &Location, header: &TeleportInit) -> TeleportStatus {
+    let filename = loc.display();
+    match loc.dir.metadata(&loc.name) {
+        Ok(m) if !m.is_dir() => {
+            println!(" => Refusing to replace file with directory: {filename}");
+            return TeleportStatus::NoOverwrite;
+        }
+        Ok(_) => (),
+        Err(_) => {
+            if loc.dir.mkdir(&loc.name, header.chmod).is_err() {
+                println!("Error: unable to create directory: {filename}");
+                return TeleportStatus::NoPermission;
+            }
+        }
+    }
+
+    let dir = match loc.dir.open_dir(&loc.name) {
+        Ok(d) => d,
+        Err(_) => {
+            println!("Error: unable to open directory: {filename}");
+            return TeleportStatus::NoPermission;
+        }
+    };
+    if dir
+        .file()
+        .set_permissions(fs::Permissions::from_mode(header.chmod))
+        .is_err()
+    {
+        println!("Error: unable to set permissions of directory: {filename}");
+        return TeleportStatus::NoPermission;
+    }
+
+    if let Some(meta) = &header.metadata {
+        metadata::apply(dir.file(), meta, header.chmod, &filename);
+    }
+
+    TeleportStatus::Proceed
+}
+
+fn make_link(
+    action: u8,
+    loc: &Location,
+    target: String,
+    header: &TeleportInit,
+    sandbox: &Sandbox,
+    opt: &ListenOpt,
+) -> TeleportStatus {
+    let filename = loc.display();
+
+    // Replace an existing file only if overwrite was requested
+    if let Ok(meta) = loc.dir.metadata(&loc.name) {
+        if !TeleportFeatures::Overwrite.check_u32(header.features) || meta.is_dir() {
+            println!(" => Refusing to overwrite file: {filename}");
+            return TeleportStatus::NoOverwrite;
+        }
+        if loc.dir.remove(&loc.name).is_err() {
+            return TeleportStatus::NoPermission;
+        }
+    }
+
+    if action == TeleportAction::Symlink as u8 {
+        // Symlinks must not point outside of the receive root. Measure from
+        // where the link really is, as the path may pass through symlinks.
+        let parent = loc.real.parent().unwrap_or_else(|| Path::new(""));
+        if !opt.allow_dangerous_filepath && !utils::stays_within(parent, Path::new(&target)) {
+            println!(" => Refusing symlink pointing outside of root: {filename} -> {target}");
+            return TeleportStatus::BadFileName;
+        }
+        if loc.dir.symlink(OsStr::new(&target), &loc.name).is_err() {
+            println!("Error: unable to create symlink: {filename}");
+            return TeleportStatus::NoPermission;
+        }
+        if let Some(meta) = &header.metadata {
+            metadata::apply_link(&loc.dir, &loc.name, meta, &filename);
+        }
+    } else {
+        let from = match sandbox.resolve(&target, false) {
+            Ok(l) => l,
+            Err(e) => {
+                println!(" => Refusing hard link: {filename} => {target} ({e})");
+                return TeleportStatus::BadFileName;
+            }
+        };
+        if loc.dir.link(&from.dir, &from.name, &loc.name).is_err() {
+            println!("Error: unable to create hard link: {filename} => {target}");
+            return TeleportStatus::NoPermission;
+        }
+    }
+
+    TeleportStatus::Proceed
+}
+
+/// Replace a file with one of its backups, counting back from the most
+/// recent one. The current contents are kept as a new backup.
+fn restore(
+    loc: &Location,
+    generation: u16,
+    features: u32,
+    retention: &Retention,
+) -> TeleportStatus {
+    let filename = loc.display();
+    if !TeleportFeatures::Overwrite.check_u32(features) {
+        println!(" => Refusing to overwrite file: {filename}");
+        return TeleportStatus::NoOverwrite;
+    }
+
+    let generations = backup::list(&loc.dir, &loc.name).unwrap_or_default();
+    let from = match usize::from(generation)
+        .checked_sub(1)
+        .and_then(|i| generations.get(i))
+    {
+        Some(g) => g,
+        None => {
+            println!(" => No backup generation {generation} of: {filename}");
+            return TeleportStatus::NoBackup;
+        }
+    };
+
+    match restore_from(loc, &from.name, retention) {
+        Ok(_) => {
+            println!(
+                " => Restored {filename} from: {}",
+                from.name.to_string_lossy()
+            );
+            TeleportStatus::Proceed
+        }
+        Err(e) => {
+            println!("Error: unable to restore {filename}: {e}");
+            TeleportStatus::NoPermission
+        }
+    }
+}
+
+fn restore_from(loc: &Location, from: &OsStr, retention: &Retention) -> Result<(), TeleportError> {
+    let src = loc.dir.open_file(from, libc::O_RDONLY, 0)?;
+    let mut tmp = AtomicFile::create(loc)?;
+    tmp.clone_from(&src)?;
+
+    // The backup was the file before, so it is trusted with all of it
+    let trust = metadata::Trust {
+        owner: true,
+        xattrs: true,
+    };
+    metadata::inherit(&tmp.file, &src, &trust, &loc.path.display().to_string());
+
+    backup::create(loc, retention)?;
+    tmp.persist()?;
+
+    Ok(())
 }
 
 fn handle_connection(
     mut stream: TcpStream,
     recv_list: &Arc<Mutex<Vec<String>>>,
+    server: &Server,
     opt: ListenOpt,
 ) -> Result<(), TeleportError> {
     let start_time = Instant::now();
     let ip = stream.peer_addr()?;
+    let config = server.config();
 
     let mut enc: Option<TeleportEnc> = None;
 
@@ -114,7 +969,8 @@ fn handle_connection(
             "\rPing received from Teleporter v{} at {}",
             ping.version, ip
         );
-        let pong = TeleportInitAck::new(TeleportStatus::Pong);
+        let mut pong = TeleportInitAck::new(TeleportStatus::Pong);
+        pong.capabilities = capabilities(&opt, server, &config, ip.ip());
         return utils::send_packet(
             &mut stream,
             TeleportAction::PingAck,
@@ -122,136 +978,441 @@ fn handle_connection(
             pong.serialize()?,
         );
     } else if packet.action == TeleportAction::Ecdh as u8 {
-        let mut ctx = TeleportEnc::new();
-        let privkey = crypto::genkey(&mut ctx);
-        ctx.deserialize(&packet.data)?;
-        ctx.calc_secret(privkey);
-        utils::send_packet(&mut stream, TeleportAction::EcdhAck, &None, ctx.serialize())?;
-        enc = Some(ctx);
+        enc = Some(key_exchange(&mut stream, &packet.data)?);
         packet = utils::recv_packet(&mut stream, &enc)?;
     } else if opt.must_encrypt {
         let resp = TeleportInitAck::new(TeleportStatus::RequiresEncryption);
         return send_ack(resp, &mut stream, &enc);
     }
 
+    let action = packet.action;
     let mut header = TeleportInit::new(TeleportFeatures::NewFile);
-    header.deserialize(&packet.data)?;
-
-    if packet.action != TeleportAction::Init as u8 {
+    let mut target: Option<Vec<u8>> = None;
+    let mut generation: Option<u16> = None;
+    if action == TeleportAction::Init as u8 || action == TeleportAction::Mkdir as u8 {
+        header.deserialize(&packet.data)?;
+    } else if action == TeleportAction::Symlink as u8 || action == TeleportAction::Hardlink as u8 {
+        let mut link = TeleportLink::default();
+        link.deserialize(&packet.data)?;
+        header = link.init;
+        target = Some(link.target);
+    } else if action == TeleportAction::Restore as u8 {
+        let mut req = TeleportRestore::default();
+        req.deserialize(&packet.data)?;
+        header = req.init;
+        generation = Some(req.generation);
+    } else {
         let resp = TeleportInitAck::new(TeleportStatus::EncryptionError);
         return send_ack(resp, &mut stream, &enc);
     }
 
-    let mut filename: String = String::from_utf8(header.filename)?;
-    let features: u32 = header.features;
+    let mut filename: String = String::from_utf8(header.filename.clone())?;
 
-    let version = Version::parse(VERSION).expect("Fatal version error");
-    let compatible = header.version.is_compatible(&version);
+    // Only apply the metadata that clients are trusted with
+    header.chmod = server.trust.mode(header.chmod);
+    if let Some(meta) = &mut header.metadata {
+        server.trust.filter(meta);
+    }
 
-    if !compatible {
+    // Agree on the wire format revision to speak with the client
+    if header.negotiate().is_none() {
         println!(
-            "Error: Version mismatch from: {:?}! Us:{} Client:{}",
-            ip, VERSION, header.version
+            "Error: Version mismatch from: {:?}! Us:{} (revision {}) Client:{} (revision {})",
+            ip,
+            VERSION,
+            PROTOCOL_REVISION,
+            header.version,
+            header.revision.unwrap_or(LEGACY_REVISION)
         );
-        let resp = TeleportInitAck::new(TeleportStatus::WrongVersion);
+        let mut resp = TeleportInitAck::new(TeleportStatus::WrongVersion);
+        resp.revision = Some(PROTOCOL_REVISION);
         return send_ack(resp, &mut stream, &enc);
     }
 
-    if !opt.allow_dangerous_filepath {
-        if filename.starts_with('/') {
-            // Remove any preceeding '/'
-            filename.remove(0);
+    // Store the transfer where the template places it. Restores name the
+    // file where it was placed before.
+    if let (Some(template), None) = (&opt.place, generation) {
+        // Link targets were placed by other transfers, possibly on another
+        // date, and relative symlinks would point elsewhere once placed
+        if action == TeleportAction::Symlink as u8 || action == TeleportAction::Hardlink as u8 {
+            println!(" => Refusing link from {ip} with --place: {filename:?}");
+            let resp = TeleportInitAck::new(TeleportStatus::PathNotAllowed);
+            return send_ack(resp, &mut stream, &enc);
+        }
+        match place(template, &filename, ip.ip(), &header, &config) {
+            Ok(f) => filename = f,
+            Err(e) => {
+                println!(" => Refusing path from {ip}: {filename:?} ({e})");
+                let resp = TeleportInitAck::new(TeleportStatus::BadFileName);
+                return send_ack(resp, &mut stream, &enc);
+            }
+        }
+    }
+
+    // Apply the access policy for this client
+    if let Some(policy) = &config.policy {
+        let status = check_policy(policy, ip.ip(), &filename, &mut header);
+        if status != TeleportStatus::Proceed {
+            return send_ack(TeleportInitAck::new(status), &mut stream, &enc);
+        }
+    }
+
+    // Select the requested share and apply its policy
+    let sandbox = match &header.share {
+        None => &config.root,
+        Some(name) => {
+            let name = String::from_utf8_lossy(name).to_string();
+            let (share, sandbox) = match config.shares.get(&name) {
+                Some(s) => s,
+                None => {
+                    println!(" => Unknown share requested by {ip}: {name}");
+                    let resp = TeleportInitAck::new(TeleportStatus::UnknownShare);
+                    return send_ack(resp, &mut stream, &enc);
+                }
+            };
+            if share.must_encrypt && enc.is_none() {
+                let resp = TeleportInitAck::new(TeleportStatus::RequiresEncryption);
+                return send_ack(resp, &mut stream, &enc);
+            }
+            if share.read_only {
+                println!(" => Refusing upload to read-only share: {name}");
+                let resp = TeleportInitAck::new(TeleportStatus::NoPermission);
+                return send_ack(resp, &mut stream, &enc);
+            }
+            if !share.overwrite {
+                header.features &= !(TeleportFeatures::Overwrite as u32);
+            }
+            if share.backup {
+                TeleportFeatures::Backup.add_u32(&mut header.features);
+            }
+            sandbox
         }
+    };
+    let features: u32 = header.features;
 
-        // Prohibit directory traversal
-        filename = filename.replace("../", "");
+    // Check where the path leads within the root. Nothing is created until
+    // the transfer is accepted.
+    let real = match sandbox.locate(&filename) {
+        Ok(r) => r,
+        Err(TeleportError::Io(e)) => {
+            println!("Error: unable to resolve path: {filename} ({e})");
+            let resp = TeleportInitAck::new(TeleportStatus::NoPermission);
+            return send_ack(resp, &mut stream, &enc);
+        }
+        Err(e) => {
+            println!(" => Refusing path from {ip}: {filename:?} ({e})");
+            let resp = TeleportInitAck::new(TeleportStatus::BadFileName);
+            return send_ack(resp, &mut stream, &enc);
+        }
+    };
+    let shown = sandbox::normalize(&filename)
+        .map_or_else(|_| filename.clone(), |p| p.display().to_string());
+
+    // Names of backups are reserved, since backups are pruned by name
+    if real.file_name().map_or(false, backup::is_backup) {
+        println!(" => Refusing backup name from {ip}: {shown}");
+        let resp = TeleportInitAck::new(TeleportStatus::BadFileName);
+        return send_ack(resp, &mut stream, &enc);
+    }
+
+    // Apply the policy to where the path leads once symlinks are expanded
+    let rule = config.policy.as_ref().and_then(|p| p.rule(ip.ip()));
+    if let Some(rule) = rule {
+        let status = check_location(rule, ip.ip(), &real, action, target.as_deref(), sandbox);
+        if status != TeleportStatus::Proceed {
+            return send_ack(TeleportInitAck::new(status), &mut stream, &enc);
+        }
+    }
+
+    // Let the pre-accept hook veto the transfer
+    let kind = match action {
+        x if x == TeleportAction::Mkdir as u8 => "dir",
+        x if x == TeleportAction::Symlink as u8 => "symlink",
+        x if x == TeleportAction::Hardlink as u8 => "hardlink",
+        x if x == TeleportAction::Restore as u8 => "restore",
+        _ => "file",
+    };
+    let share = header
+        .share
+        .as_ref()
+        .map_or(String::new(), |s| String::from_utf8_lossy(s).to_string());
+    let peer = utils::canonical_ip(ip.ip()).to_string();
+    let env: hooks::Env = vec![
+        ("TELEPORTER_PEER", peer.clone()),
+        ("TELEPORTER_SHARE", share.clone()),
+        ("TELEPORTER_ACTION", kind.to_string()),
+        ("TELEPORTER_FILENAME", shown.clone()),
+        ("TELEPORTER_SIZE", header.filesize.to_string()),
+        ("TELEPORTER_FEATURES", hooks::features(features)),
+        ("TELEPORTER_ENCRYPTED", u8::from(enc.is_some()).to_string()),
+    ];
+    let status = server.hooks.pre_accept(&env);
+    if status != TeleportStatus::Proceed {
+        println!(" => Pre-accept hook refused {shown} from {ip}: {status:?}");
+        return send_ack(TeleportInitAck::new(status), &mut stream, &enc);
+    }
+
+    // Links could expose files that were never approved
+    if server.quarantine.is_some() && target.is_some() {
+        println!(" => Refusing link while quarantining files: {shown}");
+        let resp = TeleportInitAck::new(TeleportStatus::FeatureDenied);
+        return send_ack(resp, &mut stream, &enc);
+    }
+
+    // Let the operator accept, rename or reject the transfer
+    let mut renamed = None;
+    if opt.interactive {
+        // v0.10 clients do not know the status and just wait for the answer
+        if header.revision.is_some() {
+            send_ack(
+                TeleportInitAck::new(TeleportStatus::Pending),
+                &mut stream,
+                &enc,
+            )?;
+        }
+
+        let mut question = format!("\rIncoming {kind} from {peer}");
+        if enc.is_some() {
+            question.push_str(" (encrypted)");
+        }
+        question.push_str(&format!(": {shown}"));
+        if !share.is_empty() {
+            question.push_str(&format!(" [share: {share}]"));
+        }
+        if action == TeleportAction::Init as u8 {
+            question.push_str(&format!(", {}", utils::format_size(header.filesize)));
+        }
+        if let Some(t) = &target {
+            question.push_str(&format!(" -> {}", String::from_utf8_lossy(t)));
+        }
+        if TeleportFeatures::Overwrite.check_u32(features) {
+            question.push_str(", overwrite requested");
+        }
+
+        let timeout = Duration::from_secs(opt.prompt_timeout);
+        match ask_operator(server, &question, timeout) {
+            Decision::Accept => (),
+            Decision::Rename(name) => match sandbox.resolve(&name, true) {
+                Ok(l) => renamed = Some(l),
+                Err(e) => {
+                    println!(" => Cannot rename to {name:?} ({e}), rejecting");
+                    let resp = TeleportInitAck::new(TeleportStatus::Rejected);
+                    return send_ack(resp, &mut stream, &enc);
+                }
+            },
+            Decision::Reject => {
+                println!(" => Rejected: {shown}");
+                let resp = TeleportInitAck::new(TeleportStatus::Rejected);
+                return send_ack(resp, &mut stream, &enc);
+            }
+        }
+    }
+
+    // Resolve the path within the root, creating any missing directories
+    let resolved = match renamed {
+        Some(l) => Ok(l),
+        None => sandbox.resolve(&filename, generation.is_none()),
+    };
+    let mut loc = match resolved {
+        Ok(l) => l,
+        Err(TeleportError::Io(_)) if generation.is_some() => {
+            println!(" => No backups of: {filename}");
+            let resp = TeleportInitAck::new(TeleportStatus::NoBackup);
+            return send_ack(resp, &mut stream, &enc);
+        }
+        Err(TeleportError::Io(e)) => {
+            println!("Error: unable to create directories for: {filename} ({e})");
+            let resp = TeleportInitAck::new(TeleportStatus::NoPermission);
+            return send_ack(resp, &mut stream, &enc);
+        }
+        Err(e) => {
+            println!(" => Refusing path from {ip}: {filename:?} ({e})");
+            let resp = TeleportInitAck::new(TeleportStatus::BadFileName);
+            return send_ack(resp, &mut stream, &enc);
+        }
+    };
+
+    // Symlinks may have changed since the path was checked
+    if let Some(rule) = rule {
+        let status = check_location(rule, ip.ip(), &loc.real, action, target.as_deref(), sandbox);
+        if status != TeleportStatus::Proceed {
+            return send_ack(TeleportInitAck::new(status), &mut stream, &enc);
+        }
+    }
+
+    // Directories, links and restores carry no file data
+    if action != TeleportAction::Init as u8 {
+        let status = match (generation, target) {
+            (Some(g), _) => restore(&loc, g, features, &server.retention),
+            (None, Some(t)) => {
+                make_link(action, &loc, String::from_utf8(t)?, &header, sandbox, &opt)
+            }
+            (None, None) => make_dir(&loc, &header),
+        };
+        let proceed = status == TeleportStatus::Proceed;
+        let mut resp = TeleportInitAck::new(status);
+        if proceed {
+            if generation.is_none() {
+                println!(
+                    " => Created: {} (from: {} v{})",
+                    loc.display(),
+                    ip,
+                    &header.version
+                );
+            }
+            TeleportFeatures::NewFile.add(&mut resp.features)?;
+            if header.share.is_some() {
+                TeleportFeatures::Share.add(&mut resp.features)?;
+            }
+            if TeleportFeatures::Path.check_u32(features) {
+                TeleportFeatures::Path.add(&mut resp.features)?;
+                resp.path = Some(loc.display().into_bytes());
+            }
+            if header.revision.is_some() {
+                TeleportFeatures::Revision.add(&mut resp.features)?;
+                resp.revision = header.negotiate();
+            }
+        }
+        return send_ack(resp, &mut stream, &enc);
     }
 
     if TeleportFeatures::Rename.check_u32(features) {
         let mut num = 1;
-        let mut dest = filename.clone();
-        while Path::new(&dest).exists() {
-            dest = filename.clone() + "." + &num.to_string();
+        let base = loc.name.clone();
+        while loc.dir.exists(&loc.name) {
+            let mut name = base.clone();
+            name.push(format!(".{num}"));
+            loc.set_name(name);
             num += 1;
         }
-        filename = dest;
     }
+    let filename = loc.display();
 
     // Test if overwrite is false and file exists
-    if !TeleportFeatures::Overwrite.check_u32(features) && Path::new(&filename).exists() {
+    if !TeleportFeatures::Overwrite.check_u32(features) && loc.dir.exists(&loc.name) {
         println!(" => Refusing to overwrite file: {}", &filename);
         let resp = TeleportInitAck::new(TeleportStatus::NoOverwrite);
         return send_ack(resp, &mut stream, &enc);
     }
 
-    // Create recursive dirs
-    let path = match Path::new(&filename).parent() {
-        Some(p) => p,
-        None => {
+    // Check that the file and any backup fit, keeping the reserve free
+    let mut needed = header.filesize.saturating_add(opt.reserve);
+    if TeleportFeatures::Backup.check_u32(features) {
+        needed = needed.saturating_add(loc.dir.metadata(&loc.name).map_or(0, |m| m.len()));
+    }
+    let free = match &server.quarantine {
+        Some(q) => q.free_space(),
+        None => loc.dir.free_space(),
+    };
+    if let Ok(free) = free {
+        if free < needed {
             println!(
-                "Error: unable to parse the path and filename: {}",
-                &filename
+                " => Not enough space for {}: {} bytes needed, {} available",
+                &filename, needed, free
             );
-            let resp = TeleportInitAck::new(TeleportStatus::BadFileName);
+            let resp = TeleportInitAck::new(TeleportStatus::NoSpace);
+            return send_ack(resp, &mut stream, &enc);
+        }
+    }
+
+    // Count the file against the quota of the client
+    let charge = match server.quota.charge(ip.ip(), header.filesize) {
+        Some(c) => c,
+        None => {
+            let client = utils::canonical_ip(ip.ip());
+            println!(" => Quota exceeded for {}: {}", client, &filename);
+            let resp = TeleportInitAck::new(TeleportStatus::QuotaExceeded);
             return send_ack(resp, &mut stream, &enc);
         }
     };
 
-    if fs::create_dir_all(path).is_err() {
-        println!("Error: unable to create directories: {}", &path.display());
-        let resp = TeleportInitAck::new(TeleportStatus::NoPermission);
-        return send_ack(resp, &mut stream, &enc);
+    // Open the existing file, if any
+    let orig = match loc.dir.open_file(&loc.name, libc::O_RDONLY, 0) {
+        Ok(f) if f.metadata()?.is_dir() => {
+            println!("Error: unable to replace directory: {}", &filename);
+            let resp = TeleportInitAck::new(TeleportStatus::NoPermission);
+            return send_ack(resp, &mut stream, &enc);
+        }
+        Ok(f) => Some(f),
+        Err(_) => None,
     };
 
-    // Open file for writing
-    let mut file = match OpenOptions::new().read(true).write(true).open(&filename) {
-        Ok(f) => {
-            if TeleportFeatures::Backup.check_u32(features) {
-                let dest = filename.clone() + ".bak";
-                fs::copy(&filename, &dest)?;
-            }
-            f
+    // Quarantined files are held under a new id until they are approved
+    let staged = match &server.quarantine {
+        Some(q) => {
+            let id = inbox::Entry::new_id(q);
+            Some(Location {
+                dir: q.try_clone()?,
+                name: id.clone().into(),
+                real: id.clone().into(),
+                path: id.into(),
+            })
+        }
+        None => None,
+    };
+
+    // Receive into a temporary file that replaces the file once complete
+    let mut tmp = match AtomicFile::create(staged.as_ref().unwrap_or(&loc)) {
+        Ok(t) => t,
+        Err(_) => {
+            println!("Error: unable to create file: {}", &filename);
+            let resp = TeleportInitAck::new(TeleportStatus::NoPermission);
+            return send_ack(resp, &mut stream, &enc);
         }
-        Err(_) => match File::create(&filename) {
-            Ok(f) => f,
-            Err(_) => {
-                println!("Error: unable to create file: {}", &filename);
-                let resp = TeleportInitAck::new(TeleportStatus::NoPermission);
-                return send_ack(resp, &mut stream, &enc);
-            }
-        },
     };
-    let meta = file.metadata()?;
-    let mut perms = meta.permissions();
-    perms.set_mode(header.chmod);
-    if fs::set_permissions(&filename, perms).is_err() {
+    if tmp
+        .file
+        .set_permissions(fs::Permissions::from_mode(header.chmod))
+        .is_err()
+    {
         println!("Could not set file permissions");
         let resp = TeleportInitAck::new(TeleportStatus::NoPermission);
         return send_ack(resp, &mut stream, &enc);
     };
 
+    // Renaming over the file would drop its owner and attributes, so start
+    // from those. Metadata sent by the client is applied on top once the
+    // data is complete.
+    if let (Some(orig), None) = (&orig, &staged) {
+        metadata::inherit(&tmp.file, orig, &server.trust, &filename);
+    }
+
     // Send ready for data ACK
     let mut resp = TeleportInitAck::new(TeleportStatus::Proceed);
     TeleportFeatures::NewFile.add(&mut resp.features)?;
+    if header.metadata.is_some() {
+        TeleportFeatures::Metadata.add(&mut resp.features)?;
+    }
+    if TeleportFeatures::Sparse.check_u32(features) {
+        TeleportFeatures::Sparse.add(&mut resp.features)?;
+    }
+    if header.share.is_some() {
+        TeleportFeatures::Share.add(&mut resp.features)?;
+    }
+    if TeleportFeatures::Path.check_u32(features) {
+        TeleportFeatures::Path.add(&mut resp.features)?;
+        resp.path = Some(filename.clone().into_bytes());
+    }
+    if header.revision.is_some() {
+        TeleportFeatures::Revision.add(&mut resp.features)?;
+        resp.revision = header.negotiate();
+    }
 
     // Add file to list
-    let mut recv_data = recv_list.lock().expect("Fatal error locking recv_list");
-    recv_data.push(filename.clone());
-    print_list(&recv_data);
-    drop(recv_data);
+    let _receiving = Receiving::add(recv_list, &filename);
 
     // If overwrite and file exists, build TeleportDelta
-    file.set_len(header.filesize)?;
-    if meta.len() > 0 {
+    if let Some(orig) = orig.filter(|f| f.metadata().map_or(false, |m| m.len() > 0)) {
         TeleportFeatures::Overwrite.add(&mut resp.features)?;
         if TeleportFeatures::Delta.check_u32(features) {
             TeleportFeatures::Delta.add(&mut resp.features)?;
-            resp.delta = match TeleportDelta::delta_hash(&file) {
-                Ok(d) => Some(d),
-                _ => None,
-            };
+            resp.delta = TeleportDelta::delta_hash(&orig).ok();
+
+            // Only changed chunks will be sent, start from the original data
+            tmp.clone_from(&orig)?;
         }
     }
+    tmp.file.set_len(header.filesize)?;
 
     match send_ack(resp, &mut stream, &enc) {
         Ok(_) => (),
@@ -260,17 +1421,33 @@ fn handle_connection(
                 "Connection closed (reason: {:?}). Aborted {} transfer.",
                 e, &filename
             );
-            rm_filename_from_list(&filename, recv_list);
             return Ok(());
         }
     }
 
     // Receive file data
+    let file = &mut tmp.file;
     let mut received: u64 = 0;
+    let mut complete = false;
+    let mut abort: Option<TeleportAbort> = None;
     loop {
+        // Let the client know when the transfer is cancelled on shutdown
+        if server.streams.cancelled() {
+            println!(" => Cancelled {} transfer.", &filename);
+            abort = Some(TeleportAbort::new(
+                TeleportAbortCode::Cancelled,
+                "The server is shutting down",
+            ));
+            break;
+        }
+
         // Read from network connection
         let packet = match utils::recv_packet(&mut stream, &enc) {
             Ok(s) => s,
+            Err(e) if e.is_abort() => {
+                println!(" => {}. Aborted {} transfer.", e, &filename);
+                break;
+            }
             Err(e) => {
                 println!(
                     "Connection closed (reason: {:?}). Aborted {} transfer.",
@@ -279,6 +1456,31 @@ fn handle_connection(
                 break;
             }
         };
+
+        // Keep holes sparse, deallocating any existing data
+        if packet.action == TeleportAction::Hole as u8 {
+            let mut hole = TeleportHole::new();
+            hole.deserialize(&packet.data)?;
+            let end = match hole.offset.checked_add(hole.length) {
+                Some(end) if end <= header.filesize => end,
+                _ => {
+                    println!("Error: Received hole beyond filesize!");
+                    abort = Some(TeleportAbort::new(
+                        TeleportAbortCode::Protocol,
+                        "Received hole beyond filesize",
+                    ));
+                    break;
+                }
+            };
+            if let Err(e) = sparse::punch_hole(file, hole.offset, hole.length) {
+                println!("Error writing to file: {}: {}", &filename, e);
+                abort = Some(TeleportAbort::from_error(&e));
+                break;
+            }
+            received = end;
+            continue;
+        }
+
         let mut chunk = TeleportData::new();
         chunk.deserialize(&packet.data)?;
 
@@ -286,30 +1488,40 @@ fn handle_connection(
             if received == header.filesize
                 || (header.filesize == chunk.offset && chunk.data_len == 0)
             {
-                let duration = start_time.elapsed();
-                let speed =
-                    (header.filesize as f64 * 8.0) / duration.as_secs() as f64 / 1024.0 / 1024.0;
-                println!(
-                    " => Received file: {} (from: {} v{}) ({:.2?} @ {:.3} Mbps)",
-                    &filename, ip, &header.version, duration, speed
-                );
+                complete = true;
             } else {
                 println!(" => Error receiving: {}", &filename);
+                abort = Some(TeleportAbort::new(
+                    TeleportAbortCode::Protocol,
+                    "Received incomplete file data",
+                ));
             }
             break;
         }
 
-        // Seek to offset
-        file.seek(SeekFrom::Start(chunk.offset))?;
-
-        // Write received data to file
-        let wrote = file.write(&chunk.data)?;
+        // Seek to offset and write received data to file
+        let wrote = match file
+            .seek(SeekFrom::Start(chunk.offset))
+            .and_then(|_| file.write(&chunk.data))
+        {
+            Ok(w) => w,
+            Err(e) => {
+                let e = TeleportError::from(e);
+                println!("Error writing to file: {}: {}", &filename, e);
+                abort = Some(TeleportAbort::from_error(&e));
+                break;
+            }
+        };
 
         if chunk.data_len as usize != wrote {
             println!(
                 "Error writing to file: {} (read: {}, wrote: {}). Out of space?",
                 &filename, chunk.data_len, wrote
             );
+            abort = Some(TeleportAbort::new(
+                TeleportAbortCode::NoSpace,
+                "Short write to the file",
+            ));
             break;
         }
 
@@ -321,11 +1533,269 @@ fn handle_connection(
                 "Error: Received {} greater than filesize!",
                 received - header.filesize
             );
+            abort = Some(TeleportAbort::new(
+                TeleportAbortCode::Protocol,
+                "Received data beyond filesize",
+            ));
             break;
         }
     }
 
-    rm_filename_from_list(&filename, recv_list);
+    // Clients that predate Abort messages would take it for file data
+    if let Some(abort) = abort.take() {
+        if header.negotiate().map_or(false, |r| r >= ABORT_REVISION) {
+            utils::send_abort(&mut stream, &enc, abort);
+        }
+    }
+
+    let mut saved = false;
+    if complete {
+        // Apply any preserved metadata now that the data is final
+        if let Some(meta) = &header.metadata {
+            metadata::apply(&tmp.file, meta, header.chmod, &filename);
+        }
+
+        // Keep a handle on the data for the post-receive hook
+        let received = tmp.file.try_clone();
+
+        // Describe quarantined files, so that they can be approved later
+        let described = staged
+            .as_ref()
+            .map(|s| -> Result<inbox::Entry, TeleportError> {
+                Ok(inbox::Entry {
+                    id: s.display(),
+                    peer: peer.clone(),
+                    time: utils::now(),
+                    size: header.filesize,
+                    hash: utils::hash_file(&tmp.file)?,
+                    share: share.clone(),
+                    root: fs::canonicalize(sandbox.root())?,
+                    filename: filename.clone(),
+                    overwrite: TeleportFeatures::Overwrite.check_u32(features),
+                    backup: match TeleportFeatures::Backup.check_u32(features) {
+                        true => Some(server.retention),
+                        false => None,
+                    },
+                    dangerous: opt.allow_dangerous_filepath,
+                })
+            })
+            .transpose();
+
+        let persisted = described.and_then(|entry| {
+            // Keep the previous version as a backup. Quarantined files are
+            // backed up when they are approved.
+            if TeleportFeatures::Backup.check_u32(features) && entry.is_none() {
+                backup::create(&loc, &server.retention)?;
+            }
+
+            // Replace the destination with the completed file
+            tmp.persist()?;
+            if let (Some(q), Some(e)) = (&server.quarantine, &entry) {
+                e.save(q)?;
+            }
+            Ok(entry)
+        });
+        match persisted {
+            Ok(entry) => {
+                charge.keep();
+                saved = true;
+                server.stats.files.fetch_add(1, Ordering::SeqCst);
+                server
+                    .stats
+                    .bytes
+                    .fetch_add(header.filesize, Ordering::SeqCst);
+                let duration = start_time.elapsed();
+                let speed =
+                    (header.filesize as f64 * 8.0) / duration.as_secs() as f64 / 1024.0 / 1024.0;
+                println!(
+                    " => Received file: {} (from: {} v{}) ({:.2?} @ {:.3} Mbps)",
+                    &filename, ip, &header.version, duration, speed
+                );
+
+                let path = match (&opt.quarantine, &entry) {
+                    (Some(q), Some(e)) => {
+                        println!(" => Holding {} for approval as: {}", &filename, e.id);
+                        q.join(&e.id)
+                    }
+                    _ => sandbox.path(&loc),
+                };
+                if let Ok(file) = received {
+                    let mut env: hooks::Env = vec![
+                        ("TELEPORTER_PEER", peer),
+                        ("TELEPORTER_SHARE", share),
+                        ("TELEPORTER_FILENAME", filename.clone()),
+                        ("TELEPORTER_PATH", path.display().to_string()),
+                        ("TELEPORTER_SIZE", header.filesize.to_string()),
+                        (
+                            "TELEPORTER_DURATION",
+                            format!("{:.3}", duration.as_secs_f64()),
+                        ),
+                    ];
+                    if let Some(e) = entry {
+                        env.push(("TELEPORTER_INBOX_ID", e.id));
+                    }
+                    server.hooks.post_receive(file, env);
+                }
+            }
+            Err(e) => {
+                println!(" => Error saving {}: {}", &filename, e);
+                abort = Some(TeleportAbort::from_error(&e));
+            }
+        }
+
+        // Tell the client whether the file was stored, as failures after the
+        // last data packet would otherwise go unnoticed
+        if header.negotiate().map_or(false, |r| r >= CONFIRM_REVISION) {
+            match abort {
+                Some(abort) => utils::send_abort(&mut stream, &enc, abort),
+                None => {
+                    let _ = utils::send_data_complete(&mut stream, &enc, header.filesize);
+                }
+            }
+        }
+    }
+    if !saved {
+        server.stats.failed.fetch_add(1, Ordering::SeqCst);
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::TestDir;
+    use clap::Parser;
+    use std::os::unix::fs::symlink;
+    use std::path::PathBuf;
+
+    fn test_root() -> TestDir {
+        let root = TestDir::create();
+        fs::create_dir_all(root.join("uploads")).expect("Test should never fail");
+        fs::create_dir_all(root.join("builds")).expect("Test should never fail");
+        root
+    }
+
+    #[test]
+    fn test_check_location_symlinks() {
+        let root = test_root();
+        let sandbox = Sandbox::new(&root, false).expect("Test should never fail");
+        let ip: IpAddr = "192.168.1.20".parse().expect("Test should never fail");
+        let rule = Rule {
+            name: "lan".to_string(),
+            networks: vec!["192.168.1.0/24".parse().expect("Test should never fail")],
+            paths: vec![PathBuf::from("uploads")],
+            overwrite: false,
+            backup: false,
+            rename: false,
+            max_size: None,
+        };
+        let check = |path: &str, action: TeleportAction, target: Option<&str>| {
+            let real = sandbox.locate(path).expect("Test should never fail");
+            let target = target.map(str::as_bytes);
+            check_location(&rule, ip, &real, action as u8, target, &sandbox)
+        };
+
+        assert_eq!(
+            check("uploads/x", TeleportAction::Init, None),
+            TeleportStatus::Proceed
+        );
+
+        // A symlink inside of an allowed path must not lead out of it
+        symlink("../builds", root.join("uploads/l")).expect("Test should never fail");
+        assert!(rule.allows_path("uploads/l/x"));
+        assert_eq!(
+            check("uploads/l/x", TeleportAction::Init, None),
+            TeleportStatus::PathNotAllowed
+        );
+
+        // Nor may links be created to files outside of the allowed paths
+        assert_eq!(
+            check("uploads/s", TeleportAction::Symlink, Some("../builds/x")),
+            TeleportStatus::PathNotAllowed
+        );
+        assert_eq!(
+            check("uploads/s", TeleportAction::Symlink, Some("l/x")),
+            TeleportStatus::PathNotAllowed
+        );
+        assert_eq!(
+            check("uploads/s", TeleportAction::Symlink, Some("x")),
+            TeleportStatus::Proceed
+        );
+        fs::write(root.join("builds/x"), "x").expect("Test should never fail");
+        assert_eq!(
+            check("uploads/h", TeleportAction::Hardlink, Some("builds/x")),
+            TeleportStatus::PathNotAllowed
+        );
+        assert_eq!(
+            check("uploads/h", TeleportAction::Hardlink, Some("uploads/l/x")),
+            TeleportStatus::PathNotAllowed
+        );
+        fs::write(root.join("uploads/x"), "x").expect("Test should never fail");
+        assert_eq!(
+            check("uploads/h", TeleportAction::Hardlink, Some("uploads/x")),
+            TeleportStatus::Proceed
+        );
+    }
+
+    #[test]
+    fn test_clients_limit() {
+        let clients = Arc::new(Clients::default());
+        let ip: IpAddr = "192.168.1.20".parse().expect("Test should never fail");
+        let mapped: IpAddr = "::ffff:192.168.1.20"
+            .parse()
+            .expect("Test should never fail");
+        let other: IpAddr = "192.168.1.21".parse().expect("Test should never fail");
+
+        let first = clients.enter(ip, 2).expect("Test should never fail");
+        // IPv4 clients of IPv6 sockets count as the same client
+        let second = clients.enter(mapped, 2).expect("Test should never fail");
+        assert!(clients.enter(ip, 2).is_none());
+        assert!(clients.enter(other, 2).is_some());
+
+        // Slots are released when dropped
+        drop(first);
+        let third = clients.enter(ip, 2).expect("Test should never fail");
+        drop(second);
+        drop(third);
+        assert!(clients
+            .active
+            .lock()
+            .expect("Test should never fail")
+            .is_empty());
+
+        // A limit of 0 is unlimited
+        let slots: Vec<_> = (0..100).filter_map(|_| clients.enter(ip, 0)).collect();
+        assert_eq!(slots.len(), 100);
+    }
+
+    #[test]
+    fn test_make_link_escape() {
+        let root = test_root();
+        let sandbox = Sandbox::new(&root, false).expect("Test should never fail");
+        let opt = ListenOpt::parse_from(["listen"]);
+        let header = TeleportInit::new(TeleportFeatures::NewFile);
+        let link = |path: &str, target: &str| {
+            let loc = sandbox
+                .resolve(path, false)
+                .expect("Test should never fail");
+            let action = TeleportAction::Symlink as u8;
+            make_link(action, &loc, target.to_string(), &header, &sandbox, &opt)
+        };
+
+        // The link really is in the root, however deep its path looks
+        symlink(".", root.join("deep")).expect("Test should never fail");
+        assert_eq!(
+            link("deep/deep/deep/l", "../../../etc"),
+            TeleportStatus::BadFileName
+        );
+        assert!(fs::symlink_metadata(root.join("l")).is_err());
+
+        assert_eq!(link("uploads/l", "../builds"), TeleportStatus::Proceed);
+        assert_eq!(link("deep/deep/l", "uploads"), TeleportStatus::Proceed);
+        assert_eq!(
+            fs::read_link(root.join("l")).expect("Test should never fail"),
+            PathBuf::from("uploads")
+        );
+    }
+}
@@ -1,7 +1,7 @@
 use clap::Parser;
 
-use teleporter::{listen, scan, send};
-use teleporter::{ListenOpt, ScanOpt, SendOpt};
+use teleporter::{inbox, listen, remote, scan, send};
+use teleporter::{InboxOpt, ListenOpt, RemoteOpt, ScanOpt, SendOpt};
 
 /// Teleporter is a simple application for sending files from Point A to Point B
 #[derive(Clone, Debug, Parser, PartialEq, Eq)]
@@ -20,6 +20,10 @@ pub enum Cmd {
     Send(SendOpt),
     /// Scan all network devices for any reachable Teleport listeners
     Scan(ScanOpt),
+    /// Manage files on a teleporter in server mode
+    Remote(RemoteOpt),
+    /// Approve or reject files held in the quarantine directory of a listener
+    Inbox(InboxOpt),
 }
 
 fn main() {
@@ -31,6 +35,8 @@ fn main() {
         Cmd::Listen(l) => listen::run(l),
         Cmd::Send(s) => send::run(s),
         Cmd::Scan(s) => scan::run(s),
+        Cmd::Remote(r) => remote::run(r),
+        Cmd::Inbox(i) => inbox::run(i),
     };
 
     // Display any errors
@@ -0,0 +1,533 @@
+use crate::errors::TeleportError;
+use crate::sandbox::Dir;
+use crate::teleport::{TeleportMetadata, TeleportOwner, TeleportTimes, TeleportXattr};
+use crate::utils;
+use crate::Preserve;
+use std::ffi::{CStr, CString, OsStr};
+use std::fs::{File, Metadata, Permissions};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+/// Extended attributes that hold POSIX ACLs on Linux
+const ACL_XATTRS: [&[u8]; 2] = [b"system.posix_acl_access", b"system.posix_acl_default"];
+
+/// Extended attribute namespace any user may set on their own files
+const USER_XATTR_PREFIX: &[u8] = b"user.";
+
+/// Which received metadata the listener applies. Ownership and extended
+/// attributes outside of the `user.` namespace (ACLs, capabilities, security
+/// labels) are only applied when the listener opted in to them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Trust {
+    pub owner: bool,
+    pub xattrs: bool,
+}
+
+impl Trust {
+    /// Drop the items of received metadata that are not trusted
+    pub fn filter(&self, meta: &mut TeleportMetadata) {
+        if !self.owner {
+            meta.owner = None;
+        }
+        if !self.xattrs {
+            meta.xattrs
+                .retain(|x| x.name.starts_with(USER_XATTR_PREFIX));
+        }
+    }
+
+    /// The mode received files are given. Setuid and setgid bits are only
+    /// kept when the owner is preserved too, as they would otherwise grant
+    /// the rights of the listener.
+    pub fn mode(&self, chmod: u32) -> u32 {
+        if self.owner {
+            chmod
+        } else {
+            chmod & !(libc::S_ISUID | libc::S_ISGID)
+        }
+    }
+}
+
+/// Collect the metadata items requested in `preserve` from an open file
+pub fn gather(file: &File, preserve: &[Preserve]) -> Result<TeleportMetadata, TeleportError> {
+    let meta = file.metadata()?;
+    let mut out = gather_stat(&meta, preserve);
+
+    let xattrs = preserve.contains(&Preserve::Xattrs);
+    let acls = preserve.contains(&Preserve::Acls);
+    if xattrs || acls {
+        for name in list_xattrs(file)? {
+            let is_acl = ACL_XATTRS.contains(&name.as_slice());
+            if (is_acl && !acls) || (!is_acl && !xattrs) {
+                continue;
+            }
+            if let Ok(value) = get_xattr(file, &name) {
+                out.xattrs.push(TeleportXattr { name, value });
+            }
+        }
+    }
+
+    Ok(out)
+}
+
+/// Collect the times and ownership of a symlink itself
+pub fn gather_link(path: &Path, preserve: &[Preserve]) -> Result<TeleportMetadata, TeleportError> {
+    let meta = path.symlink_metadata()?;
+    Ok(gather_stat(&meta, preserve))
+}
+
+fn gather_stat(meta: &Metadata, preserve: &[Preserve]) -> TeleportMetadata {
+    let mut out = TeleportMetadata::default();
+
+    if preserve.contains(&Preserve::Times) {
+        out.times = Some(TeleportTimes {
+            atime: meta.atime(),
+            atime_nsec: meta.atime_nsec() as u32,
+            mtime: meta.mtime(),
+            mtime_nsec: meta.mtime_nsec() as u32,
+        });
+    }
+
+    if preserve.contains(&Preserve::Owner) {
+        out.owner = Some(TeleportOwner {
+            uid: meta.uid(),
+            gid: meta.gid(),
+            user: user_name(meta.uid()).unwrap_or_default(),
+            group: group_name(meta.gid()).unwrap_or_default(),
+        });
+    }
+
+    out
+}
+
+/// Apply received metadata to an open file. Items that cannot be applied
+/// (e.g. ownership without privileges) are reported and skipped.
+pub fn apply(file: &File, meta: &TeleportMetadata, chmod: u32, filename: &str) {
+    if let Some(o) = &meta.owner {
+        // Prefer names so that differing uid/gid numbering maps correctly
+        let uid = user_id(&o.user).unwrap_or(o.uid);
+        let gid = group_id(&o.group).unwrap_or(o.gid);
+        match fchown(file, uid, gid) {
+            // chown clears setuid/setgid bits, so restore the mode
+            Ok(_) => {
+                let _ = file.set_permissions(Permissions::from_mode(chmod));
+            }
+            Err(e) => println!(" => Warning: unable to set owner of {filename}: {e}"),
+        }
+    }
+
+    for x in &meta.xattrs {
+        if let Err(e) = set_xattr(file, &x.name, &x.value) {
+            println!(
+                " => Warning: unable to set attribute {} on {filename}: {e}",
+                String::from_utf8_lossy(&x.name)
+            );
+        }
+    }
+
+    // Times are applied last, as the other operations may update them
+    if let Some(t) = &meta.times {
+        if let Err(e) = set_times(file, t) {
+            println!(" => Warning: unable to set times of {filename}: {e}");
+        }
+    }
+}
+
+/// Give a file that replaces `orig` the owner, mode and extended attributes
+/// (including ACLs) of `orig`, before any received metadata is applied. As the
+/// data comes from the client, setuid/setgid bits and attributes outside of
+/// the `user.` namespace other than ACLs are only kept as `trust` allows.
+pub fn inherit(file: &File, orig: &File, trust: &Trust, filename: &str) {
+    let (meta, current) = match (orig.metadata(), file.metadata()) {
+        (Ok(m), Ok(c)) => (m, c),
+        _ => return,
+    };
+
+    if (meta.uid(), meta.gid()) != (current.uid(), current.gid()) {
+        if let Err(e) = fchown(file, meta.uid(), meta.gid()) {
+            println!(" => Warning: unable to keep the owner of {filename}: {e}");
+        }
+    }
+
+    // chown clears setuid/setgid bits, so the mode is set afterwards
+    let mode = trust.mode(meta.mode() & 0o7777);
+    if let Err(e) = file.set_permissions(Permissions::from_mode(mode)) {
+        println!(" => Warning: unable to keep the mode of {filename}: {e}");
+    }
+
+    for name in list_xattrs(orig).unwrap_or_default() {
+        let allowed = trust.xattrs
+            || name.starts_with(USER_XATTR_PREFIX)
+            || ACL_XATTRS.contains(&name.as_slice());
+        if !allowed {
+            continue;
+        }
+        if let Err(e) = get_xattr(orig, &name).and_then(|v| set_xattr(file, &name, &v)) {
+            println!(
+                " => Warning: unable to keep attribute {} on {filename}: {e}",
+                String::from_utf8_lossy(&name)
+            );
+        }
+    }
+}
+
+/// Apply received times and ownership to a symlink in `dir` without
+/// following it
+pub fn apply_link(dir: &Dir, name: &OsStr, meta: &TeleportMetadata, filename: &str) {
+    let cpath = match CString::new(name.as_bytes()) {
+        Ok(p) => p,
+        Err(_) => return,
+    };
+
+    if let Some(o) = &meta.owner {
+        let uid = user_id(&o.user).unwrap_or(o.uid);
+        let gid = group_id(&o.group).unwrap_or(o.gid);
+        if let Err(e) = utils::cvt(unsafe {
+            libc::fchownat(
+                dir.as_raw_fd(),
+                cpath.as_ptr(),
+                uid,
+                gid,
+                libc::AT_SYMLINK_NOFOLLOW,
+            )
+        }) {
+            println!(" => Warning: unable to set owner of {filename}: {e}");
+        }
+    }
+
+    if let Some(t) = &meta.times {
+        let times = timespecs(t);
+        if let Err(e) = utils::cvt(unsafe {
+            libc::utimensat(
+                dir.as_raw_fd(),
+                cpath.as_ptr(),
+                times.as_ptr(),
+                libc::AT_SYMLINK_NOFOLLOW,
+            )
+        }) {
+            println!(" => Warning: unable to set times of {filename}: {e}");
+        }
+    }
+}
+
+fn fchown(file: &File, uid: u32, gid: u32) -> io::Result<()> {
+    utils::cvt(unsafe { libc::fchown(file.as_raw_fd(), uid, gid) }).map(|_| ())
+}
+
+fn set_times(file: &File, t: &TeleportTimes) -> io::Result<()> {
+    let times = timespecs(t);
+    utils::cvt(unsafe { libc::futimens(file.as_raw_fd(), times.as_ptr()) }).map(|_| ())
+}
+
+fn timespecs(t: &TeleportTimes) -> [libc::timespec; 2] {
+    [
+        libc::timespec {
+            tv_sec: t.atime as libc::time_t,
+            tv_nsec: t.atime_nsec as libc::c_long,
+        },
+        libc::timespec {
+            tv_sec: t.mtime as libc::time_t,
+            tv_nsec: t.mtime_nsec as libc::c_long,
+        },
+    ]
+}
+
+fn list_xattrs(file: &File) -> Result<Vec<Vec<u8>>, TeleportError> {
+    let fd = file.as_raw_fd();
+    let mut buf = Vec::<u8>::new();
+    loop {
+        let len = unsafe { libc::flistxattr(fd, std::ptr::null_mut(), 0) };
+        if len < 0 {
+            let err = io::Error::last_os_error();
+            // Filesystems without xattr support simply have none
+            if err.raw_os_error() == Some(libc::ENOTSUP) {
+                return Ok(Vec::new());
+            }
+            return Err(TeleportError::Io(err));
+        }
+        buf.resize(len as usize, 0);
+        let len = unsafe { libc::flistxattr(fd, buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+        if len >= 0 {
+            buf.truncate(len as usize);
+            break;
+        }
+        // The list grew between calls, try again
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::ERANGE) {
+            return Err(TeleportError::Io(err));
+        }
+    }
+
+    Ok(buf
+        .split(|c| *c == 0)
+        .filter(|n| !n.is_empty())
+        .map(|n| n.to_vec())
+        .collect())
+}
+
+fn get_xattr(file: &File, name: &[u8]) -> io::Result<Vec<u8>> {
+    let fd = file.as_raw_fd();
+    let name = CString::new(name)?;
+    let mut buf = Vec::<u8>::new();
+    loop {
+        let len = unsafe { libc::fgetxattr(fd, name.as_ptr(), std::ptr::null_mut(), 0) };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        buf.resize(len as usize, 0);
+        let len = unsafe {
+            libc::fgetxattr(
+                fd,
+                name.as_ptr(),
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+            )
+        };
+        if len >= 0 {
+            buf.truncate(len as usize);
+            return Ok(buf);
+        }
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::ERANGE) {
+            return Err(err);
+        }
+    }
+}
+
+fn set_xattr(file: &File, name: &[u8], value: &[u8]) -> io::Result<()> {
+    let name = CString::new(name)?;
+    utils::cvt(unsafe {
+        libc::fsetxattr(
+            file.as_raw_fd(),
+            name.as_ptr(),
+            value.as_ptr() as *const libc::c_void,
+            value.len(),
+            0,
+        )
+    })
+    .map(|_| ())
+}
+
+/// Call a getpw*_r/getgr*_r style function, growing the buffer as required
+fn lookup<T, F>(mut f: F) -> Option<T>
+where
+    F: FnMut(&mut Vec<libc::c_char>) -> Result<Option<T>, libc::c_int>,
+{
+    let mut buf = vec![0 as libc::c_char; 1024];
+    loop {
+        match f(&mut buf) {
+            Ok(out) => return out,
+            Err(libc::ERANGE) if buf.len() < 1 << 20 => {
+                let len = buf.len() * 2;
+                buf.resize(len, 0);
+            }
+            Err(_) => return None,
+        }
+    }
+}
+
+fn user_name(uid: u32) -> Option<Vec<u8>> {
+    lookup(|buf| {
+        let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+        let mut result = std::ptr::null_mut();
+        let ret =
+            unsafe { libc::getpwuid_r(uid, &mut pwd, buf.as_mut_ptr(), buf.len(), &mut result) };
+        if ret != 0 {
+            return Err(ret);
+        }
+        if result.is_null() {
+            return Ok(None);
+        }
+        Ok(Some(
+            unsafe { CStr::from_ptr(pwd.pw_name) }.to_bytes().to_vec(),
+        ))
+    })
+}
+
+fn group_name(gid: u32) -> Option<Vec<u8>> {
+    lookup(|buf| {
+        let mut grp: libc::group = unsafe { std::mem::zeroed() };
+        let mut result = std::ptr::null_mut();
+        let ret =
+            unsafe { libc::getgrgid_r(gid, &mut grp, buf.as_mut_ptr(), buf.len(), &mut result) };
+        if ret != 0 {
+            return Err(ret);
+        }
+        if result.is_null() {
+            return Ok(None);
+        }
+        Ok(Some(
+            unsafe { CStr::from_ptr(grp.gr_name) }.to_bytes().to_vec(),
+        ))
+    })
+}
+
+fn user_id(name: &[u8]) -> Option<u32> {
+    let name = CString::new(name)
+        .ok()
+        .filter(|n| !n.as_bytes().is_empty())?;
+    lookup(|buf| {
+        let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+        let mut result = std::ptr::null_mut();
+        let ret = unsafe {
+            libc::getpwnam_r(
+                name.as_ptr(),
+                &mut pwd,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut result,
+            )
+        };
+        if ret != 0 {
+            return Err(ret);
+        }
+        if result.is_null() {
+            return Ok(None);
+        }
+        Ok(Some(pwd.pw_uid))
+    })
+}
+
+fn group_id(name: &[u8]) -> Option<u32> {
+    let name = CString::new(name)
+        .ok()
+        .filter(|n| !n.as_bytes().is_empty())?;
+    lookup(|buf| {
+        let mut grp: libc::group = unsafe { std::mem::zeroed() };
+        let mut result = std::ptr::null_mut();
+        let ret = unsafe {
+            libc::getgrnam_r(
+                name.as_ptr(),
+                &mut grp,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut result,
+            )
+        };
+        if ret != 0 {
+            return Err(ret);
+        }
+        if result.is_null() {
+            return Ok(None);
+        }
+        Ok(Some(grp.gr_gid))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::TestDir;
+
+    fn xattr(name: &str) -> TeleportXattr {
+        TeleportXattr {
+            name: name.as_bytes().to_vec(),
+            value: vec![1, 2, 3],
+        }
+    }
+
+    #[test]
+    fn test_trust_filter() {
+        let meta = TeleportMetadata {
+            owner: Some(TeleportOwner::default()),
+            xattrs: vec![
+                xattr("user.comment"),
+                xattr("security.capability"),
+                xattr("trusted.overlay"),
+                xattr("system.posix_acl_access"),
+            ],
+            ..Default::default()
+        };
+
+        let mut filtered = meta.clone();
+        Trust::default().filter(&mut filtered);
+        assert!(filtered.owner.is_none());
+        assert_eq!(filtered.xattrs, vec![xattr("user.comment")]);
+
+        let mut filtered = meta.clone();
+        let trust = Trust {
+            owner: true,
+            xattrs: true,
+        };
+        trust.filter(&mut filtered);
+        assert_eq!(filtered, meta);
+
+        assert_eq!(Trust::default().mode(0o104755), 0o100755);
+        assert_eq!(Trust::default().mode(0o42775), 0o40775);
+        assert_eq!(trust.mode(0o106755), 0o106755);
+    }
+
+    #[test]
+    fn test_gather_apply() {
+        let dir = TestDir::create();
+        let src = File::create(dir.join("src")).expect("Test should never fail");
+        let dst = File::create(dir.join("dst")).expect("Test should never fail");
+
+        let times = TeleportTimes {
+            atime: 1_234_567_890,
+            atime_nsec: 1,
+            mtime: 1_234_567_891,
+            mtime_nsec: 2,
+        };
+        set_times(&src, &times).expect("Test should never fail");
+        // Not every filesystem supports user extended attributes
+        let xattrs = set_xattr(&src, b"user.teleporter", b"value").is_ok();
+
+        let preserve = [Preserve::Times, Preserve::Owner, Preserve::Xattrs];
+        let meta = gather(&src, &preserve).expect("Test should never fail");
+        assert_eq!(meta.times, Some(times));
+        assert!(meta.owner.is_some());
+        if xattrs {
+            assert!(meta
+                .xattrs
+                .iter()
+                .any(|x| x.name == b"user.teleporter" && x.value == b"value"));
+        }
+
+        apply(&dst, &meta, 0o640, "dst");
+        assert_eq!(
+            gather(&dst, &preserve).expect("Test should never fail"),
+            meta
+        );
+        let mode = dst.metadata().expect("Test should never fail").mode();
+        assert_eq!(mode & 0o7777, 0o640);
+
+        // Only requested items are gathered
+        let meta = gather(&src, &[Preserve::Times]).expect("Test should never fail");
+        assert!(meta.owner.is_none());
+        assert!(meta.xattrs.is_empty());
+    }
+
+    #[test]
+    fn test_inherit() {
+        let dir = TestDir::create();
+        let orig = File::create(dir.join("orig")).expect("Test should never fail");
+        let file = File::create(dir.join("file")).expect("Test should never fail");
+        orig.set_permissions(Permissions::from_mode(0o4751))
+            .expect("Test should never fail");
+        let xattrs = set_xattr(&orig, b"user.teleporter", b"value").is_ok();
+
+        // Setuid is only kept when the owner is trusted
+        inherit(&file, &orig, &Trust::default(), "file");
+        let meta = file.metadata().expect("Test should never fail");
+        let orig_meta = orig.metadata().expect("Test should never fail");
+        assert_eq!(meta.mode() & 0o7777, 0o751);
+        assert_eq!((meta.uid(), meta.gid()), (orig_meta.uid(), orig_meta.gid()));
+        if xattrs {
+            assert_eq!(
+                get_xattr(&file, b"user.teleporter").expect("Test should never fail"),
+                b"value"
+            );
+        }
+
+        let trust = Trust {
+            owner: true,
+            xattrs: false,
+        };
+        inherit(&file, &orig, &trust, "file");
+        let meta = file.metadata().expect("Test should never fail");
+        assert_eq!(meta.mode() & 0o7777, 0o4751);
+    }
+}
@@ -0,0 +1,181 @@
+use crate::utils;
+use std::ffi::OsStr;
+use std::path::Path;
+
+/// Names of the variables a placement template may use
+const VARS: [(&str, Var); 12] = [
+    ("peer_ip", Var::PeerIp),
+    ("identity", Var::Identity),
+    ("share", Var::Share),
+    ("date", Var::Date),
+    ("year", Var::Year),
+    ("month", Var::Month),
+    ("day", Var::Day),
+    ("path", Var::Path),
+    ("dir", Var::Dir),
+    ("filename", Var::Filename),
+    ("stem", Var::Stem),
+    ("ext", Var::Ext),
+];
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Var {
+    PeerIp,
+    Identity,
+    Share,
+    Date,
+    Year,
+    Month,
+    Day,
+    Path,
+    Dir,
+    Filename,
+    Stem,
+    Ext,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Part {
+    Text(String),
+    Var(Var),
+}
+
+/// Where the listener stores received files, e.g. `{peer_ip}/{date}/{filename}`.
+/// Braces are written as `{{` and `}}`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+/// What is known about a transfer when it is placed
+pub struct Vars<'a> {
+    pub peer_ip: String,
+    /// Name of the policy rule matching the client, or its address. Clients
+    /// carry no identity of their own.
+    pub identity: &'a str,
+    pub share: &'a str,
+    /// Seconds since the epoch
+    pub time: u64,
+    /// Path sent by the client, already normalized
+    pub path: &'a Path,
+}
+
+impl Template {
+    pub fn parse(template: &str) -> Result<Template, String> {
+        let mut parts = Vec::new();
+        let mut text = String::new();
+        let mut chars = template.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    text.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    text.push('}');
+                }
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => name.push(c),
+                            None => return Err(format!("unclosed {{{name}")),
+                        }
+                    }
+                    let var = match VARS.iter().find(|(n, _)| *n == name) {
+                        Some((_, v)) => *v,
+                        None => return Err(format!("unknown variable {{{name}}}")),
+                    };
+                    if !text.is_empty() {
+                        parts.push(Part::Text(std::mem::take(&mut text)));
+                    }
+                    parts.push(Part::Var(var));
+                }
+                '}' => return Err("unmatched }".to_string()),
+                c => text.push(c),
+            }
+        }
+        if !text.is_empty() {
+            parts.push(Part::Text(text));
+        }
+
+        Ok(Template { parts })
+    }
+
+    /// Build the path a transfer is stored at, relative to the receive root.
+    /// Empty path components, e.g. from a missing extension, are left out.
+    pub fn expand(&self, vars: &Vars) -> String {
+        let date = utils::format_time(vars.time);
+        let name = |s: Option<&OsStr>| s.map_or(String::new(), |s| s.to_string_lossy().to_string());
+
+        let mut out = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Text(t) => out.push_str(t),
+                Part::Var(v) => out.push_str(&match v {
+                    Var::PeerIp => vars.peer_ip.clone(),
+                    Var::Identity => vars.identity.to_string(),
+                    Var::Share => vars.share.to_string(),
+                    Var::Date => date[..10].to_string(),
+                    Var::Year => date[..4].to_string(),
+                    Var::Month => date[5..7].to_string(),
+                    Var::Day => date[8..10].to_string(),
+                    Var::Path => vars.path.to_string_lossy().to_string(),
+                    Var::Dir => name(vars.path.parent().map(|p| p.as_os_str())),
+                    Var::Filename => name(vars.path.file_name()),
+                    Var::Stem => name(vars.path.file_stem()),
+                    Var::Ext => name(vars.path.extension()),
+                }),
+            }
+        }
+
+        out.split('/')
+            .filter(|c| !c.is_empty())
+            .collect::<Vec<&str>>()
+            .join("/")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(path: &Path) -> Vars<'_> {
+        Vars {
+            peer_ip: "192.168.1.20".to_string(),
+            identity: "lab",
+            share: "",
+            time: 1_700_000_000,
+            path,
+        }
+    }
+
+    #[test]
+    fn test_template_expand() {
+        let path = Path::new("photos/cat.jpg");
+        let t = Template::parse("{peer_ip}/{date}/{filename}").expect("Test should never fail");
+        assert_eq!(t.expand(&vars(path)), "192.168.1.20/2023-11-14/cat.jpg");
+
+        let t = Template::parse("{identity}/{share}/{path}").expect("Test should never fail");
+        assert_eq!(t.expand(&vars(path)), "lab/photos/cat.jpg");
+
+        let t = Template::parse("by-ext/{ext}/{stem}-{year}{month}{day}.{ext}")
+            .expect("Test should never fail");
+        assert_eq!(t.expand(&vars(path)), "by-ext/jpg/cat-20231114.jpg");
+
+        let t = Template::parse("{ext}/{filename}").expect("Test should never fail");
+        assert_eq!(t.expand(&vars(Path::new("src/Makefile"))), "Makefile");
+
+        let t = Template::parse("{{{dir}}}").expect("Test should never fail");
+        assert_eq!(t.expand(&vars(path)), "{photos}");
+    }
+
+    #[test]
+    fn test_template_invalid() {
+        assert!(Template::parse("{peer}/{filename}").is_err());
+        assert!(Template::parse("{filename").is_err());
+        assert!(Template::parse("files}/{filename}").is_err());
+    }
+}
@@ -0,0 +1,192 @@
+use crate::config;
+use crate::errors::TeleportError;
+use crate::sandbox;
+use crate::utils;
+use ipnetwork::IpNetwork;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+/// What clients matching a set of networks are allowed to do
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Rule {
+    pub name: String,
+    pub networks: Vec<IpNetwork>,
+    /// Directories (relative to the receive root) that may be written to,
+    /// any path if empty
+    pub paths: Vec<PathBuf>,
+    pub overwrite: bool,
+    pub backup: bool,
+    pub rename: bool,
+    pub max_size: Option<u64>,
+}
+
+impl Rule {
+    pub fn matches(&self, ip: IpAddr) -> bool {
+        self.networks.iter().any(|n| n.contains(ip))
+    }
+
+    /// Check a path received from the client against the allowed paths
+    pub fn allows_path(&self, filename: &str) -> bool {
+        if self.paths.is_empty() {
+            return true;
+        }
+
+        match sandbox::normalize(filename) {
+            Ok(path) => self.allows(&path),
+            Err(_) => false,
+        }
+    }
+
+    /// Check a path relative to the receive root, with any symlinks already
+    /// expanded, against the allowed paths
+    pub fn allows(&self, path: &Path) -> bool {
+        self.paths.is_empty() || self.paths.iter().any(|p| path.starts_with(p))
+    }
+}
+
+/// Access rules for the listener. The first rule matching the client
+/// address applies, clients that match no rule are denied.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Policy {
+    pub rules: Vec<Rule>,
+}
+
+impl Policy {
+    /// Load a policy file, one section per rule:
+    /// ```text
+    /// [lan]
+    /// match = 192.168.1.0/24, fd00::/8
+    /// paths = uploads, builds/nightly
+    /// overwrite = true
+    /// backup = true
+    /// rename = true
+    /// max_size = 10G
+    /// ```
+    pub fn load(file: &Path) -> Result<Policy, TeleportError> {
+        Policy::parse(&config::load(file)?)
+    }
+
+    fn parse(sections: &[config::Section]) -> Result<Policy, TeleportError> {
+        let mut rules = Vec::<Rule>::new();
+
+        for section in sections {
+            let mut rule = Rule {
+                name: section.name.clone(),
+                networks: Vec::new(),
+                paths: Vec::new(),
+                overwrite: false,
+                backup: false,
+                rename: false,
+                max_size: None,
+            };
+
+            for entry in &section.entries {
+                match entry.key.as_str() {
+                    "match" => {
+                        for net in list(&entry.value) {
+                            match net.parse::<IpNetwork>() {
+                                Ok(n) => rule.networks.push(n),
+                                Err(_) => return Err(entry.error("invalid network in")),
+                            }
+                        }
+                    }
+                    "paths" => {
+                        for path in list(&entry.value) {
+                            match sandbox::normalize(path) {
+                                Ok(p) => rule.paths.push(p),
+                                Err(_) => return Err(entry.error("invalid path in")),
+                            }
+                        }
+                    }
+                    "overwrite" => rule.overwrite = entry.as_bool()?,
+                    "backup" => rule.backup = entry.as_bool()?,
+                    "rename" => rule.rename = entry.as_bool()?,
+                    "max_size" => match utils::parse_size(&entry.value) {
+                        Some(s) => rule.max_size = Some(s),
+                        None => return Err(entry.error("invalid size for")),
+                    },
+                    _ => return Err(entry.error("unknown policy option")),
+                }
+            }
+
+            if rule.networks.is_empty() {
+                return Err(TeleportError::InvalidConfig(format!(
+                    "line {}: rule [{}] does not match any network",
+                    section.line, section.name
+                )));
+            }
+
+            rules.push(rule);
+        }
+
+        Ok(Policy { rules })
+    }
+
+    /// Find the rule that applies to a client
+    pub fn rule(&self, ip: IpAddr) -> Option<&Rule> {
+        let ip = utils::canonical_ip(ip);
+        self.rules.iter().find(|r| r.matches(ip))
+    }
+}
+
+/// Split a comma separated list, ignoring empty items
+fn list(value: &str) -> impl Iterator<Item = &str> {
+    value.split(',').map(str::trim).filter(|s| !s.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TESTPOLICY: &str = "[lan]
+match = 192.168.1.0/24, fd00::/8
+paths = uploads, ./builds//nightly
+overwrite = yes
+max_size = 10M
+
+[any]
+match = 0.0.0.0/0
+rename = true
+";
+
+    #[test]
+    fn test_policy_rule() {
+        let sections = config::parse(TESTPOLICY).expect("Test should never fail");
+        let policy = Policy::parse(&sections).expect("Test should never fail");
+
+        let lan = policy
+            .rule(
+                "::ffff:192.168.1.20"
+                    .parse()
+                    .expect("Test should never fail"),
+            )
+            .expect("Test should never fail");
+        assert_eq!(lan.name, "lan");
+        assert!(lan.overwrite);
+        assert!(!lan.rename);
+        assert_eq!(lan.max_size, Some(10 << 20));
+
+        let any = policy
+            .rule("10.0.0.1".parse().expect("Test should never fail"))
+            .expect("Test should never fail");
+        assert_eq!(any.name, "any");
+
+        assert!(policy
+            .rule("fe80::1".parse().expect("Test should never fail"))
+            .is_none());
+    }
+
+    #[test]
+    fn test_policy_paths() {
+        let sections = config::parse(TESTPOLICY).expect("Test should never fail");
+        let policy = Policy::parse(&sections).expect("Test should never fail");
+        let lan = &policy.rules[0];
+
+        assert!(lan.allows_path("uploads/file"));
+        assert!(lan.allows_path("./builds/nightly/a/b"));
+        assert!(!lan.allows_path("uploads-other/file"));
+        assert!(!lan.allows_path("builds/file"));
+        assert!(!lan.allows_path("uploads/../etc/file"));
+        assert!(policy.rules[1].allows_path("anything"));
+    }
+}
@@ -0,0 +1,147 @@
+use std::panic;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::thread::JoinHandle;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// A fixed number of worker threads fed through a bounded queue. Submitting
+/// a job blocks while all workers are busy and the queue is full, which
+/// pushes back on whoever produces the jobs.
+pub struct Pool {
+    sender: Option<SyncSender<Job>>,
+    workers: Vec<JoinHandle<()>>,
+    busy: Arc<AtomicUsize>,
+}
+
+impl Pool {
+    pub fn new(size: usize, queue: usize) -> Pool {
+        let (sender, receiver) = sync_channel::<Job>(queue);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let busy = Arc::new(AtomicUsize::new(0));
+
+        let workers = (0..size.max(1))
+            .map(|_| {
+                let receiver = Arc::clone(&receiver);
+                let busy = Arc::clone(&busy);
+                thread::spawn(move || worker(&receiver, &busy))
+            })
+            .collect();
+
+        Pool {
+            sender: Some(sender),
+            workers,
+            busy,
+        }
+    }
+
+    /// Check if a job would start right away
+    pub fn has_idle_worker(&self) -> bool {
+        self.busy.load(Ordering::SeqCst) < self.workers.len()
+    }
+
+    /// Run a job on the next free worker, waiting for one if required
+    pub fn execute<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        // Counted as busy from submission, so that a following check does
+        // not see a worker as idle before it picked up the job
+        self.busy.fetch_add(1, Ordering::SeqCst);
+        self.sender
+            .as_ref()
+            .expect("Fatal error: worker pool stopped")
+            .send(Box::new(job))
+            .expect("Fatal error: worker pool stopped");
+    }
+
+    /// Wait for all submitted jobs to finish
+    fn stop(&mut self) {
+        // Closing the queue ends the workers once it is drained
+        self.sender.take();
+        for w in self.workers.drain(..) {
+            let _ = w.join();
+        }
+    }
+}
+
+impl Drop for Pool {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn worker(receiver: &Mutex<Receiver<Job>>, busy: &AtomicUsize) {
+    loop {
+        let job = receiver
+            .lock()
+            .expect("Fatal error locking job queue")
+            .recv();
+        match job {
+            // A panicking job must not take the worker down with it
+            Ok(job) => {
+                let _ = panic::catch_unwind(panic::AssertUnwindSafe(job));
+                busy.fetch_sub(1, Ordering::SeqCst);
+            }
+            Err(_) => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_pool_runs_jobs() {
+        let done = Arc::new(AtomicUsize::new(0));
+        let pool = Pool::new(4, 0);
+        for _ in 0..32 {
+            let done = Arc::clone(&done);
+            pool.execute(move || {
+                done.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+
+        // Dropping the pool waits for the submitted jobs
+        drop(pool);
+        assert_eq!(done.load(Ordering::SeqCst), 32);
+    }
+
+    #[test]
+    fn test_pool_idle_worker() {
+        let pool = Pool::new(1, 0);
+        assert!(pool.has_idle_worker());
+
+        let (release, wait) = channel::<()>();
+        pool.execute(move || {
+            let _ = wait.recv();
+        });
+        assert!(!pool.has_idle_worker());
+
+        release.send(()).expect("Test should never fail");
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !pool.has_idle_worker() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(pool.has_idle_worker());
+    }
+
+    #[test]
+    fn test_pool_survives_panic() {
+        let done = Arc::new(AtomicUsize::new(0));
+        let pool = Pool::new(1, 0);
+        pool.execute(|| panic!("Job panicked on purpose"));
+        let counter = Arc::clone(&done);
+        pool.execute(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+
+        drop(pool);
+        assert_eq!(done.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,222 @@
+use crate::utils;
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct Entry {
+    id: u64,
+    time: Instant,
+    size: u64,
+}
+
+#[derive(Debug, Default)]
+struct Clients {
+    next_id: u64,
+    list: HashMap<IpAddr, VecDeque<Entry>>,
+}
+
+/// Limits on the bytes and files each client may send over a rolling window
+#[derive(Debug)]
+pub struct Quota {
+    bytes: Option<u64>,
+    files: Option<u64>,
+    window: Duration,
+    clients: Mutex<Clients>,
+}
+
+/// A file counted against the quota of a client. The charge is refunded if
+/// it is dropped without being kept, e.g. when the transfer fails.
+#[derive(Debug)]
+pub struct Charge<'a> {
+    quota: &'a Quota,
+    ip: IpAddr,
+    id: u64,
+    kept: bool,
+}
+
+impl Quota {
+    pub fn new(bytes: Option<u64>, files: Option<u64>, window: Duration) -> Quota {
+        Quota {
+            bytes,
+            files,
+            window,
+            clients: Mutex::new(Clients::default()),
+        }
+    }
+
+    /// Count a file of `size` bytes against the quota of a client, unless
+    /// that would exceed it
+    pub fn charge(&self, ip: IpAddr, size: u64) -> Option<Charge<'_>> {
+        let ip = utils::canonical_ip(ip);
+        let mut charge = Charge {
+            quota: self,
+            ip,
+            id: 0,
+            kept: true,
+        };
+        if self.bytes.is_none() && self.files.is_none() {
+            return Some(charge);
+        }
+
+        let now = Instant::now();
+        let mut clients = self.clients.lock().expect("Fatal error locking quota");
+
+        // Forget anything older than the window
+        clients.list.retain(|_, list| {
+            while list
+                .front()
+                .map_or(false, |e| now.duration_since(e.time) > self.window)
+            {
+                list.pop_front();
+            }
+            !list.is_empty()
+        });
+
+        let list = clients.list.entry(ip).or_default();
+        let used = list
+            .iter()
+            .fold(0u64, |used, e| used.saturating_add(e.size));
+        if self
+            .bytes
+            .map_or(false, |max| used.saturating_add(size) > max)
+            || self.files.map_or(false, |max| list.len() as u64 >= max)
+        {
+            return None;
+        }
+
+        clients.next_id += 1;
+        charge.id = clients.next_id;
+        charge.kept = false;
+        let entry = Entry {
+            id: charge.id,
+            time: now,
+            size,
+        };
+        clients.list.entry(ip).or_default().push_back(entry);
+
+        Some(charge)
+    }
+}
+
+impl Charge<'_> {
+    /// Keep the charge once the file has been received
+    pub fn keep(mut self) {
+        self.kept = true;
+    }
+}
+
+impl Drop for Charge<'_> {
+    fn drop(&mut self) {
+        if self.kept {
+            return;
+        }
+        let mut clients = self
+            .quota
+            .clients
+            .lock()
+            .expect("Fatal error locking quota");
+        if let Some(list) = clients.list.get_mut(&self.ip) {
+            list.retain(|e| e.id != self.id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    const WINDOW: Duration = Duration::from_secs(3600);
+
+    fn ip(addr: &str) -> IpAddr {
+        addr.parse().expect("Test should never fail")
+    }
+
+    #[test]
+    fn test_quota_bytes() {
+        let quota = Quota::new(Some(100), None, WINDOW);
+        let client = ip("192.168.1.20");
+
+        quota
+            .charge(client, 60)
+            .expect("Test should never fail")
+            .keep();
+        assert!(quota.charge(client, 50).is_none());
+        // Other clients have their own quota, IPv4 clients of IPv6 sockets
+        // share that of the IPv4 address
+        assert!(quota.charge(ip("192.168.1.21"), 50).is_some());
+        assert!(quota.charge(ip("::ffff:192.168.1.20"), 50).is_none());
+        quota
+            .charge(client, 40)
+            .expect("Test should never fail")
+            .keep();
+        assert!(quota.charge(client, 1).is_none());
+
+        // Declared sizes cannot overflow the sum
+        let quota = Quota::new(Some(u64::MAX - 1), None, WINDOW);
+        quota
+            .charge(client, u64::MAX - 1)
+            .expect("Test should never fail")
+            .keep();
+        assert!(quota.charge(client, 2).is_none());
+    }
+
+    #[test]
+    fn test_quota_files() {
+        let quota = Quota::new(None, Some(2), WINDOW);
+        let client = ip("fd00::1");
+
+        for _ in 0..2 {
+            quota
+                .charge(client, u64::MAX)
+                .expect("Test should never fail")
+                .keep();
+        }
+        assert!(quota.charge(client, 0).is_none());
+
+        // Without limits every file is accepted
+        let quota = Quota::new(None, None, WINDOW);
+        for _ in 0..10 {
+            assert!(quota.charge(client, u64::MAX).is_some());
+        }
+    }
+
+    #[test]
+    fn test_quota_refund() {
+        let quota = Quota::new(Some(100), Some(1), WINDOW);
+        let client = ip("192.168.1.20");
+
+        // A transfer in progress counts, one that failed does not
+        let charge = quota.charge(client, 100).expect("Test should never fail");
+        assert!(quota.charge(client, 1).is_none());
+        drop(charge);
+        quota
+            .charge(client, 100)
+            .expect("Test should never fail")
+            .keep();
+        assert!(quota.charge(client, 1).is_none());
+    }
+
+    #[test]
+    fn test_quota_window() {
+        let quota = Quota::new(Some(100), None, Duration::from_millis(50));
+        let client = ip("192.168.1.20");
+
+        quota
+            .charge(client, 100)
+            .expect("Test should never fail")
+            .keep();
+        assert!(quota.charge(client, 1).is_none());
+
+        // Files older than the window no longer count
+        thread::sleep(Duration::from_millis(100));
+        quota
+            .charge(client, 100)
+            .expect("Test should never fail")
+            .keep();
+        let clients = quota.clients.lock().expect("Test should never fail");
+        assert_eq!(clients.list[&client].len(), 1);
+    }
+}
@@ -0,0 +1,42 @@
+use crate::beacon;
+use crate::errors::TeleportError;
+use crate::send::{check_status, request, Next};
+use crate::teleport::{TeleportAction, TeleportFeatures, TeleportInit, TeleportRestore};
+use crate::{RemoteAction, RemoteOpt, SendOpt};
+
+/// Client function for requests that manage files on the server
+pub fn run(opt: RemoteOpt) -> Result<(), TeleportError> {
+    let mut send = SendOpt {
+        dest: opt.dest,
+        port: opt.port,
+        encrypt: opt.encrypt,
+        share: opt.share,
+        ..Default::default()
+    };
+    beacon::resolve_dest(&mut send)?;
+
+    match opt.action {
+        RemoteAction::Restore { file, generation } => restore(&send, &file, generation),
+    }
+}
+
+fn restore(opt: &SendOpt, file: &str, generation: u16) -> Result<(), TeleportError> {
+    // Restoring replaces the current file, which is kept as a new backup
+    let mut init = TeleportInit::new(TeleportFeatures::NewFile);
+    TeleportFeatures::Overwrite.add_u32(&mut init.features);
+    if let Some(share) = &opt.share {
+        TeleportFeatures::Share.add_u32(&mut init.features);
+        init.share = Some(share.as_bytes().to_vec());
+    }
+    init.filename = file.as_bytes().to_vec();
+    init.advertise_revision();
+
+    let req = TeleportRestore { generation, init };
+    let (_, _, recv) = request(opt, TeleportAction::Restore, req.serialize()?, &mut false)?;
+
+    if let Next::Proceed = check_status(&recv, file, opt)? {
+        println!("Restored {file} to backup generation {generation}");
+    }
+
+    Ok(())
+}
@@ -0,0 +1,629 @@
+use crate::errors::TeleportError;
+use crate::utils;
+use std::collections::VecDeque;
+use std::ffi::{CStr, CString, OsStr, OsString};
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::{Component, Path, PathBuf};
+
+/// Maximum number of symlinks expanded while resolving a single path
+const MAX_LINKS: usize = 40;
+
+/// What to do with directories that do not exist while walking a path
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Missing {
+    Fail,
+    Create,
+    /// Continue as if they existed, without opening anything
+    Assume,
+}
+
+/// Split a path received from a client into its components, rejecting
+/// anything that could refer to a location outside of the receive root
+pub fn normalize(path: &str) -> Result<PathBuf, TeleportError> {
+    if path.contains('\0') {
+        return Err(TeleportError::InvalidFileName);
+    }
+
+    let mut out = PathBuf::new();
+    for c in Path::new(path).components() {
+        match c {
+            Component::Normal(n) => out.push(n),
+            Component::CurDir => (),
+            Component::RootDir | Component::Prefix(_) | Component::ParentDir => {
+                return Err(TeleportError::InvalidFileName)
+            }
+        }
+    }
+
+    if out.as_os_str().is_empty() {
+        return Err(TeleportError::InvalidFileName);
+    }
+
+    Ok(out)
+}
+
+fn cstr(name: &OsStr) -> io::Result<CString> {
+    CString::new(name.as_bytes()).map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))
+}
+
+/// An open directory that is used as the base of `*at()` operations, so that
+/// no path outside of it is ever resolved again
+#[derive(Debug)]
+pub struct Dir {
+    fd: File,
+}
+
+impl AsRawFd for Dir {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl Dir {
+    pub fn open(path: &Path) -> io::Result<Dir> {
+        let path = cstr(path.as_os_str())?;
+        let fd = utils::cvt(unsafe {
+            libc::open(
+                path.as_ptr(),
+                libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC,
+            )
+        })?;
+        Ok(Dir {
+            fd: unsafe { File::from_raw_fd(fd) },
+        })
+    }
+
+    pub fn try_clone(&self) -> io::Result<Dir> {
+        Ok(Dir {
+            fd: self.fd.try_clone()?,
+        })
+    }
+
+    /// The directory itself, e.g. to change its permissions
+    pub fn file(&self) -> &File {
+        &self.fd
+    }
+
+    /// Open a file in this directory. The final component is never followed
+    /// if it is a symlink.
+    pub fn open_file(&self, name: &OsStr, flags: libc::c_int, mode: u32) -> io::Result<File> {
+        let name = cstr(name)?;
+        let fd = utils::cvt(unsafe {
+            libc::openat(
+                self.as_raw_fd(),
+                name.as_ptr(),
+                flags | libc::O_NOFOLLOW | libc::O_CLOEXEC,
+                mode as libc::c_uint,
+            )
+        })?;
+        Ok(unsafe { File::from_raw_fd(fd) })
+    }
+
+    /// Open a subdirectory without following symlinks
+    pub fn open_dir(&self, name: &OsStr) -> io::Result<Dir> {
+        let fd = self.open_file(name, libc::O_RDONLY | libc::O_DIRECTORY, 0)?;
+        Ok(Dir { fd })
+    }
+
+    /// Names of the entries in this directory
+    pub fn list(&self) -> io::Result<Vec<OsString>> {
+        let fd = self.open_file(OsStr::new("."), libc::O_RDONLY | libc::O_DIRECTORY, 0)?;
+        let dir = unsafe { libc::fdopendir(fd.as_raw_fd()) };
+        if dir.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        // The stream owns the descriptor from now on
+        std::mem::forget(fd);
+
+        let mut names = Vec::new();
+        loop {
+            let entry = unsafe { libc::readdir(dir) };
+            if entry.is_null() {
+                break;
+            }
+            let name = unsafe { CStr::from_ptr((*entry).d_name.as_ptr()) };
+            let name = OsStr::from_bytes(name.to_bytes());
+            if name != "." && name != ".." {
+                names.push(name.to_os_string());
+            }
+        }
+        unsafe { libc::closedir(dir) };
+
+        Ok(names)
+    }
+
+    /// Get the metadata of an entry without following symlinks
+    pub fn metadata(&self, name: &OsStr) -> io::Result<fs::Metadata> {
+        self.open_file(name, libc::O_PATH, 0)?.metadata()
+    }
+
+    pub fn exists(&self, name: &OsStr) -> bool {
+        self.metadata(name).is_ok()
+    }
+
+    pub fn mkdir(&self, name: &OsStr, mode: u32) -> io::Result<()> {
+        let name = cstr(name)?;
+        utils::cvt(unsafe {
+            libc::mkdirat(self.as_raw_fd(), name.as_ptr(), mode as libc::mode_t)
+        })?;
+        Ok(())
+    }
+
+    pub fn rename(&self, from: &OsStr, to: &OsStr) -> io::Result<()> {
+        self.move_to(from, self, to)
+    }
+
+    /// Move `from` in this directory to `to` in `to_dir`, which must be on
+    /// the same filesystem
+    pub fn move_to(&self, from: &OsStr, to_dir: &Dir, to: &OsStr) -> io::Result<()> {
+        let from = cstr(from)?;
+        let to = cstr(to)?;
+        utils::cvt(unsafe {
+            libc::renameat(
+                self.as_raw_fd(),
+                from.as_ptr(),
+                to_dir.as_raw_fd(),
+                to.as_ptr(),
+            )
+        })?;
+        Ok(())
+    }
+
+    /// Move `from` like `move_to`, but fail with AlreadyExists instead of
+    /// replacing an existing `to`. Filesystems that cannot rename without
+    /// replacing get a hard link that is then removed from here.
+    pub fn move_new(&self, from: &OsStr, to_dir: &Dir, to: &OsStr) -> io::Result<()> {
+        let cfrom = cstr(from)?;
+        let cto = cstr(to)?;
+        let moved = utils::cvt(unsafe {
+            libc::renameat2(
+                self.as_raw_fd(),
+                cfrom.as_ptr(),
+                to_dir.as_raw_fd(),
+                cto.as_ptr(),
+                libc::RENAME_NOREPLACE,
+            )
+        });
+        match moved {
+            Ok(_) => Ok(()),
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) => {
+                to_dir.link(self, from, to)?;
+                self.remove(from)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn remove(&self, name: &OsStr) -> io::Result<()> {
+        let name = cstr(name)?;
+        utils::cvt(unsafe { libc::unlinkat(self.as_raw_fd(), name.as_ptr(), 0) })?;
+        Ok(())
+    }
+
+    pub fn symlink(&self, target: &OsStr, name: &OsStr) -> io::Result<()> {
+        let target = cstr(target)?;
+        let name = cstr(name)?;
+        utils::cvt(unsafe { libc::symlinkat(target.as_ptr(), self.as_raw_fd(), name.as_ptr()) })?;
+        Ok(())
+    }
+
+    /// Create `name` in this directory as a hard link to `from` in `from_dir`
+    pub fn link(&self, from_dir: &Dir, from: &OsStr, name: &OsStr) -> io::Result<()> {
+        let from = cstr(from)?;
+        let name = cstr(name)?;
+        utils::cvt(unsafe {
+            libc::linkat(
+                from_dir.as_raw_fd(),
+                from.as_ptr(),
+                self.as_raw_fd(),
+                name.as_ptr(),
+                0,
+            )
+        })?;
+        Ok(())
+    }
+
+    /// Space available to unprivileged users on the filesystem
+    pub fn free_space(&self) -> io::Result<u64> {
+        let mut st: libc::statvfs = unsafe { std::mem::zeroed() };
+        utils::cvt(unsafe { libc::fstatvfs(self.as_raw_fd(), &mut st) })?;
+        Ok(st.f_bavail as u64 * st.f_frsize as u64)
+    }
+
+    pub fn readlink(&self, name: &OsStr) -> io::Result<PathBuf> {
+        let name = cstr(name)?;
+        let mut buf = vec![0u8; libc::PATH_MAX as usize];
+        let len = unsafe {
+            libc::readlinkat(
+                self.as_raw_fd(),
+                name.as_ptr(),
+                buf.as_mut_ptr() as *mut libc::c_char,
+                buf.len(),
+            )
+        };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        buf.truncate(len as usize);
+        Ok(PathBuf::from(OsString::from_vec(buf)))
+    }
+}
+
+/// A resolved destination: the open directory containing it and its name
+#[derive(Debug)]
+pub struct Location {
+    pub dir: Dir,
+    pub name: OsString,
+    /// The path relative to the receive root, for display
+    pub path: PathBuf,
+    /// The path relative to the receive root with all symlinks expanded
+    pub real: PathBuf,
+}
+
+impl Location {
+    pub fn display(&self) -> String {
+        self.path.display().to_string()
+    }
+
+    /// Change the final component of the location
+    pub fn set_name(&mut self, name: OsString) {
+        self.path.set_file_name(&name);
+        self.real.set_file_name(&name);
+        self.name = name;
+    }
+}
+
+/// The directory that all received files are placed in
+#[derive(Debug)]
+pub struct Sandbox {
+    root: Dir,
+    root_path: PathBuf,
+    dangerous: bool,
+}
+
+impl Sandbox {
+    /// Open the receive root. If `dangerous` is set, paths are not confined
+    /// to the root and may be absolute or contain `..`.
+    pub fn new(root: &Path, dangerous: bool) -> io::Result<Sandbox> {
+        Ok(Sandbox {
+            root: Dir::open(root)?,
+            root_path: root.to_path_buf(),
+            dangerous,
+        })
+    }
+
+    /// The directory the sandbox was opened with
+    pub fn root(&self) -> &Path {
+        &self.root_path
+    }
+
+    /// Space available on the filesystem of the root
+    pub fn free_space(&self) -> io::Result<u64> {
+        self.root.free_space()
+    }
+
+    /// The path of a location on disk
+    pub fn path(&self, loc: &Location) -> PathBuf {
+        self.root_path.join(&loc.path)
+    }
+
+    /// Resolve a path received from a client to the directory that contains
+    /// it, creating any missing directories if `create` is set
+    pub fn resolve(&self, path: &str, create: bool) -> Result<Location, TeleportError> {
+        if self.dangerous {
+            return self.resolve_unconfined(path, create);
+        }
+
+        let path = normalize(path)?;
+        let name = path
+            .file_name()
+            .ok_or(TeleportError::InvalidFileName)?
+            .to_os_string();
+        let parent = path.parent().unwrap_or_else(|| Path::new(""));
+        let missing = match create {
+            true => Missing::Create,
+            false => Missing::Fail,
+        };
+        let (dir, mut real) = self.walk(parent, missing)?;
+        real.push(&name);
+
+        Ok(Location {
+            dir: dir.expect("Fatal error resolving path"),
+            name,
+            real,
+            path,
+        })
+    }
+
+    /// Check where a path received from a client leads like `resolve`, but
+    /// without creating anything. Returns the path relative to the root with
+    /// symlinks expanded, taking missing directories as they are named.
+    pub fn locate(&self, path: &str) -> Result<PathBuf, TeleportError> {
+        if self.dangerous {
+            if path.contains('\0') {
+                return Err(TeleportError::InvalidFileName);
+            }
+            return Ok(PathBuf::from(path));
+        }
+
+        let path = normalize(path)?;
+        let name = path.file_name().ok_or(TeleportError::InvalidFileName)?;
+        let parent = path.parent().unwrap_or_else(|| Path::new(""));
+        let (_, mut real) = self.walk(parent, Missing::Assume)?;
+        real.push(name);
+
+        Ok(real)
+    }
+
+    fn resolve_unconfined(&self, path: &str, create: bool) -> Result<Location, TeleportError> {
+        if path.contains('\0') {
+            return Err(TeleportError::InvalidFileName);
+        }
+        let path = PathBuf::from(path);
+        let name = match path.components().next_back() {
+            Some(Component::Normal(n)) => n.to_os_string(),
+            _ => return Err(TeleportError::InvalidFileName),
+        };
+        let parent = self
+            .root_path
+            .join(path.parent().unwrap_or_else(|| Path::new("")));
+        if create {
+            fs::create_dir_all(&parent)?;
+        }
+        let dir = Dir::open(&parent)?;
+
+        Ok(Location {
+            dir,
+            name,
+            real: path.clone(),
+            path,
+        })
+    }
+
+    /// Walk the directories of `parent` one component at a time. Symlinks
+    /// are expanded manually and may never leave the root. Returns the
+    /// directory, unless it is missing, and its path relative to the root
+    /// once symlinks are expanded.
+    fn walk(
+        &self,
+        parent: &Path,
+        missing: Missing,
+    ) -> Result<(Option<Dir>, PathBuf), TeleportError> {
+        let mut stack = vec![self.root.try_clone()?];
+        let mut real = PathBuf::new();
+        // Components below the last directory that exists
+        let mut assumed = 0;
+        let mut pending: VecDeque<OsString> = parent
+            .components()
+            .map(|c| c.as_os_str().to_os_string())
+            .collect();
+        let mut links = 0;
+
+        while let Some(c) = pending.pop_front() {
+            if c == ".." && assumed > 0 {
+                assumed -= 1;
+                real.pop();
+                continue;
+            }
+            if c == ".." {
+                if stack.len() == 1 {
+                    return Err(TeleportError::UnsafePath);
+                }
+                stack.pop();
+                real.pop();
+                continue;
+            }
+            if c == "." || c.is_empty() {
+                continue;
+            }
+            if assumed > 0 {
+                assumed += 1;
+                real.push(&c);
+                continue;
+            }
+
+            let cur = stack.last().expect("Fatal error resolving path");
+            let next = match cur.open_dir(&c) {
+                Ok(d) => d,
+                Err(e) if e.kind() == io::ErrorKind::NotFound && missing == Missing::Assume => {
+                    assumed = 1;
+                    real.push(&c);
+                    continue;
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound && missing == Missing::Create => {
+                    if let Err(e) = cur.mkdir(&c, 0o755) {
+                        if e.kind() != io::ErrorKind::AlreadyExists {
+                            return Err(TeleportError::Io(e));
+                        }
+                    }
+                    cur.open_dir(&c)?
+                }
+                Err(e)
+                    if e.raw_os_error() == Some(libc::ELOOP)
+                        || e.raw_os_error() == Some(libc::ENOTDIR) =>
+                {
+                    // Expand symlinks relative to the current directory
+                    let target = cur.readlink(&c).map_err(|_| e)?;
+                    links += 1;
+                    if links > MAX_LINKS || target.has_root() {
+                        return Err(TeleportError::UnsafePath);
+                    }
+                    for t in target.components().rev() {
+                        pending.push_front(t.as_os_str().to_os_string());
+                    }
+                    continue;
+                }
+                Err(e) => return Err(TeleportError::Io(e)),
+            };
+            stack.push(next);
+            real.push(&c);
+        }
+
+        match assumed {
+            0 => Ok((stack.pop(), real)),
+            _ => Ok((None, real)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::TestDir;
+    use std::os::unix::fs::symlink;
+
+    fn test_root() -> TestDir {
+        let root = TestDir::create();
+        fs::create_dir_all(root.join("inside")).expect("Test should never fail");
+        root
+    }
+
+    #[test]
+    fn test_normalize_valid() {
+        assert_eq!(normalize("file").unwrap(), PathBuf::from("file"));
+        assert_eq!(normalize("./a//b/./c").unwrap(), PathBuf::from("a/b/c"));
+        assert_eq!(normalize("....//etc").unwrap(), PathBuf::from("..../etc"));
+        assert_eq!(
+            normalize("..foo/bar..").unwrap(),
+            PathBuf::from("..foo/bar..")
+        );
+        assert_eq!(normalize("%2e%2e/x").unwrap(), PathBuf::from("%2e%2e/x"));
+        assert_eq!(normalize("..\\..\\x").unwrap(), PathBuf::from("..\\..\\x"));
+    }
+
+    #[test]
+    fn test_normalize_bypass() {
+        for p in [
+            "",
+            ".",
+            "./",
+            "..",
+            "../x",
+            "../../etc/passwd",
+            "a/../../x",
+            "a/b/../../../x",
+            "./../x",
+            "/etc/passwd",
+            "//etc/passwd",
+            "/../x",
+            "a/..",
+            "file\0.txt",
+            "a\0/../../x",
+        ] {
+            assert!(normalize(p).is_err(), "{p:?} should be rejected");
+        }
+    }
+
+    #[test]
+    fn test_resolve_inside() {
+        let root = test_root();
+        let sandbox = Sandbox::new(&root, false).expect("Test should never fail");
+
+        let loc = sandbox
+            .resolve("new/dir/file", true)
+            .expect("Test should never fail");
+        assert_eq!(loc.name, "file");
+        assert_eq!(loc.display(), "new/dir/file");
+        assert!(root.join("new/dir").is_dir());
+
+        // Symlinks that stay inside of the root may be followed
+        symlink("inside", root.join("ok")).expect("Test should never fail");
+        symlink("../inside/..", root.join("inside/up")).expect("Test should never fail");
+        let loc = sandbox
+            .resolve("ok/file", false)
+            .expect("Test should never fail");
+        assert_eq!(loc.display(), "ok/file");
+        assert_eq!(loc.real, PathBuf::from("inside/file"));
+        let loc = sandbox
+            .resolve("inside/up/ok/file", false)
+            .expect("Test should never fail");
+        assert_eq!(loc.real, PathBuf::from("inside/file"));
+    }
+
+    #[test]
+    fn test_locate() {
+        let root = test_root();
+        let sandbox = Sandbox::new(&root, false).expect("Test should never fail");
+
+        // Nothing is created, missing directories are taken as named
+        assert_eq!(
+            sandbox
+                .locate("new/dir/file")
+                .expect("Test should never fail"),
+            PathBuf::from("new/dir/file")
+        );
+        assert!(!root.join("new").exists());
+
+        // Symlinks are expanded as far as they exist
+        symlink("inside", root.join("ok")).expect("Test should never fail");
+        symlink("new/../..", root.join("inside/up")).expect("Test should never fail");
+        assert_eq!(
+            sandbox
+                .locate("ok/new/file")
+                .expect("Test should never fail"),
+            PathBuf::from("inside/new/file")
+        );
+        assert_eq!(
+            sandbox
+                .locate("inside/up/file")
+                .expect("Test should never fail"),
+            PathBuf::from("file")
+        );
+
+        symlink("..", root.join("parent")).expect("Test should never fail");
+        symlink("new/../../..", root.join("inside/deep")).expect("Test should never fail");
+        for p in ["parent/file", "inside/deep/file", "../file"] {
+            assert!(sandbox.locate(p).is_err(), "{p:?} should be rejected");
+        }
+    }
+
+    #[test]
+    fn test_resolve_escape() {
+        let root = test_root();
+        let sandbox = Sandbox::new(&root, false).expect("Test should never fail");
+
+        symlink("/tmp", root.join("abs")).expect("Test should never fail");
+        symlink("..", root.join("parent")).expect("Test should never fail");
+        symlink("../..", root.join("inside/deep")).expect("Test should never fail");
+        symlink("loop", root.join("loop")).expect("Test should never fail");
+
+        for p in ["abs/file", "parent/file", "inside/deep/file", "loop/file"] {
+            assert!(
+                sandbox.resolve(p, true).is_err(),
+                "{p:?} should be rejected"
+            );
+        }
+    }
+
+    #[test]
+    fn test_move_new() {
+        let root = test_root();
+        fs::write(root.join("a"), "a").expect("Test should never fail");
+        fs::write(root.join("b"), "b").expect("Test should never fail");
+        let dir = Dir::open(&root).expect("Test should never fail");
+        let inside = Dir::open(&root.join("inside")).expect("Test should never fail");
+
+        // An existing destination is kept, and so is the source
+        let err = dir
+            .move_new(OsStr::new("a"), &dir, OsStr::new("b"))
+            .expect_err("Test should never fail");
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(
+            fs::read(root.join("b")).expect("Test should never fail"),
+            b"b"
+        );
+        assert!(root.join("a").exists());
+
+        dir.move_new(OsStr::new("a"), &inside, OsStr::new("a"))
+            .expect("Test should never fail");
+        assert!(!root.join("a").exists());
+        assert_eq!(
+            fs::read(root.join("inside/a")).expect("Test should never fail"),
+            b"a"
+        );
+    }
+}
@@ -1,53 +1,817 @@
+use crate::atomic::AtomicFile;
 use crate::errors::TeleportError;
+use crate::pool::Pool;
+use crate::sandbox::{Dir, Location};
 use crate::teleport::{
-    TeleportAction, TeleportFeatures, TeleportInit, TeleportInitAck, TeleportStatus,
+    TeleportAction, TeleportCapabilities, TeleportFeatures, TeleportInit, TeleportInitAck,
+    TeleportStatus,
 };
 use crate::utils;
+use crate::{beacon, discover, signals};
 use ipnetwork::IpNetwork;
 use pnet_datalink::interfaces;
-use std::net::SocketAddr;
+use pnet_datalink::NetworkInterface;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::Write;
 use std::net::TcpStream;
-use std::net::ToSocketAddrs;
-use std::time::Duration;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use crate::ScanOpt;
 
+/// How often the progress line is redrawn
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Scans in a row a listener must miss before `--watch` reports it gone
+const MISSES_BEFORE_GONE: u32 = 2;
+
+/// How often `--watch` checks for interruption between scans
+const WATCH_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
 pub fn run(opt: ScanOpt) -> Result<(), TeleportError> {
-    let ifs = interfaces();
+    if opt.passive {
+        return passive(&opt);
+    }
+    if opt.watch {
+        return watch(&opt);
+    }
+
+    let start = Instant::now();
+    let targets = targets(&opt, false, start)?;
+    scan_hosts(&targets, &opt, start);
+
+    Ok(())
+}
+
+/// Find the hosts to probe, sweeping the networks attached to interfaces
+/// first. Messages about the sweeps are left out if `quiet` is set. The
+/// deadline counts from `start`.
+fn targets(opt: &ScanOpt, quiet: bool, start: Instant) -> Result<Vec<Hosts>, TeleportError> {
+    let note = |line: String| {
+        if !quiet {
+            note(opt, line)
+        }
+    };
     let localv4 = IpNetwork::V4("127.0.0.1/8".parse().unwrap());
+    let timeout = Duration::from_millis(opt.timeout);
+    let deadline = opt.deadline.map(|d| start + Duration::from_secs(d));
 
-    for i in ifs {
-        if !i.ips.is_empty() {
-            if i.ips.contains(&localv4) {
+    let ifs: Vec<NetworkInterface> = interfaces()
+        .into_iter()
+        .filter(|i| !i.ips.is_empty() && !i.ips.contains(&localv4))
+        .collect();
+    for name in &opt.interface {
+        if !ifs.iter().any(|i| &i.name == name) {
+            return Err(TeleportError::InvalidTarget(format!("no interface {name}")));
+        }
+    }
+
+    // Walk the interfaces unless only explicit targets were given
+    let walk = !opt.interface.is_empty() || (opt.cidr.is_empty() && opt.hosts_file.is_none());
+    let selected: Vec<&NetworkInterface> = ifs
+        .iter()
+        .filter(|i| walk && (opt.interface.is_empty() || opt.interface.contains(&i.name)))
+        .collect();
+
+    let mut subnets = Vec::<Subnet>::new();
+    for i in &selected {
+        for v in &i.ips {
+            if let IpNetwork::V4(n) = v {
+                subnets.push(Subnet {
+                    net: *v,
+                    attached: Some(((*i).clone(), n.ip())),
+                });
+            }
+        }
+    }
+    let mut listed = Vec::new();
+    let mut nets = opt.cidr.clone();
+    if let Some(path) = &opt.hosts_file {
+        let text = fs::read_to_string(path)?;
+        let (n, hosts) = parse_hosts(&text, opt.port).map_err(TeleportError::InvalidTarget)?;
+        nets.extend(n);
+        listed = hosts;
+    }
+    for net in nets {
+        subnets.push(Subnet {
+            net,
+            attached: attached(&ifs, &net),
+        });
+    }
+
+    // Check the size of the scan before sending anything
+    let total = subnets
+        .iter()
+        .map(|s| Hosts::Network(s.net).count())
+        .fold(listed.len() as u64, u64::saturating_add);
+    if total > opt.max_hosts {
+        println!(
+            "Narrow the scan with --interface, --cidr or --hosts-file, or raise --max-hosts ({})",
+            opt.max_hosts
+        );
+        return Err(TeleportError::TooManyHosts(total));
+    }
+
+    let mut targets = Vec::<Hosts>::new();
+    for subnet in subnets {
+        let (iface, source, net) = match (&subnet.attached, subnet.net) {
+            (Some((i, source)), IpNetwork::V4(n)) if !opt.no_arp => (i, *source, n),
+            _ => {
+                targets.push(Hosts::Network(subnet.net));
                 continue;
             }
-            for v in &i.ips {
-                if v.is_ipv4() {
-                    scan_network(v, opt.port)?;
+        };
+
+        // Only probe the hosts that answer ARP, plus ourselves
+        let cidr = format!("{}/{}", net.network(), net.prefix());
+        match discover::arp_sweep(iface, source, net, timeout, deadline) {
+            Ok(found) => {
+                note(format!(
+                    "ARP sweep of {cidr} on {}: {} hosts up",
+                    iface.name,
+                    found.len()
+                ));
+                let mut live: Vec<SocketAddr> = found
+                    .into_keys()
+                    .map(|ip| SocketAddr::new(IpAddr::V4(ip), 0))
+                    .collect();
+                if net.contains(source) {
+                    live.push(SocketAddr::new(IpAddr::V4(source), 0));
                 }
+                targets.push(Hosts::Live(live));
+            }
+            Err(e) => {
+                note(format!(
+                    "Unable to ARP sweep {cidr} on {} ({e}), probing every address",
+                    iface.name
+                ));
+                targets.push(Hosts::Network(subnet.net));
+            }
+        }
+    }
+
+    // IPv6 networks are far too large to probe every address, so only the
+    // hosts answering on the link are probed
+    for i in selected {
+        if i.is_up() && i.is_multicast() && i.ips.iter().any(|ip| ip.is_ipv6()) {
+            match discover::icmpv6_sweep(i, timeout, deadline) {
+                Ok(found) => {
+                    note(format!(
+                        "ICMPv6 sweep on {}: {} hosts up",
+                        i.name,
+                        found.len()
+                    ));
+                    targets.push(Hosts::Live(found.into_iter().map(SocketAddr::V6).collect()));
+                }
+                Err(e) => note(format!("Unable to find IPv6 hosts on {} ({e})", i.name)),
+            }
+        }
+    }
+    if !listed.is_empty() {
+        targets.push(Hosts::Listed(listed));
+    }
+
+    Ok(targets)
+}
+
+/// Print a message about the scan itself, kept out of the way of JSON output
+fn note(opt: &ScanOpt, line: String) {
+    match opt.json {
+        true => eprintln!("{line}"),
+        false => println!("{line}"),
+    }
+}
+
+/// An IPv4 network to scan, along with the interface and address it is
+/// attached to, if any
+struct Subnet {
+    net: IpNetwork,
+    attached: Option<(NetworkInterface, Ipv4Addr)>,
+}
+
+/// The interface and address a network is part of, so that it can be swept
+/// with ARP
+fn attached(ifs: &[NetworkInterface], net: &IpNetwork) -> Option<(NetworkInterface, Ipv4Addr)> {
+    let net = match net {
+        IpNetwork::V4(n) => n,
+        IpNetwork::V6(_) => return None,
+    };
+    ifs.iter().find_map(|i| {
+        i.ips.iter().find_map(|ip| match ip {
+            IpNetwork::V4(n) if n.prefix() <= net.prefix() && n.contains(net.network()) => {
+                Some((i.clone(), n.ip()))
+            }
+            _ => None,
+        })
+    })
+}
+
+/// Parse a hosts file into networks and addresses. Each line holds an
+/// address with an optional port, a network in CIDR notation or a host name,
+/// and `#` starts a comment.
+fn parse_hosts(text: &str, port: u16) -> Result<(Vec<IpNetwork>, Vec<SocketAddr>), String> {
+    let mut nets = Vec::new();
+    let mut hosts = Vec::new();
+    for (num, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        if line.contains('/') {
+            match line.parse::<IpNetwork>() {
+                Ok(n) => nets.push(n),
+                Err(e) => return Err(format!("line {}: {line} ({e})", num + 1)),
+            }
+        } else if let Ok(addr) = line.parse::<SocketAddr>() {
+            hosts.push(addr);
+        } else if let Ok(ip) = line.parse::<IpAddr>() {
+            hosts.push(SocketAddr::new(ip, port));
+        } else {
+            let addrs = match line.contains(':') {
+                true => line.to_socket_addrs(),
+                false => (line, port).to_socket_addrs(),
+            };
+            match addrs.ok().and_then(|mut a| a.next()) {
+                Some(addr) => hosts.push(addr),
+                None => return Err(format!("line {}: cannot resolve {line}", num + 1)),
             }
         }
     }
 
+    Ok((nets, hosts))
+}
+
+/// Collect the beacons of listeners announcing themselves, until the
+/// deadline or for two beacon intervals
+fn passive(opt: &ScanOpt) -> Result<(), TeleportError> {
+    let duration = match opt.deadline {
+        Some(d) => Duration::from_secs(d),
+        None => beacon::BEACON_INTERVAL * 2,
+    };
+    note(
+        opt,
+        format!("Listening for Teleporter beacons for {duration:?}"),
+    );
+
+    let mut found = 0;
+    beacon::collect(duration, |heard| {
+        found += 1;
+        if opt.json {
+            let mut obj = JsonObject::event("listener");
+            obj.string("addr", &heard.addr.to_string())
+                .string("version", &heard.beacon.version.to_string())
+                .string("name", &heard.name())
+                .boolean("must_encrypt", heard.beacon.must_encrypt())
+                .string("fingerprint", &format!("{:016x}", heard.beacon.fingerprint));
+            println!("{obj}");
+            return false;
+        }
+
+        let mut line = format!(
+            "Teleporter v{} \"{}\" announced on {}",
+            heard.beacon.version,
+            heard.name(),
+            heard.addr
+        );
+        if heard.beacon.must_encrypt() {
+            line.push_str(" (encryption required)");
+        }
+        println!("{line} [{:016x}]", heard.beacon.fingerprint);
+        false
+    })?;
+
+    match opt.json {
+        true => println!("{}", JsonObject::event("summary").number("found", found)),
+        false => println!("Found {found} Teleporter instances"),
+    }
+
     Ok(())
 }
 
-fn scan_network(network: &IpNetwork, port: u16) -> Result<(), TeleportError> {
-    for i in network.iter().skip(1) {
-        let sa = format!("{}:{port}", i);
-        let socket = sa.to_socket_addrs().unwrap();
-        for s in socket {
-            if let Ok(ack) = ping(&s) {
-                println!("Teleporter v{} detected on {sa}", ack.version);
+/// Addresses to probe for Teleporter instances
+enum Hosts {
+    /// Every address of a network but the network address
+    Network(IpNetwork),
+    /// Hosts known to be up
+    Live(Vec<SocketAddr>),
+    /// Hosts given by the user, with the port to probe
+    Listed(Vec<SocketAddr>),
+}
+
+impl Hosts {
+    fn count(&self) -> u64 {
+        match self {
+            Hosts::Network(IpNetwork::V4(n)) => u64::from(n.size()).saturating_sub(self.skipped()),
+            Hosts::Network(IpNetwork::V6(n)) => u64::try_from(n.size())
+                .unwrap_or(u64::MAX)
+                .saturating_sub(self.skipped()),
+            Hosts::Live(hosts) | Hosts::Listed(hosts) => hosts.len() as u64,
+        }
+    }
+
+    /// Point-to-point networks and single hosts have no network address
+    fn skipped(&self) -> u64 {
+        match self {
+            Hosts::Network(n) if n.prefix() + 1 < max_prefix(n) => 1,
+            _ => 0,
+        }
+    }
+
+    /// Iterate the addresses with the port to probe, along with whether the
+    /// host is known to be up
+    fn addrs(&self, port: u16) -> Box<dyn Iterator<Item = (SocketAddr, bool)> + '_> {
+        match self {
+            Hosts::Network(n) => Box::new(
+                n.iter()
+                    .skip(self.skipped() as usize)
+                    .map(move |ip| (SocketAddr::new(ip, port), false)),
+            ),
+            Hosts::Live(hosts) => Box::new(hosts.iter().map(move |addr| {
+                let mut addr = *addr;
+                addr.set_port(port);
+                (addr, true)
+            })),
+            Hosts::Listed(hosts) => Box::new(hosts.iter().map(|addr| (*addr, false))),
+        }
+    }
+}
+
+fn max_prefix(net: &IpNetwork) -> u8 {
+    match net {
+        IpNetwork::V4(_) => 32,
+        IpNetwork::V6(_) => 128,
+    }
+}
+
+/// Ping the hosts, many at once, returning the instances that answered. The
+/// deadline counts from `start`. Each instance is printed as it answers,
+/// unless watching.
+fn scan_hosts(
+    targets: &[Hosts],
+    opt: &ScanOpt,
+    start: Instant,
+) -> BTreeMap<SocketAddr, TeleportInitAck> {
+    let deadline = opt.deadline.map(|d| start + Duration::from_secs(d));
+    let timeout = Duration::from_millis(opt.timeout);
+
+    let total = targets.iter().map(Hosts::count).sum();
+    let output = match (opt.watch, opt.json) {
+        (true, _) => Output::Silent,
+        (false, true) => Output::Json,
+        (false, false) => Output::Text,
+    };
+    let progress = Arc::new(Progress::new(total, output));
+
+    // Submitting waits for a free worker, so hosts are only generated as
+    // fast as they are probed
+    let pool = Pool::new(usize::from(opt.concurrency), 0);
+    let mut stopped = false;
+    for (addr, live) in targets.iter().flat_map(|t| t.addrs(opt.port)) {
+        if deadline.map_or(false, |d| Instant::now() >= d) {
+            stopped = true;
+            break;
+        }
+        if signals::shutdown_count() > 0 {
+            break;
+        }
+
+        let progress = Arc::clone(&progress);
+        pool.execute(move || {
+            // Never wait past the deadline
+            let timeout = match deadline {
+                Some(d) => timeout.min(d.saturating_duration_since(Instant::now())),
+                None => timeout,
+            };
+            let ack = match timeout.is_zero() {
+                true => None,
+                false => ping(&addr, timeout).ok(),
             };
+            progress.update(&addr, live, ack);
+        });
+    }
+    drop(pool);
+
+    progress.finish(start.elapsed(), stopped)
+}
+
+/// How the hosts scanned are reported
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Output {
+    /// Each instance as it answers, below a progress line
+    Text,
+    /// Each host as a JSON object, without a progress line
+    Json,
+    /// Nothing, the caller reports the instances found
+    Silent,
+}
+
+/// Counts of the hosts scanned so far, shown on a progress line below the
+/// instances found
+struct Progress {
+    total: u64,
+    output: Output,
+    state: Mutex<ProgressState>,
+}
+
+struct ProgressState {
+    scanned: u64,
+    found: BTreeMap<SocketAddr, TeleportInitAck>,
+    drawn: Option<Instant>,
+}
+
+impl Progress {
+    fn new(total: u64, output: Output) -> Self {
+        Progress {
+            total,
+            output,
+            state: Mutex::new(ProgressState {
+                scanned: 0,
+                found: BTreeMap::new(),
+                drawn: None,
+            }),
+        }
+    }
+
+    fn update(&self, addr: &SocketAddr, live: bool, ack: Option<TeleportInitAck>) {
+        let mut state = self.state.lock().expect("Fatal error locking progress");
+        state.scanned += 1;
+
+        match (self.output, &ack) {
+            (Output::Text, Some(ack)) => println!("\r{}", describe_line("detected on", addr, ack)),
+            (Output::Text, None) if live => {
+                println!("\rHost {} is up, Teleporter not detected", addr.ip())
+            }
+            (Output::Json, Some(ack)) => println!("{}", describe_json("listener", addr, ack)),
+            (Output::Json, None) if live => {
+                let mut obj = JsonObject::event("host");
+                obj.string("addr", &addr.ip().to_string());
+                println!("{obj}");
+            }
+            _ => (),
+        }
+        let answered = ack.is_some();
+        if let Some(ack) = ack {
+            state.found.insert(*addr, ack);
+        }
+
+        let recent = state
+            .drawn
+            .map_or(false, |d| d.elapsed() < PROGRESS_INTERVAL);
+        let news = answered || live || state.scanned >= self.total;
+        if self.output != Output::Text || (recent && !news) {
+            return;
+        }
+
+        print!(
+            "\rScanned {}/{} hosts, {} found",
+            state.scanned,
+            self.total,
+            state.found.len()
+        );
+        io::stdout().flush().expect("Fatal IO error");
+        state.drawn = Some(Instant::now());
+    }
+
+    /// Print the totals, returning the instances found
+    fn finish(&self, elapsed: Duration, stopped: bool) -> BTreeMap<SocketAddr, TeleportInitAck> {
+        let mut state = self.state.lock().expect("Fatal error locking progress");
+        match self.output {
+            Output::Text => {
+                print!(
+                    "\rScanned {}/{} hosts in {:.2?}, found {} Teleporter instances",
+                    state.scanned,
+                    self.total,
+                    elapsed,
+                    state.found.len()
+                );
+                if stopped {
+                    print!(" (deadline reached)");
+                }
+                println!();
+            }
+            Output::Json => {
+                let mut obj = JsonObject::event("summary");
+                obj.number("scanned", state.scanned)
+                    .number("total", self.total)
+                    .number("found", state.found.len() as u64)
+                    .number("elapsed_ms", elapsed.as_millis() as u64)
+                    .boolean("deadline_reached", stopped);
+                println!("{obj}");
+            }
+            Output::Silent => (),
+        }
+
+        std::mem::take(&mut state.found)
+    }
+}
+
+/// A listener found by `--watch`
+struct Seen {
+    ack: TeleportInitAck,
+    /// When the listener was first found and last answered, in seconds
+    /// since the epoch
+    since: u64,
+    last_seen: u64,
+    /// Scans in a row the listener did not answer
+    missed: u32,
+}
+
+/// Scan every `--interval` seconds until interrupted, printing the listeners
+/// that appear, disappear or change
+fn watch(opt: &ScanOpt) -> Result<(), TeleportError> {
+    signals::install()?;
+    note(
+        opt,
+        format!("Watching for Teleporter instances every {}s", opt.interval),
+    );
+
+    let mut seen = BTreeMap::<SocketAddr, Seen>::new();
+    let mut first = true;
+    while signals::shutdown_count() == 0 {
+        let start = Instant::now();
+        let found = scan_hosts(&targets(opt, !first, start)?, opt, start);
+        if signals::shutdown_count() > 0 {
+            break;
+        }
+        let now = utils::now();
+
+        for (addr, ack) in found.iter() {
+            match seen.get_mut(addr) {
+                Some(s) => {
+                    if changed(&s.ack, ack) {
+                        event(opt, "changed", addr, ack, now);
+                    }
+                    s.ack = ack.clone();
+                    s.last_seen = now;
+                    s.missed = 0;
+                }
+                None => {
+                    event(opt, "appeared", addr, ack, now);
+                    seen.insert(
+                        *addr,
+                        Seen {
+                            ack: ack.clone(),
+                            since: now,
+                            last_seen: now,
+                            missed: 0,
+                        },
+                    );
+                }
+            }
+        }
+
+        // A single lost probe does not make a listener disappear
+        seen.retain(|addr, s| {
+            if found.contains_key(addr) {
+                return true;
+            }
+            s.missed += 1;
+            if s.missed < MISSES_BEFORE_GONE {
+                return true;
+            }
+            event(opt, "disappeared", addr, &s.ack, now);
+            false
+        });
+
+        if let Some(path) = &opt.state_file {
+            write_state(path, &seen, now)?;
+        }
+        first = false;
+
+        let next = start + Duration::from_secs(opt.interval);
+        while Instant::now() < next && signals::shutdown_count() == 0 {
+            thread::sleep(WATCH_POLL_INTERVAL);
         }
     }
 
     Ok(())
 }
 
-fn ping(ip_addr: &SocketAddr) -> Result<TeleportInitAck, TeleportError> {
-    let stream = TcpStream::connect_timeout(ip_addr, Duration::new(0, 5000000))?;
+/// Whether a listener changed in a way worth reporting. The free space
+/// changes with every file received, so it is left out.
+fn changed(old: &TeleportInitAck, new: &TeleportInitAck) -> bool {
+    let strip = |ack: &TeleportInitAck| {
+        ack.capabilities.clone().map(|mut caps| {
+            caps.free_space = 0;
+            caps
+        })
+    };
+    old.version != new.version || strip(old) != strip(new)
+}
+
+/// Print a change seen by `--watch`
+fn event(opt: &ScanOpt, kind: &str, addr: &SocketAddr, ack: &TeleportInitAck, now: u64) {
+    if opt.json {
+        let mut obj = describe_json(kind, addr, ack);
+        obj.number("time", now);
+        println!("{obj}");
+        return;
+    }
+
+    let verb = match kind {
+        "disappeared" => "disappeared from".to_string(),
+        kind => format!("{kind} on"),
+    };
+    println!(
+        "{} {}",
+        utils::format_time(now),
+        describe_line(&verb, addr, ack)
+    );
+}
+
+/// Replace the state file with the listeners currently online, so that
+/// readers never see a partially written file
+fn write_state(path: &Path, seen: &BTreeMap<SocketAddr, Seen>, now: u64) -> io::Result<()> {
+    let listeners: Vec<JsonObject> = seen
+        .iter()
+        .map(|(addr, s)| {
+            let mut obj = JsonObject::new();
+            listener_json(&mut obj, addr, &s.ack);
+            obj.number("since", s.since)
+                .number("last_seen", s.last_seen);
+            obj
+        })
+        .collect();
+    let mut state = JsonObject::new();
+    state
+        .number("updated", now)
+        .objects("listeners", &listeners);
+
+    let name = path
+        .file_name()
+        .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+    let dir = match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    let loc = Location {
+        dir: Dir::open(dir)?,
+        name: name.to_os_string(),
+        path: path.to_path_buf(),
+        real: path.to_path_buf(),
+    };
+    let mut tmp = AtomicFile::create(&loc)?;
+    tmp.file.write_all(format!("{state}\n").as_bytes())?;
+    tmp.file
+        .set_permissions(fs::Permissions::from_mode(0o644))?;
+    tmp.persist()
+}
+
+/// Describe a listener on one line, e.g. `Teleporter v0.10.8 "lab1" detected
+/// on 192.168.1.20:9001 (encryption required)`
+fn describe_line(verb: &str, addr: &SocketAddr, ack: &TeleportInitAck) -> String {
+    match &ack.capabilities {
+        Some(caps) => format!(
+            "Teleporter v{} \"{}\" {verb} {addr}{}",
+            ack.version,
+            String::from_utf8_lossy(&caps.name),
+            describe(caps)
+        ),
+        None => format!("Teleporter v{} {verb} {addr}", ack.version),
+    }
+}
+
+/// Summarize what a listener offers, e.g. ` (encryption required, 1.500G
+/// free, shares: photos, logs)`
+fn describe(caps: &TeleportCapabilities) -> String {
+    let mut notes = Vec::new();
+    if caps.must_encrypt() {
+        notes.push("encryption required".to_string());
+    }
+    if !caps.overwrite() {
+        notes.push("no overwrite".to_string());
+    }
+    if caps.free_space != u64::MAX {
+        notes.push(format!("{} free", utils::format_size(caps.free_space)));
+    }
+    if !caps.shares.is_empty() {
+        let shares: Vec<String> = caps
+            .shares
+            .iter()
+            .map(|s| String::from_utf8_lossy(s).to_string())
+            .collect();
+        notes.push(format!("shares: {}", shares.join(", ")));
+    }
+
+    match notes.is_empty() {
+        true => String::new(),
+        false => format!(" ({})", notes.join(", ")),
+    }
+}
+
+/// An event about a listener, as a JSON object
+fn describe_json(event: &str, addr: &SocketAddr, ack: &TeleportInitAck) -> JsonObject {
+    let mut obj = JsonObject::event(event);
+    listener_json(&mut obj, addr, ack);
+    obj
+}
+
+/// Add the fields describing a listener to a JSON object
+fn listener_json(obj: &mut JsonObject, addr: &SocketAddr, ack: &TeleportInitAck) {
+    obj.string("addr", &addr.to_string())
+        .string("version", &ack.version.to_string());
+    if let Some(caps) = &ack.capabilities {
+        let shares: Vec<String> = caps
+            .shares
+            .iter()
+            .map(|s| String::from_utf8_lossy(s).to_string())
+            .collect();
+        obj.string("name", &String::from_utf8_lossy(&caps.name))
+            .boolean("must_encrypt", caps.must_encrypt())
+            .boolean("overwrite", caps.overwrite())
+            .number("features", u64::from(caps.features))
+            .strings("shares", &shares);
+        if caps.free_space != u64::MAX {
+            obj.number("free_space", caps.free_space);
+        }
+    }
+}
+
+/// A JSON object, built one field at a time
+struct JsonObject {
+    fields: Vec<String>,
+}
+
+impl JsonObject {
+    fn new() -> Self {
+        JsonObject { fields: Vec::new() }
+    }
+
+    /// An object describing an event of the scan
+    fn event(event: &str) -> Self {
+        let mut obj = JsonObject::new();
+        obj.string("event", event);
+        obj
+    }
+
+    fn field(&mut self, key: &str, value: String) -> &mut Self {
+        self.fields.push(format!("{}:{value}", json_string(key)));
+        self
+    }
+
+    fn string(&mut self, key: &str, value: &str) -> &mut Self {
+        self.field(key, json_string(value))
+    }
+
+    fn number(&mut self, key: &str, value: u64) -> &mut Self {
+        self.field(key, value.to_string())
+    }
+
+    fn boolean(&mut self, key: &str, value: bool) -> &mut Self {
+        self.field(key, value.to_string())
+    }
+
+    fn strings(&mut self, key: &str, values: &[String]) -> &mut Self {
+        let list: Vec<String> = values.iter().map(|v| json_string(v)).collect();
+        self.field(key, format!("[{}]", list.join(",")))
+    }
+
+    fn objects(&mut self, key: &str, values: &[JsonObject]) -> &mut Self {
+        let list: Vec<String> = values.iter().map(|v| v.to_string()).collect();
+        self.field(key, format!("[{}]", list.join(",")))
+    }
+}
+
+impl fmt::Display for JsonObject {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{{{}}}", self.fields.join(","))
+    }
+}
+
+/// Quote and escape a string for JSON
+fn json_string(value: &str) -> String {
+    let mut out = String::from('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+pub(crate) fn ping(
+    ip_addr: &SocketAddr,
+    timeout: Duration,
+) -> Result<TeleportInitAck, TeleportError> {
+    let stream = TcpStream::connect_timeout(ip_addr, timeout)?;
+    // Something else may listen on the port and never answer
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
     query(stream)
 }
 
@@ -70,3 +834,82 @@ fn query(mut stream: TcpStream) -> Result<TeleportInitAck, TeleportError> {
         Ok(ack)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::teleport::TeleportCapabilityFlags;
+
+    #[test]
+    fn test_parse_hosts() {
+        let text = "# lab machines\n10.0.0.5\n10.0.0.6:9100  # alternate port\n\n[fd00::7]:9001\n10.1.0.0/30\n";
+        let (nets, hosts) = parse_hosts(text, 9001).expect("Test should never fail");
+        assert_eq!(
+            nets,
+            vec!["10.1.0.0/30"
+                .parse::<IpNetwork>()
+                .expect("Test should never fail")]
+        );
+        let hosts: Vec<String> = hosts.iter().map(|h| h.to_string()).collect();
+        assert_eq!(
+            hosts,
+            vec!["10.0.0.5:9001", "10.0.0.6:9100", "[fd00::7]:9001"]
+        );
+
+        assert!(parse_hosts("10.0.0.0/33", 9001).is_err());
+    }
+
+    #[test]
+    fn test_hosts_count() {
+        let net = |n: &str| Hosts::Network(n.parse().expect("Test should never fail"));
+        assert_eq!(net("192.168.1.0/24").count(), 255);
+        assert_eq!(net("192.168.1.7/32").count(), 1);
+        assert_eq!(net("192.168.1.6/31").count(), 2);
+        assert_eq!(net("fd00::/120").count(), 255);
+
+        let addrs: Vec<SocketAddr> = net("192.168.1.7/32").addrs(9001).map(|(a, _)| a).collect();
+        assert_eq!(
+            addrs,
+            vec!["192.168.1.7:9001".parse().expect("Test should never fail")]
+        );
+    }
+
+    #[test]
+    fn test_watch_changed() {
+        let mut old = TeleportInitAck::new(TeleportStatus::Pong);
+        old.capabilities = Some(TeleportCapabilities {
+            free_space: 1 << 30,
+            name: b"lab1".to_vec(),
+            ..Default::default()
+        });
+
+        // Receiving files is not a change
+        let mut new = old.clone();
+        if let Some(caps) = &mut new.capabilities {
+            caps.free_space -= 4096;
+        }
+        assert!(!changed(&old, &new));
+
+        if let Some(caps) = &mut new.capabilities {
+            caps.flags = TeleportCapabilityFlags::MustEncrypt as u8;
+        }
+        assert!(changed(&old, &new));
+
+        new = old.clone();
+        new.version.patch += 1;
+        assert!(changed(&old, &new));
+    }
+
+    #[test]
+    fn test_json_object() {
+        let mut obj = JsonObject::event("listener");
+        obj.string("name", "lab \"1\"\n")
+            .number("free_space", 42)
+            .boolean("overwrite", false)
+            .strings("shares", &["photos".to_string(), "a\\b".to_string()]);
+        assert_eq!(
+            obj.to_string(),
+            r#"{"event":"listener","name":"lab \"1\"\n","free_space":42,"overwrite":false,"shares":["photos","a\\b"]}"#
+        );
+    }
+}
@@ -1,16 +1,25 @@
 use crate::errors::TeleportError;
+use crate::sparse::Extent;
+use crate::teleport::{TeleportAbort, ABORT_REVISION, CONFIRM_REVISION};
 use crate::teleport::{TeleportAction, TeleportFeatures, TeleportStatus};
 use crate::teleport::{TeleportData, TeleportDelta, TeleportEnc, TeleportInit, TeleportInitAck};
-use crate::SendOpt;
+use crate::teleport::{TeleportHole, TeleportLink};
+use crate::teleport::{MIN_PROTOCOL_REVISION, PROTOCOL_REVISION};
 use crate::VERSION;
-use crate::{crypto, utils};
-use std::fs::File;
+use crate::{beacon, crypto, metadata, scan, sparse, utils};
+use crate::{LinkPolicy, SendOpt};
+use std::collections::HashMap;
+use std::fs;
+use std::fs::{File, Metadata};
 use std::io::{Read, Seek, SeekFrom};
 use std::net::{TcpStream, ToSocketAddrs};
-use std::os::unix::fs::PermissionsExt;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
 use std::path::{Path, PathBuf};
 use std::thread;
-use std::time::Instant;
+use std::time::{Duration, Instant};
+
+/// How long to wait for the listener to describe itself
+const PING_TIMEOUT: Duration = Duration::from_secs(2);
 
 #[derive(Debug)]
 struct Replace {
@@ -18,14 +27,56 @@ struct Replace {
     new: Vec<String>,
 }
 
-fn get_file_list(opt: &SendOpt) -> Vec<String> {
-    let mut files = Vec::<String>::new();
+/// An item located for sending
+#[derive(Debug)]
+enum Entry {
+    File(String),
+    /// An empty directory
+    Dir(String),
+    /// A symlink and its target
+    Symlink(String, String),
+    /// A hard link and the first path sent for the same inode
+    Hardlink(String, String),
+}
+
+/// State kept while walking the input list
+#[derive(Default)]
+struct Scope {
+    /// Directories on the current recursion path, used to detect symlink loops
+    ancestors: Vec<(u64, u64)>,
+    /// First path located for each inode with multiple hard links
+    links: HashMap<(u64, u64), String>,
+}
+
+impl Scope {
+    fn file(&mut self, path: &Path, meta: &Metadata) -> Entry {
+        let path = path
+            .to_str()
+            .expect("Fatal error converting item to str")
+            .to_string();
+
+        // Only send the data once for each group of hard links
+        if meta.nlink() > 1 {
+            let id = (meta.dev(), meta.ino());
+            if let Some(first) = self.links.get(&id) {
+                return Entry::Hardlink(path, first.clone());
+            }
+            self.links.insert(id, path.clone());
+        }
+
+        Entry::File(path)
+    }
+}
+
+fn get_file_list(opt: &SendOpt) -> Vec<Entry> {
+    let mut files = Vec::<Entry>::new();
+    let mut scope = Scope::default();
 
     // Iterate over each item in list
     for item in opt.input.iter() {
         if opt.recursive && item.is_dir() {
             // Recurse into directories
-            let mut tmp = match scope_dir(item) {
+            let mut tmp = match scope_dir(item, item, opt, &mut scope) {
                 Ok(t) => t,
                 Err(_) => {
                     println!("Error: Cannot read item: {item:?}");
@@ -36,48 +87,122 @@ fn get_file_list(opt: &SendOpt) -> Vec<String> {
             files.append(&mut tmp);
         } else if item.exists() && item.is_file() {
             // Append the file
-            files.push(
-                item.to_str()
-                    .expect("Fatal error converting item to str")
-                    .to_string(),
-            );
+            match item.metadata() {
+                Ok(meta) => files.push(scope.file(item, &meta)),
+                Err(_) => println!("Error: Cannot read item: {item:?}"),
+            }
         }
     }
 
     files
 }
 
-fn scope_dir(dir: &Path) -> Result<Vec<String>, TeleportError> {
-    let path = Path::new(&dir);
-    let mut files = Vec::<String>::new();
+fn scope_dir(
+    dir: &Path,
+    root: &Path,
+    opt: &SendOpt,
+    scope: &mut Scope,
+) -> Result<Vec<Entry>, TeleportError> {
+    let mut files = Vec::<Entry>::new();
+
+    // Refuse to enter a directory that is already being walked
+    let meta = dir.metadata()?;
+    let id = (meta.dev(), meta.ino());
+    if scope.ancestors.contains(&id) {
+        println!("Warning: Symlink loop detected, skipping: {dir:?}");
+        return Ok(files);
+    }
+    scope.ancestors.push(id);
 
     // Iterate over each item in directory
-    for entry in path.read_dir()? {
-        if entry.as_ref().unwrap().file_type().unwrap().is_dir() {
-            // Skip current directory
-            if entry.as_ref().unwrap().path() == *dir {
-                continue;
-            }
-
+    for entry in dir.read_dir()? {
+        let entry = entry?;
+        let path = entry.path();
+        let ftype = entry.file_type()?;
+        if ftype.is_dir() {
             // Recurse into subdirectories
-            let mut tmp = match scope_dir(&entry.as_ref().unwrap().path()) {
+            let mut tmp = match scope_dir(&path, root, opt, scope) {
                 Ok(t) => t,
                 Err(_) => {
-                    println!("Error: Cannot read dir: {entry:?}");
+                    println!("Error: Cannot read dir: {path:?}");
                     continue;
                 }
             };
             // Append any files located
             files.append(&mut tmp);
-        } else if entry.as_ref().unwrap().file_type().unwrap().is_file() {
+        } else if ftype.is_file() {
             // Append the file
-            files.push(entry.unwrap().path().to_str().unwrap().to_string());
+            files.push(scope.file(&path, &entry.metadata()?));
+        } else if ftype.is_symlink() {
+            // Append the link, or what it points to
+            files.append(&mut scope_link(&path, root, opt, scope)?);
         }
     }
 
+    scope.ancestors.pop();
+
+    // Recreate directories that contain nothing to send
+    if files.is_empty() {
+        files.push(Entry::Dir(
+            dir.to_str()
+                .expect("Fatal error converting item to str")
+                .to_string(),
+        ));
+    }
+
     Ok(files)
 }
 
+fn scope_link(
+    link: &Path,
+    root: &Path,
+    opt: &SendOpt,
+    scope: &mut Scope,
+) -> Result<Vec<Entry>, TeleportError> {
+    let target = fs::read_link(link)?;
+
+    // Relative links that stay inside the sent tree are always recreated
+    if opt.unsafe_links == LinkPolicy::Keep || link_in_tree(root, link, &target) {
+        return Ok(vec![Entry::Symlink(
+            link.to_str()
+                .expect("Fatal error converting item to str")
+                .to_string(),
+            target
+                .to_str()
+                .expect("Fatal error converting item to str")
+                .to_string(),
+        )]);
+    }
+
+    if opt.unsafe_links == LinkPolicy::Skip {
+        println!("Skipping symlink that points outside of {root:?}: {link:?} -> {target:?}");
+        return Ok(Vec::new());
+    }
+
+    // Follow the link and send whatever it points to
+    match link.metadata() {
+        Ok(meta) if meta.is_dir() => scope_dir(link, root, opt, scope),
+        Ok(meta) if meta.is_file() => Ok(vec![scope.file(link, &meta)]),
+        Ok(_) => Ok(Vec::new()),
+        Err(e) if e.raw_os_error() == Some(libc::ELOOP) => {
+            println!("Warning: Symlink loop detected, skipping: {link:?}");
+            Ok(Vec::new())
+        }
+        Err(_) => {
+            println!("Warning: Skipping dangling symlink: {link:?} -> {target:?}");
+            Ok(Vec::new())
+        }
+    }
+}
+
+/// Check whether a symlink target resolves to a location inside of `root`
+fn link_in_tree(root: &Path, link: &Path, target: &Path) -> bool {
+    match link.parent().and_then(|p| p.strip_prefix(root).ok()) {
+        Some(parent) => utils::stays_within(parent, target),
+        None => false,
+    }
+}
+
 fn find_replacements(opt: &mut SendOpt) -> Replace {
     let mut rep = Replace {
         orig: Vec::<String>::new(),
@@ -115,9 +240,7 @@ fn find_replacements(opt: &mut SendOpt) -> Replace {
     }
 
     // For every replacement being made
-    while !poppers.is_empty() {
-        // Get the index of the string to be replaced
-        let idx = poppers.pop().unwrap();
+    while let Some(idx) = poppers.pop() {
         // Remove the string from the input list
         opt.input.remove(idx);
         // Insert the original file name to be used
@@ -144,8 +267,289 @@ fn connect_to_client(
     Err(TeleportError::InvalidDest)
 }
 
+/// How to continue after the server responds to a request
+pub(crate) enum Next {
+    Proceed,
+    Skip,
+    Stop,
+}
+
+/// Number of times a request is retried while the server is busy
+const BUSY_RETRIES: u64 = 10;
+
+/// Send a request to the server and receive its response, waiting and
+/// retrying while the server is busy. Sets `legacy` once the server turns out
+/// to only speak the legacy wire format.
+pub(crate) fn request(
+    opt: &SendOpt,
+    action: TeleportAction,
+    mut data: Vec<u8>,
+    legacy: &mut bool,
+) -> Result<(TcpStream, Option<TeleportEnc>, TeleportInitAck), TeleportError> {
+    let mut attempt = 0;
+    loop {
+        let (mut stream, enc) = connect(opt)?;
+        utils::send_packet(&mut stream, action, &enc, data.clone())?;
+
+        // Receive response from server
+        let packet = utils::recv_packet(&mut stream, &enc)?;
+        let mut recv = TeleportInitAck::new(TeleportStatus::Proceed);
+        recv.deserialize(&packet.data)?;
+
+        // The final response follows once the server operator decided
+        if recv.status == TeleportStatus::Pending as u8 {
+            println!("Waiting for approval from the server...");
+            let packet = utils::recv_packet(&mut stream, &enc)?;
+            recv = TeleportInitAck::new(TeleportStatus::Proceed);
+            recv.deserialize(&packet.data)?;
+        }
+
+        if recv.status == TeleportStatus::Busy as u8 && attempt < BUSY_RETRIES {
+            attempt += 1;
+            thread::sleep(Duration::from_millis(100 * attempt));
+            continue;
+        }
+
+        // Listeners that predate revision negotiation only accept their own
+        // release, so retry files once in the legacy wire format
+        if recv.status == TeleportStatus::WrongVersion as u8 && recv.revision.is_none() && !*legacy
+        {
+            *legacy = true;
+            if action == TeleportAction::Init {
+                let mut init = TeleportInit::default();
+                init.deserialize(&data)?;
+                if init.downgrade() {
+                    data = init.serialize()?;
+                    continue;
+                }
+            }
+        }
+
+        return Ok((stream, enc, recv));
+    }
+}
+
+/// Connect to the server and, if enabled, negotiate encryption
+fn connect(opt: &SendOpt) -> Result<(TcpStream, Option<TeleportEnc>), TeleportError> {
+    let mut enc: Option<TeleportEnc> = None;
+
+    // Connect to server
+    let addr = match format!("{}:{}", opt.dest, opt.port).to_socket_addrs() {
+        Ok(a) => a,
+        Err(_) => {
+            return Err(TeleportError::InvalidDest);
+        }
+    };
+    let mut stream = connect_to_client(addr)?;
+
+    // If encrypt is enabled
+    if opt.encrypt {
+        // Generate EC keypair
+        let mut ctx = TeleportEnc::new();
+        let privkey = crypto::genkey(&mut ctx);
+        // Send pubkey
+        utils::send_packet(&mut stream, TeleportAction::Ecdh, &None, ctx.serialize())?;
+        // Receive remote pubkey and generate session secret
+        let packet = utils::recv_packet(&mut stream, &None)?;
+        if packet.action == TeleportAction::EcdhAck as u8 {
+            ctx.deserialize(&packet.data)?;
+            ctx.calc_secret(privkey);
+            enc = Some(ctx);
+        }
+    }
+
+    Ok((stream, enc))
+}
+
+/// Validate the response from the server
+pub(crate) fn check_status(
+    recv: &TeleportInitAck,
+    filename: &str,
+    opt: &SendOpt,
+) -> Result<Next, TeleportError> {
+    match recv.status.try_into()? {
+        TeleportStatus::NoOverwrite => {
+            println!("The server refused to overwrite the file: {filename}");
+            Ok(Next::Skip)
+        }
+        TeleportStatus::NoPermission => {
+            println!("The server does not have permission to write to this file: {filename}");
+            Ok(Next::Skip)
+        }
+        TeleportStatus::NoSpace => {
+            println!("The server has no space available to write the file: {filename}");
+            Ok(Next::Skip)
+        }
+        TeleportStatus::BadFileName => {
+            println!("The server refused the file name: {filename}");
+            Ok(Next::Skip)
+        }
+        TeleportStatus::WrongVersion => {
+            match recv.revision {
+                Some(r) => println!(
+                    "Version mismatch! Server: {} (revision {r}) Us: {} (revisions {}-{})",
+                    recv.version, VERSION, MIN_PROTOCOL_REVISION, PROTOCOL_REVISION
+                ),
+                None => println!("Version mismatch! Server: {} Us: {}", recv.version, VERSION),
+            }
+            Ok(Next::Stop)
+        }
+        TeleportStatus::RequiresEncryption => {
+            println!("The server requires encryption");
+            Ok(Next::Stop)
+        }
+        TeleportStatus::EncryptionError => {
+            println!("Error initializing encryption handshake");
+            Ok(Next::Stop)
+        }
+        TeleportStatus::AccessDenied => {
+            println!("The server denied access to this client");
+            Ok(Next::Stop)
+        }
+        TeleportStatus::PathNotAllowed => {
+            println!("The server does not allow writing to: {filename}");
+            Ok(Next::Skip)
+        }
+        TeleportStatus::TooLarge => {
+            println!("The file is larger than the server allows: {filename}");
+            Ok(Next::Skip)
+        }
+        TeleportStatus::FeatureDenied => {
+            println!("The server does not allow the requested backup or rename options");
+            Ok(Next::Stop)
+        }
+        TeleportStatus::Busy => {
+            println!("The server is busy, try again later");
+            Ok(Next::Stop)
+        }
+        TeleportStatus::QuotaExceeded => {
+            println!("The server quota for this client has been reached");
+            Ok(Next::Stop)
+        }
+        TeleportStatus::NoBackup => {
+            println!("The server has no such backup of: {filename}");
+            Ok(Next::Stop)
+        }
+        TeleportStatus::Rejected => {
+            println!("The server operator rejected the file: {filename}");
+            Ok(Next::Skip)
+        }
+        TeleportStatus::Pending => {
+            println!("The server did not decide on the file: {filename}");
+            Ok(Next::Stop)
+        }
+        TeleportStatus::UnknownShare => {
+            println!(
+                "The server has no share named: {}",
+                opt.share.as_deref().unwrap_or_default()
+            );
+            Ok(Next::Stop)
+        }
+        TeleportStatus::Proceed
+            if opt.share.is_some() && !TeleportFeatures::Share.check(&recv.features) =>
+        {
+            println!("The server does not support shares");
+            Ok(Next::Stop)
+        }
+        _ => Ok(Next::Proceed),
+    }
+}
+
+/// Build the name the server will store a local path as
+fn remote_name(path: &str, rep: &Replace, keep_path: bool) -> String {
+    let mut filename = path.to_string();
+
+    // Locate and replace the filename of the transfer file, if renamed
+    for (idx, item) in rep.orig.iter().enumerate() {
+        if item.contains(path) {
+            filename = rep.new[idx].clone();
+        }
+    }
+
+    // Remove all path info if !keep_path
+    if !keep_path {
+        filename = Path::new(&filename)
+            .file_name()
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string();
+    }
+
+    filename
+}
+
+/// What a ping revealed about the listener
+enum Probe {
+    /// The listener negotiates the wire format revision
+    Current,
+    /// The listener only speaks the legacy wire format
+    Legacy,
+}
+
+/// Ask the listener what it offers, enabling encryption if it is required
+/// and warning about requested features it does not allow. Fails if the
+/// requested share does not exist. Listeners that do not answer are sent to
+/// as usual.
+fn check_listener(opt: &mut SendOpt) -> Result<Probe, TeleportError> {
+    let pong = match format!("{}:{}", opt.dest, opt.port).to_socket_addrs() {
+        Ok(mut addrs) => addrs
+            .next()
+            .and_then(|addr| scan::ping(&addr, PING_TIMEOUT).ok()),
+        Err(_) => None,
+    };
+    let caps = match pong {
+        Some(pong) if pong.version.is_legacy() => {
+            if let Some(share) = &opt.share {
+                println!("Server {} predates shares", pong.version);
+                return Err(TeleportError::UnknownShare(share.clone()));
+            }
+            println!(
+                "Server {} speaks the legacy wire format, directories, links and metadata are not sent",
+                pong.version
+            );
+            return Ok(Probe::Legacy);
+        }
+        Some(TeleportInitAck {
+            capabilities: Some(c),
+            ..
+        }) => c,
+        _ => return Ok(Probe::Current),
+    };
+    let name = String::from_utf8_lossy(&caps.name).to_string();
+
+    if let Some(share) = &opt.share {
+        if !caps.shares.iter().any(|s| s == share.as_bytes()) {
+            return Err(TeleportError::UnknownShare(share.clone()));
+        }
+    }
+    if caps.must_encrypt() && !opt.encrypt {
+        println!("Enabling encryption as required by {name}");
+        opt.encrypt = true;
+    }
+    if opt.overwrite && !caps.overwrite() {
+        println!("Warning: {name} does not allow overwriting files");
+    }
+    if (opt.backup && !TeleportFeatures::Backup.check_u32(caps.features))
+        || (opt.filename_append && !TeleportFeatures::Rename.check_u32(caps.features))
+    {
+        println!("Warning: {name} does not allow backup or rename");
+    }
+
+    Ok(Probe::Current)
+}
+
 /// Client function sends filename and file data for each filepath
 pub fn run(mut opt: SendOpt) -> Result<(), TeleportError> {
+    // Find a listener by the name it announces
+    beacon::resolve_dest(&mut opt)?;
+
+    // Match the options to what the listener offers
+    let mut legacy = match check_listener(&mut opt)? {
+        Probe::Current => false,
+        Probe::Legacy => true,
+    };
+
     print!("Teleporter Client {VERSION} => ");
     let start_time = Instant::now();
     let mut sent = 0;
@@ -165,19 +569,22 @@ pub fn run(mut opt: SendOpt) -> Result<(), TeleportError> {
 
     // For each filepath in the input vector...
     for (num, item) in files.iter().enumerate() {
-        let file_time = Instant::now();
-
-        let mut enc: Option<TeleportEnc> = None;
+        let filepath = match item {
+            Entry::File(path) => path,
+            _ if legacy => continue,
+            entry => {
+                match send_entry(&opt, entry, &rep, num, files.len(), &mut legacy)? {
+                    Next::Proceed => sent += 1,
+                    Next::Skip => (),
+                    Next::Stop => break,
+                }
+                continue;
+            }
+        };
 
-        let filepath = item;
-        let mut filename = filepath.clone().to_string();
+        let file_time = Instant::now();
 
-        // Locate and replace the filename of the transfer file, if renamed
-        for (idx, item) in rep.orig.iter().enumerate() {
-            if item.contains(&filepath.to_string()) {
-                filename = rep.new[idx].clone();
-            }
-        }
+        let filename = remote_name(filepath, &rep, opt.keep_path);
 
         // Validate file
         let file = match File::open(filepath) {
@@ -197,16 +604,6 @@ pub fn run(mut opt: SendOpt) -> Result<(), TeleportError> {
             false => None,
         };
 
-        // Remove all path info if !opt.keep_path
-        if !opt.keep_path {
-            filename = Path::new(&filename)
-                .file_name()
-                .unwrap()
-                .to_str()
-                .unwrap()
-                .to_string();
-        }
-
         // Populate features
         let meta = file.metadata()?;
         let mut header = TeleportInit::new(TeleportFeatures::NewFile);
@@ -217,6 +614,9 @@ pub fn run(mut opt: SendOpt) -> Result<(), TeleportError> {
             TeleportFeatures::Delta.add_u32(&mut features);
         }
 
+        // Add sparse flag by default
+        TeleportFeatures::Sparse.add_u32(&mut features);
+
         // Add overwrite flag if enabled
         if opt.overwrite {
             TeleportFeatures::Overwrite.add_u32(&mut features);
@@ -231,83 +631,51 @@ pub fn run(mut opt: SendOpt) -> Result<(), TeleportError> {
         if opt.filename_append {
             TeleportFeatures::Rename.add_u32(&mut features);
         }
+
+        // Ask where the server stores the file
+        TeleportFeatures::Path.add_u32(&mut features);
+
+        // Send to a named share on the server
+        if let Some(share) = &opt.share {
+            TeleportFeatures::Share.add_u32(&mut features);
+            header.share = Some(share.as_bytes().to_vec());
+        }
+
+        // Add requested file metadata
+        if !opt.preserve.is_empty() {
+            let meta = metadata::gather(&file, &opt.preserve)?;
+            if !meta.is_empty() {
+                TeleportFeatures::Metadata.add_u32(&mut features);
+                header.metadata = Some(meta);
+            }
+        }
         header.features = features;
+        header.advertise_revision();
+        if legacy {
+            header.downgrade();
+        }
         header.chmod = meta.permissions().mode();
         header.filesize = meta.len();
         header.filename = filename.as_bytes().to_vec();
 
-        // Connect to server
-        let addr = match format!("{}:{}", opt.dest, opt.port).to_socket_addrs() {
-            Ok(a) => a,
-            Err(_) => {
-                return Err(TeleportError::InvalidDest);
-            }
-        };
-        let mut stream = connect_to_client(addr)?;
-
-        // If encrypt is enabled
-        if opt.encrypt {
-            // Generate EC keypair
-            let mut ctx = TeleportEnc::new();
-            let privkey = crypto::genkey(&mut ctx);
-            // Send pubkey
-            utils::send_packet(&mut stream, TeleportAction::Ecdh, &None, ctx.serialize())?;
-            // Receive remote pubkey and generate session secret
-            let packet = utils::recv_packet(&mut stream, &None)?;
-            if packet.action == TeleportAction::EcdhAck as u8 {
-                ctx.deserialize(&packet.data)?;
-                ctx.calc_secret(privkey);
-                enc = Some(ctx);
-            }
-        }
-
-        // Send header first
-        utils::send_packet(&mut stream, TeleportAction::Init, &enc, header.serialize()?)?;
-
-        // Receive response from server
-        let packet = utils::recv_packet(&mut stream, &enc)?;
-        let mut recv = TeleportInitAck::new(TeleportStatus::Proceed);
-        recv.deserialize(&packet.data)?;
+        // Send header first and receive the response from the server
+        let (mut stream, enc, recv) =
+            request(&opt, TeleportAction::Init, header.serialize()?, &mut legacy)?;
 
         if num == 0 {
             println!("Server {}", recv.version);
         }
 
         // Validate response
-        match recv.status.try_into()? {
-            TeleportStatus::NoOverwrite => {
-                println!("The server refused to overwrite the file: {}", &filename);
-                continue;
-            }
-            TeleportStatus::NoPermission => {
-                println!(
-                    "The server does not have permission to write to this file: {}",
-                    &filename
-                );
-                continue;
-            }
-            TeleportStatus::NoSpace => {
-                println!(
-                    "The server has no space available to write the file: {}",
-                    &filename
-                );
-                continue;
-            }
-            TeleportStatus::WrongVersion => {
-                println!("Version mismatch! Server: {} Us: {}", recv.version, VERSION);
-                break;
-            }
-            TeleportStatus::RequiresEncryption => {
-                println!("The server requires encryption");
-                break;
-            }
-            TeleportStatus::EncryptionError => {
-                println!("Error initializing encryption handshake");
-                break;
-            }
-            _ => (),
+        match check_status(&recv, &filename, &opt)? {
+            Next::Proceed => (),
+            Next::Skip => continue,
+            Next::Stop => break,
         };
 
+        // Servers that predate the confirmation close the connection instead
+        let confirm = recv.revision.map_or(false, |r| r >= CONFIRM_REVISION);
+
         // If TeleportDelta was received, else None
         let csum_recv = recv.delta.as_ref().map(|r| r.hash);
         let mut file_delta: Option<TeleportDelta> = None;
@@ -315,20 +683,34 @@ pub fn run(mut opt: SendOpt) -> Result<(), TeleportError> {
             file_delta = handle.map(|s| s.join().expect("calc_file_hash panicked"));
         }
 
-        println!("Sending file {}/{}: {}", num + 1, files.len(), &filename);
+        println!(
+            "Sending file {}/{}: {}",
+            num + 1,
+            files.len(),
+            stored_as(&recv, &filename)
+        );
 
         if csum_recv.is_some()
             && file_delta.is_some()
             && file_delta.as_ref().unwrap().hash == csum_recv.unwrap()
         {
             // File matches hash
-            send_data_complete(stream, &enc, header.filesize)?;
+            utils::send_data_complete(&mut stream, &enc, header.filesize)?;
             skip += 1;
         } else {
+            // Only skip holes if the server can recreate them
+            let extents = match TeleportFeatures::Sparse.check(&recv.features) {
+                true => Some(sparse::data_extents(&file)?),
+                false => None,
+            };
+
             // Send file data
-            send(stream, file, &header, &enc, recv.delta, file_delta)?;
+            send(&mut stream, file, &header, &enc, recv, file_delta, extents)?;
             sent += 1;
         }
+        if confirm {
+            check_stored(&mut stream, &enc)?;
+        }
 
         // Print file transfer statistics
         let duration = file_time.elapsed();
@@ -346,34 +728,147 @@ pub fn run(mut opt: SendOpt) -> Result<(), TeleportError> {
     Ok(())
 }
 
-fn send_data_complete(
-    mut stream: TcpStream,
-    enc: &Option<TeleportEnc>,
-    filesize: u64,
-) -> Result<(), TeleportError> {
-    let mut chunk = TeleportData {
-        offset: filesize,
-        data_len: 0,
-        data: Vec::<u8>::new(),
+/// Send a directory or link, which have no file data. Skipped if the server
+/// turns out to only speak the legacy wire format.
+fn send_entry(
+    opt: &SendOpt,
+    entry: &Entry,
+    rep: &Replace,
+    num: usize,
+    total: usize,
+    legacy: &mut bool,
+) -> Result<Next, TeleportError> {
+    let mut init = TeleportInit::new(TeleportFeatures::NewFile);
+    TeleportFeatures::Path.add_u32(&mut init.features);
+    if opt.overwrite {
+        TeleportFeatures::Overwrite.add_u32(&mut init.features);
+    }
+    if let Some(share) = &opt.share {
+        TeleportFeatures::Share.add_u32(&mut init.features);
+        init.share = Some(share.as_bytes().to_vec());
+    }
+    init.advertise_revision();
+
+    let (action, path, target) = match entry {
+        Entry::File(path) | Entry::Dir(path) => (TeleportAction::Mkdir, path, None),
+        Entry::Symlink(path, target) => (TeleportAction::Symlink, path, Some(target.clone())),
+        Entry::Hardlink(path, first) => (
+            TeleportAction::Hardlink,
+            path,
+            Some(remote_name(first, rep, opt.keep_path)),
+        ),
     };
 
-    // Send the data chunk
-    utils::send_packet(&mut stream, TeleportAction::Data, enc, chunk.serialize()?)?;
+    // Directories only make sense when the path is kept
+    if action == TeleportAction::Mkdir && !opt.keep_path {
+        return Ok(Next::Skip);
+    }
+
+    let filename = remote_name(path, rep, opt.keep_path);
+    init.filename = filename.as_bytes().to_vec();
+
+    // Add requested metadata of the directory or symlink itself
+    let meta = match action {
+        TeleportAction::Mkdir => {
+            init.chmod = fs::metadata(path)?.permissions().mode();
+            match opt.preserve.is_empty() {
+                true => None,
+                false => Some(metadata::gather(&File::open(path)?, &opt.preserve)?),
+            }
+        }
+        TeleportAction::Symlink if !opt.preserve.is_empty() => {
+            Some(metadata::gather_link(Path::new(path), &opt.preserve)?)
+        }
+        _ => None,
+    };
+    if let Some(meta) = meta.filter(|m| !m.is_empty()) {
+        TeleportFeatures::Metadata.add_u32(&mut init.features);
+        init.metadata = Some(meta);
+    }
+
+    let data = match target {
+        Some(target) => TeleportLink {
+            target_len: 0,
+            target: target.into_bytes(),
+            init,
+        }
+        .serialize()?,
+        None => init.serialize()?,
+    };
+
+    // Send the entry and receive the response from the server
+    let (_, _, recv) = request(opt, action, data, legacy)?;
+
+    if num == 0 {
+        println!("Server {}", recv.version);
+    }
+
+    // Later directories and links are left out without asking
+    if *legacy {
+        println!(
+            "Server {} speaks the legacy wire format, skipping: {filename}",
+            recv.version
+        );
+        return Ok(Next::Skip);
+    }
+
+    let next = check_status(&recv, &filename, opt)?;
+    if let Next::Proceed = next {
+        let kind = match action {
+            TeleportAction::Mkdir => "directory",
+            TeleportAction::Symlink => "symlink",
+            _ => "hard link",
+        };
+        println!(
+            "Created {kind} {}/{}: {}",
+            num + 1,
+            total,
+            stored_as(&recv, &filename)
+        );
+    }
+
+    Ok(next)
+}
+
+/// Show where the server stored a file, if not under the name it was sent as
+fn stored_as(recv: &TeleportInitAck, filename: &str) -> String {
+    match &recv.path {
+        Some(path) if path != filename.as_bytes() => {
+            format!("{filename} => {}", String::from_utf8_lossy(path))
+        }
+        _ => filename.to_string(),
+    }
+}
+
+/// Wait for the server to confirm that it stored the file, failing with the
+/// reason it sends otherwise
+fn check_stored(stream: &mut TcpStream, enc: &Option<TeleportEnc>) -> Result<(), TeleportError> {
+    let packet = utils::recv_packet(stream, enc)?;
+    let mut chunk = TeleportData::new();
+    chunk.deserialize(&packet.data)?;
+    if packet.action != TeleportAction::Data as u8 || chunk.data_len != 0 {
+        return Err(TeleportError::InvalidProtocol);
+    }
 
     Ok(())
 }
 
 /// Send function receives the ACK for data and sends the file data
 fn send(
-    mut stream: TcpStream,
+    stream: &mut TcpStream,
     mut file: File,
     header: &TeleportInit,
     enc: &Option<TeleportEnc>,
-    delta: Option<TeleportDelta>,
+    recv: TeleportInitAck,
     file_delta: Option<TeleportDelta>,
+    extents: Option<Vec<Extent>>,
 ) -> Result<(), TeleportError> {
     let mut buf = Vec::<u8>::new();
     let meta = file.metadata()?;
+    let delta = recv.delta;
+
+    // Servers that predate Abort messages would take it for file data
+    let abortable = recv.revision.map_or(false, |r| r >= ABORT_REVISION);
 
     // Set transfer chunk size to delta chunk size, or default to 4096
     match delta {
@@ -383,20 +878,18 @@ fn send(
 
     // If present, get the lengths of the delta hash arrays
     let compare_delta = delta.is_some() && file_delta.is_some();
-    let delta_len = if delta.is_some() {
-        delta.as_ref().unwrap().chunk_hash.len()
-    } else {
-        0
-    };
-    let file_delta_len = if file_delta.is_some() {
-        file_delta.as_ref().unwrap().chunk_hash.len()
-    } else {
-        0
-    };
+    let delta_len = delta.as_ref().map_or(0, |d| d.chunk_hash.len());
+    let file_delta_len = file_delta.as_ref().map_or(0, |d| d.chunk_hash.len());
 
     // Send file data
     let mut sent = 0;
+    let mut hole = TeleportHole::new();
     loop {
+        // Stop early if the server gave up on the transfer
+        if abortable {
+            utils::check_abort(stream, enc)?;
+        }
+
         // Check if hash matches, if so: skip chunk
         let index = sent / buf.len();
         if compare_delta
@@ -405,15 +898,37 @@ fn send(
             && delta.as_ref().unwrap().chunk_hash[index]
                 == file_delta.as_ref().unwrap().chunk_hash[index]
         {
+            send_hole(stream, enc, &mut hole)?;
             sent += buf.len();
             continue;
         }
 
+        // Check if the chunk is a hole, if so: extend the pending hole
+        let len = (buf.len() as u64).min(meta.len().saturating_sub(sent as u64));
+        if let Some(e) = &extents {
+            if len > 0 && sparse::is_hole(e, sent as u64, len) {
+                if hole.length == 0 {
+                    hole.offset = sent as u64;
+                }
+                hole.length += len;
+                sent += len as usize;
+                utils::print_updates(sent as f64, header);
+                continue;
+            }
+        }
+        send_hole(stream, enc, &mut hole)?;
+
         file.seek(SeekFrom::Start(sent as u64))?;
         // Read a chunk of the file
         let len = match file.read(&mut buf) {
             Ok(l) => l,
-            Err(s) => return Err(TeleportError::Io(s)),
+            Err(s) => {
+                let e = TeleportError::Io(s);
+                if abortable {
+                    utils::send_abort(stream, enc, TeleportAbort::from_error(&e));
+                }
+                return Err(e);
+            }
         };
 
         // If a length of 0 was read, we're done sending
@@ -429,13 +944,30 @@ fn send(
         };
 
         // Send the data chunk
-        utils::send_packet(&mut stream, TeleportAction::Data, enc, chunk.serialize()?)?;
+        utils::send_packet(stream, TeleportAction::Data, enc, chunk.serialize()?)?;
 
         sent += len;
         utils::print_updates(sent as f64, header);
     }
 
-    send_data_complete(stream, enc, meta.len())?;
+    send_hole(stream, enc, &mut hole)?;
+    utils::send_data_complete(stream, enc, meta.len())?;
+
+    Ok(())
+}
+
+/// Send the pending hole, if any
+fn send_hole(
+    stream: &mut TcpStream,
+    enc: &Option<TeleportEnc>,
+    hole: &mut TeleportHole,
+) -> Result<(), TeleportError> {
+    if hole.length == 0 {
+        return Ok(());
+    }
+
+    utils::send_packet(stream, TeleportAction::Hole, enc, hole.serialize())?;
+    *hole = TeleportHole::new();
 
     Ok(())
 }
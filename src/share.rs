@@ -0,0 +1,62 @@
+use crate::config;
+use crate::errors::TeleportError;
+use std::path::{Path, PathBuf};
+
+/// A named directory on the listener that clients can send files to
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Share {
+    pub name: String,
+    pub path: PathBuf,
+    /// Allow clients to overwrite existing files
+    pub overwrite: bool,
+    /// Always back up files that are overwritten
+    pub backup: bool,
+    pub must_encrypt: bool,
+    pub read_only: bool,
+}
+
+/// Load the shares defined in a config file, one section per share:
+/// ```text
+/// [builds]
+/// path = /srv/builds
+/// overwrite = true
+/// backup = true
+/// must_encrypt = false
+/// read_only = false
+/// ```
+pub fn load(file: &Path) -> Result<Vec<Share>, TeleportError> {
+    let mut out = Vec::<Share>::new();
+
+    for section in config::load(file)? {
+        let mut share = Share {
+            name: section.name.clone(),
+            path: PathBuf::new(),
+            overwrite: true,
+            backup: false,
+            must_encrypt: false,
+            read_only: false,
+        };
+
+        for entry in &section.entries {
+            match entry.key.as_str() {
+                "path" => share.path = PathBuf::from(&entry.value),
+                "overwrite" => share.overwrite = entry.as_bool()?,
+                "backup" => share.backup = entry.as_bool()?,
+                "must_encrypt" => share.must_encrypt = entry.as_bool()?,
+                "read_only" => share.read_only = entry.as_bool()?,
+                _ => return Err(entry.error("unknown share option")),
+            }
+        }
+
+        if share.path.as_os_str().is_empty() {
+            return Err(TeleportError::InvalidConfig(format!(
+                "line {}: share [{}] has no path",
+                section.line, section.name
+            )));
+        }
+
+        out.push(share);
+    }
+
+    Ok(out)
+}
@@ -0,0 +1,40 @@
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+static SHUTDOWN: AtomicUsize = AtomicUsize::new(0);
+static RELOAD: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handler(sig: libc::c_int) {
+    // Only async-signal-safe work is allowed here
+    if sig == libc::SIGHUP {
+        RELOAD.store(true, Ordering::SeqCst);
+    } else {
+        SHUTDOWN.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+/// Treat SIGINT and SIGTERM as shutdown requests and SIGHUP as a request to
+/// reload the configuration
+pub fn install() -> io::Result<()> {
+    for sig in [libc::SIGINT, libc::SIGTERM, libc::SIGHUP] {
+        let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+        action.sa_sigaction = handler as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESTART;
+        unsafe { libc::sigemptyset(&mut action.sa_mask) };
+        if unsafe { libc::sigaction(sig, &action, std::ptr::null_mut()) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+/// Number of shutdown signals received so far
+pub fn shutdown_count() -> usize {
+    SHUTDOWN.load(Ordering::SeqCst)
+}
+
+/// Check for, and clear, a pending reload request
+pub fn take_reload() -> bool {
+    RELOAD.swap(false, Ordering::SeqCst)
+}
@@ -0,0 +1,180 @@
+use crate::errors::TeleportError;
+use std::fs::File;
+use std::io;
+use std::io::{Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
+
+/// A range of a file that contains data, as (offset, length)
+pub type Extent = (u64, u64);
+
+/// Locate the data extents of a file using SEEK_DATA/SEEK_HOLE. Filesystems
+/// that do not support hole detection report the whole file as data.
+pub fn data_extents(file: &File) -> Result<Vec<Extent>, TeleportError> {
+    let fd = file.as_raw_fd();
+    let size = file.metadata()?.len();
+    let mut extents = Vec::<Extent>::new();
+
+    let mut offset: u64 = 0;
+    while offset < size {
+        let data = unsafe { libc::lseek(fd, offset as libc::off_t, libc::SEEK_DATA) };
+        if data < 0 {
+            let err = io::Error::last_os_error();
+            match err.raw_os_error() {
+                // No more data until the end of the file
+                Some(libc::ENXIO) => break,
+                // Hole detection is not supported
+                Some(libc::EINVAL) => return Ok(vec![(0, size)]),
+                _ => return Err(TeleportError::Io(err)),
+            }
+        }
+
+        let hole = unsafe { libc::lseek(fd, data, libc::SEEK_HOLE) };
+        if hole < 0 {
+            return Err(TeleportError::Io(io::Error::last_os_error()));
+        }
+
+        extents.push((data as u64, (hole - data) as u64));
+        offset = hole as u64;
+    }
+
+    Ok(extents)
+}
+
+/// Check whether the range [offset, offset + len) contains no data. The
+/// extents are sorted and do not overlap, as returned by `data_extents`, so
+/// only the first one ending after `offset` can overlap the range.
+pub fn is_hole(extents: &[Extent], offset: u64, len: u64) -> bool {
+    let next = extents.partition_point(|(start, elen)| start + elen <= offset);
+    extents
+        .get(next)
+        .map_or(true, |(start, _)| *start >= offset + len)
+}
+
+/// Deallocate a range of a file, keeping the file size. If the filesystem
+/// cannot punch holes, the range is overwritten with zeros instead.
+pub fn punch_hole(file: &mut File, offset: u64, len: u64) -> Result<(), TeleportError> {
+    let ret = unsafe {
+        libc::fallocate(
+            file.as_raw_fd(),
+            libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+            offset as libc::off_t,
+            len as libc::off_t,
+        )
+    };
+    if ret == 0 {
+        return Ok(());
+    }
+
+    let zeros = vec![0; 65536];
+    file.seek(SeekFrom::Start(offset))?;
+    let mut left = len;
+    while left > 0 {
+        let n = left.min(zeros.len() as u64) as usize;
+        file.write_all(&zeros[..n])?;
+        left -= n as u64;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::TestDir;
+    use std::fs;
+    use std::fs::OpenOptions;
+    use std::io::Read;
+
+    #[test]
+    fn test_sparse_roundtrip() {
+        let dir = TestDir::create();
+        let path = dir.join("file");
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .expect("Test should never fail");
+
+        // Data at the start and the end of a 4M file
+        let block = vec![0xa5; 65536];
+        file.write_all(&block).expect("Test should never fail");
+        file.seek(SeekFrom::Start((4 << 20) - 65536))
+            .expect("Test should never fail");
+        file.write_all(&block).expect("Test should never fail");
+
+        let extents = data_extents(&file).expect("Test should never fail");
+        assert!(!extents.is_empty());
+        assert!(!is_hole(&extents, 0, 1));
+        assert!(!is_hole(&extents, (4 << 20) - 1, 1));
+        let size: u64 = extents.iter().map(|(_, len)| len).sum();
+        assert!(size <= 4 << 20);
+
+        // Filesystems with hole detection report the gap in between
+        if extents.len() > 1 {
+            assert!(is_hole(&extents, 1 << 20, 65536));
+        }
+
+        // Rebuild the file from its extents, as a receiver would
+        let copy = path.with_extension("copy");
+        let mut dst = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&copy)
+            .expect("Test should never fail");
+        dst.set_len(4 << 20).expect("Test should never fail");
+        let mut offset = 0;
+        for (start, len) in &extents {
+            if *start > offset {
+                punch_hole(&mut dst, offset, start - offset).expect("Test should never fail");
+            }
+            let mut data = vec![0; *len as usize];
+            file.seek(SeekFrom::Start(*start))
+                .expect("Test should never fail");
+            file.read_exact(&mut data).expect("Test should never fail");
+            dst.seek(SeekFrom::Start(*start))
+                .expect("Test should never fail");
+            dst.write_all(&data).expect("Test should never fail");
+            offset = start + len;
+        }
+        assert_eq!(
+            fs::read(&copy).expect("Test should never fail"),
+            fs::read(&path).expect("Test should never fail")
+        );
+
+        // Punching a hole into data reads back as zeros, keeping the size
+        punch_hole(&mut file, 0, 65536).expect("Test should never fail");
+        let data = fs::read(&path).expect("Test should never fail");
+        assert_eq!(data.len(), 4 << 20);
+        assert!(data[..(4 << 20) - 65536].iter().all(|b| *b == 0));
+        assert!(data[(4 << 20) - 65536..].iter().all(|b| *b == 0xa5));
+        let extents = data_extents(&file).expect("Test should never fail");
+        if extents.len() == 1 {
+            assert!(is_hole(&extents, 0, 65536));
+        }
+    }
+
+    #[test]
+    fn test_is_hole_many_extents() {
+        // A fragmented image: 4K of data every 12K
+        let extents: Vec<Extent> = (0..100_000).map(|i| (i * 12288 + 8192, 4096)).collect();
+        let linear = |offset: u64, len: u64| {
+            !extents
+                .iter()
+                .any(|(start, elen)| *start < offset + len && offset < start + elen)
+        };
+
+        for offset in (0..1_300_000_000).step_by(10_000_019) {
+            for len in [1, 4096, 8192, 65536] {
+                assert_eq!(is_hole(&extents, offset, len), linear(offset, len));
+            }
+        }
+        assert!(is_hole(&extents, 0, 8192));
+        assert!(!is_hole(&extents, 0, 8193));
+        assert!(is_hole(&extents, 12288, 8192));
+        assert!(!is_hole(&extents, 12287, 1));
+        assert!(is_hole(&extents, 100_000 * 12288, 1 << 30));
+        assert!(is_hole(&[], 0, 4096));
+    }
+}
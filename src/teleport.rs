@@ -6,6 +6,7 @@ use semver::Version;
 use std::fmt;
 use std::fs::File;
 use std::hash::Hasher;
+use std::io;
 use std::io::{Read, Seek};
 use x25519_dalek::{EphemeralSecret, PublicKey};
 use xxhash_rust::xxh3;
@@ -26,8 +27,14 @@ pub enum TeleportAction {
     Ecdh = 0x04,
     EcdhAck = 0x08,
     Ping = 0x10,
+    Mkdir = 0x11,
+    Symlink = 0x12,
+    Hardlink = 0x13,
+    Restore = 0x14,
+    Abort = 0x15,
     PingAck = 0x20,
     Data = 0x40,
+    Hole = 0x41,
     Encrypted = 0x80,
 }
 
@@ -159,9 +166,46 @@ pub enum TeleportFeatures {
     Backup = 0x08,
     Rename = 0x10,
     Ping = 0x20,
+    Metadata = 0x40,
+    Sparse = 0x80,
+    Share = 0x100,
+    Path = 0x200,
+    Revision = 0x400,
 }
 
 impl TeleportFeatures {
+    /// Every feature this release supports
+    pub const ALL: [TeleportFeatures; 11] = [
+        TeleportFeatures::NewFile,
+        TeleportFeatures::Delta,
+        TeleportFeatures::Overwrite,
+        TeleportFeatures::Backup,
+        TeleportFeatures::Rename,
+        TeleportFeatures::Ping,
+        TeleportFeatures::Metadata,
+        TeleportFeatures::Sparse,
+        TeleportFeatures::Share,
+        TeleportFeatures::Path,
+        TeleportFeatures::Revision,
+    ];
+
+    /// Name of the feature, as passed to hooks
+    pub fn name(&self) -> &'static str {
+        match self {
+            TeleportFeatures::NewFile => "newfile",
+            TeleportFeatures::Delta => "delta",
+            TeleportFeatures::Overwrite => "overwrite",
+            TeleportFeatures::Backup => "backup",
+            TeleportFeatures::Rename => "rename",
+            TeleportFeatures::Ping => "ping",
+            TeleportFeatures::Metadata => "metadata",
+            TeleportFeatures::Sparse => "sparse",
+            TeleportFeatures::Share => "share",
+            TeleportFeatures::Path => "path",
+            TeleportFeatures::Revision => "revision",
+        }
+    }
+
     pub fn add(&self, opt: &mut Option<u32>) -> Result<(), TeleportError> {
         if let Some(o) = opt {
             *o |= *self as u32;
@@ -216,8 +260,9 @@ impl TeleportVersion {
         Ok(())
     }
 
-    pub fn is_compatible(&self, version: &Version) -> bool {
-        version.major == self.major as u64 && version.minor == self.minor as u64
+    /// Whether this is a release that predates revision negotiation
+    pub fn is_legacy(&self) -> bool {
+        self.major == LEGACY_VERSION.major && self.minor == LEGACY_VERSION.minor
     }
 }
 
@@ -227,6 +272,38 @@ impl fmt::Display for TeleportVersion {
     }
 }
 
+/// Highest revision of the wire format this build speaks
+pub const PROTOCOL_REVISION: u16 = 4;
+
+/// First revision of the wire format that understands `Abort` messages
+pub const ABORT_REVISION: u16 = 3;
+
+/// First revision of the wire format in which the server confirms that a
+/// received file was stored
+pub const CONFIRM_REVISION: u16 = 4;
+
+/// Revision of the fixed v0.10 wire format, spoken by releases that
+/// predate revision negotiation and advertise no revision at all
+pub const LEGACY_REVISION: u16 = 1;
+
+/// Lowest revision of the wire format this build still speaks
+pub const MIN_PROTOCOL_REVISION: u16 = LEGACY_REVISION;
+
+/// Version presented to listeners that only speak the legacy wire format
+pub const LEGACY_VERSION: TeleportVersion = TeleportVersion {
+    major: 0,
+    minor: 10,
+    patch: 0,
+};
+
+/// Features understood by the legacy wire format
+pub const LEGACY_FEATURES: u32 = TeleportFeatures::NewFile as u32
+    | TeleportFeatures::Delta as u32
+    | TeleportFeatures::Overwrite as u32
+    | TeleportFeatures::Backup as u32
+    | TeleportFeatures::Rename as u32
+    | TeleportFeatures::Ping as u32;
+
 #[derive(Default, Clone, Debug, PartialEq, Eq)]
 pub struct TeleportInit {
     pub version: TeleportVersion,
@@ -235,6 +312,10 @@ pub struct TeleportInit {
     pub filesize: u64,
     pub filename_len: u16,
     pub filename: Vec<u8>,
+    pub revision: Option<u16>,
+    pub share: Option<Vec<u8>>,
+    pub metadata: Option<TeleportMetadata>,
+    pub extensions: TeleportExtensions,
 }
 
 impl TeleportInit {
@@ -252,7 +333,41 @@ impl TeleportInit {
             filesize: 0,
             filename_len: 0,
             filename: Vec::<u8>::new(),
+            revision: None,
+            share: None,
+            metadata: None,
+            extensions: TeleportExtensions::default(),
+        }
+    }
+
+    /// Advertise the highest wire format revision this build speaks
+    pub fn advertise_revision(&mut self) {
+        TeleportFeatures::Revision.add_u32(&mut self.features);
+        self.revision = Some(PROTOCOL_REVISION);
+    }
+
+    /// Agree on the highest revision both sides speak, or None if there
+    /// is no common revision
+    pub fn negotiate(&self) -> Option<u16> {
+        match self.revision {
+            Some(r) if r >= MIN_PROTOCOL_REVISION => Some(r.min(PROTOCOL_REVISION)),
+            Some(_) => None,
+            None if self.version.is_legacy() => Some(LEGACY_REVISION),
+            None => None,
+        }
+    }
+
+    /// Rewrite the request in the legacy wire format, returns false if it
+    /// relies on features the legacy format cannot express
+    pub fn downgrade(&mut self) -> bool {
+        if self.share.is_some() {
+            return false;
         }
+        self.version = LEGACY_VERSION;
+        self.features &= LEGACY_FEATURES;
+        self.revision = None;
+        self.metadata = None;
+        true
     }
 
     pub fn serialize(&self) -> Result<Vec<u8>, TeleportError> {
@@ -277,6 +392,29 @@ impl TeleportInit {
         // Add filename
         out.append(&mut self.filename.to_vec());
 
+        // Add optional wire format revision
+        if TeleportFeatures::Revision.check_u32(self.features) {
+            let revision = self.revision.unwrap_or(PROTOCOL_REVISION);
+            out.append(&mut revision.to_le_bytes().to_vec());
+        }
+
+        // Add optional share name
+        if let Some(share) = &self.share {
+            let slen = u16::try_from(share.len())?;
+            out.append(&mut slen.to_le_bytes().to_vec());
+            out.append(&mut share.to_vec());
+        }
+
+        // Add optional metadata block
+        if let Some(meta) = &self.metadata {
+            out.append(&mut meta.serialize()?);
+        }
+
+        // Add optional extension area
+        if !self.extensions.is_empty() {
+            out.append(&mut self.extensions.serialize()?);
+        }
+
         Ok(out)
     }
 
@@ -299,11 +437,520 @@ impl TeleportInit {
         self.filename_len = buf.read_u16::<LittleEndian>()?;
 
         // Extract filename
-        let fname = &buf[..self.filename_len as usize].to_vec();
+        if buf.len() < self.filename_len as usize {
+            return Err(TeleportError::InvalidFileName);
+        }
+        let (fname, mut rest) = buf.split_at(self.filename_len as usize);
         self.filename = fname.to_vec();
-        if self.filename.len() != self.filename_len as usize {
+
+        // Extract optional wire format revision
+        if TeleportFeatures::Revision.check_u32(self.features) {
+            self.revision = Some(rest.read_u16::<LittleEndian>()?);
+        }
+
+        // Extract optional share name
+        if TeleportFeatures::Share.check_u32(self.features) {
+            let slen = rest.read_u16::<LittleEndian>()? as usize;
+            if rest.len() < slen {
+                return Err(TeleportError::InvalidFileName);
+            }
+            let (share, remain) = rest.split_at(slen);
+            self.share = Some(share.to_vec());
+            rest = remain;
+        }
+
+        // Extract optional metadata block
+        if TeleportFeatures::Metadata.check_u32(self.features) {
+            let mut meta = TeleportMetadata::default();
+            let len = meta.deserialize(rest)?;
+            self.metadata = Some(meta);
+            rest = &rest[len..];
+        }
+
+        // Extract optional extension area, older clients send none
+        if !rest.is_empty() {
+            self.extensions.deserialize(rest)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Payload of the `Symlink` and `Hardlink` actions: the link target followed
+/// by a `TeleportInit` describing the link itself
+#[derive(Default, Clone, Debug, PartialEq, Eq)]
+pub struct TeleportLink {
+    pub target_len: u16,
+    pub target: Vec<u8>,
+    pub init: TeleportInit,
+}
+
+impl TeleportLink {
+    pub fn serialize(&self) -> Result<Vec<u8>, TeleportError> {
+        let mut out = Vec::<u8>::new();
+
+        // Add target_len
+        let tlen = u16::try_from(self.target.len())?;
+        out.append(&mut tlen.to_le_bytes().to_vec());
+
+        // Add target
+        out.append(&mut self.target.to_vec());
+
+        // Add link init
+        out.append(&mut self.init.serialize()?);
+
+        Ok(out)
+    }
+
+    pub fn deserialize(&mut self, input: &[u8]) -> Result<(), TeleportError> {
+        let mut buf: &[u8] = input;
+
+        // Extract target_len
+        self.target_len = buf.read_u16::<LittleEndian>()?;
+
+        // Extract target
+        if buf.len() < self.target_len as usize {
             return Err(TeleportError::InvalidFileName);
         }
+        let (target, rest) = buf.split_at(self.target_len as usize);
+        self.target = target.to_vec();
+
+        // Extract link init
+        self.init.deserialize(rest)?;
+
+        Ok(())
+    }
+}
+
+/// Payload of the `Restore` action: which backup generation to restore,
+/// counting back from the most recent one (1), followed by a `TeleportInit`
+/// naming the file
+#[derive(Default, Clone, Debug, PartialEq, Eq)]
+pub struct TeleportRestore {
+    pub generation: u16,
+    pub init: TeleportInit,
+}
+
+impl TeleportRestore {
+    pub fn serialize(&self) -> Result<Vec<u8>, TeleportError> {
+        let mut out = Vec::<u8>::new();
+
+        // Add generation
+        out.append(&mut self.generation.to_le_bytes().to_vec());
+
+        // Add file init
+        out.append(&mut self.init.serialize()?);
+
+        Ok(out)
+    }
+
+    pub fn deserialize(&mut self, input: &[u8]) -> Result<(), TeleportError> {
+        let mut buf: &[u8] = input;
+
+        // Extract generation
+        self.generation = buf.read_u16::<LittleEndian>()?;
+
+        // Extract file init
+        self.init.deserialize(buf)?;
+
+        Ok(())
+    }
+}
+
+/// Announcement sent by listeners over UDP multicast, so that clients can
+/// find them without probing
+#[derive(Default, Clone, Debug, PartialEq, Eq)]
+pub struct TeleportBeacon {
+    pub version: TeleportVersion,
+    pub port: u16,
+    pub flags: u8,
+    pub fingerprint: u64,
+    pub name: Vec<u8>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TeleportBeaconFlags {
+    MustEncrypt = 0x01,
+}
+
+impl TeleportBeacon {
+    pub fn serialize(&self) -> Result<Vec<u8>, TeleportError> {
+        let mut out = Vec::<u8>::new();
+
+        // Add protocol identifier
+        out.append(&mut PROTOCOL.to_le_bytes().to_vec());
+
+        // Add version
+        out.append(&mut self.version.serialize());
+
+        // Add port
+        out.append(&mut self.port.to_le_bytes().to_vec());
+
+        // Add flags
+        out.push(self.flags);
+
+        // Add fingerprint
+        out.append(&mut self.fingerprint.to_le_bytes().to_vec());
+
+        // Add name
+        let nlen = u16::try_from(self.name.len())?;
+        out.append(&mut nlen.to_le_bytes().to_vec());
+        out.append(&mut self.name.to_vec());
+
+        Ok(out)
+    }
+
+    pub fn deserialize(&mut self, input: &[u8]) -> Result<(), TeleportError> {
+        let mut buf: &[u8] = input;
+
+        // Extract protocol identifier
+        if buf.read_u64::<LittleEndian>()? != PROTOCOL {
+            return Err(TeleportError::InvalidProtocol);
+        }
+
+        // Extract version
+        self.version.deserialize(buf)?;
+        buf = &buf[6..];
+
+        // Extract port
+        self.port = buf.read_u16::<LittleEndian>()?;
+
+        // Extract flags
+        self.flags = buf.read_u8()?;
+
+        // Extract fingerprint
+        self.fingerprint = buf.read_u64::<LittleEndian>()?;
+
+        // Extract name
+        let nlen = buf.read_u16::<LittleEndian>()? as usize;
+        if buf.len() < nlen {
+            return Err(TeleportError::InvalidLength);
+        }
+        self.name = buf[..nlen].to_vec();
+
+        Ok(())
+    }
+
+    pub fn must_encrypt(&self) -> bool {
+        self.flags & TeleportBeaconFlags::MustEncrypt as u8 != 0
+    }
+}
+
+/// What a listener offers, sent along with a Pong so that clients can pick
+/// their options before sending
+#[derive(Default, Clone, Debug, PartialEq, Eq)]
+pub struct TeleportCapabilities {
+    /// TeleportFeatures supported by the listener
+    pub features: u32,
+    pub flags: u8,
+    /// Bytes available for received files, u64::MAX if unknown
+    pub free_space: u64,
+    pub name: Vec<u8>,
+    pub shares: Vec<Vec<u8>>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TeleportCapabilityFlags {
+    MustEncrypt = 0x01,
+    Overwrite = 0x02,
+}
+
+impl TeleportCapabilities {
+    pub fn serialize(&self) -> Result<Vec<u8>, TeleportError> {
+        let mut out = Vec::<u8>::new();
+
+        // Add features
+        out.append(&mut self.features.to_le_bytes().to_vec());
+
+        // Add flags
+        out.push(self.flags);
+
+        // Add free space
+        out.append(&mut self.free_space.to_le_bytes().to_vec());
+
+        // Add name
+        let nlen = u16::try_from(self.name.len())?;
+        out.append(&mut nlen.to_le_bytes().to_vec());
+        out.append(&mut self.name.to_vec());
+
+        // Add share names
+        let count = u16::try_from(self.shares.len())?;
+        out.append(&mut count.to_le_bytes().to_vec());
+        for share in &self.shares {
+            let slen = u16::try_from(share.len())?;
+            out.append(&mut slen.to_le_bytes().to_vec());
+            out.append(&mut share.to_vec());
+        }
+
+        Ok(out)
+    }
+
+    pub fn deserialize(&mut self, input: &[u8]) -> Result<(), TeleportError> {
+        let mut buf: &[u8] = input;
+
+        // Extract features
+        self.features = buf.read_u32::<LittleEndian>()?;
+
+        // Extract flags
+        self.flags = buf.read_u8()?;
+
+        // Extract free space
+        self.free_space = buf.read_u64::<LittleEndian>()?;
+
+        // Extract name
+        let nlen = buf.read_u16::<LittleEndian>()? as usize;
+        if buf.len() < nlen {
+            return Err(TeleportError::InvalidLength);
+        }
+        self.name = buf[..nlen].to_vec();
+        buf = &buf[nlen..];
+
+        // Extract share names
+        let count = buf.read_u16::<LittleEndian>()?;
+        self.shares.clear();
+        for _ in 0..count {
+            let slen = buf.read_u16::<LittleEndian>()? as usize;
+            if buf.len() < slen {
+                return Err(TeleportError::InvalidLength);
+            }
+            self.shares.push(buf[..slen].to_vec());
+            buf = &buf[slen..];
+        }
+
+        Ok(())
+    }
+
+    pub fn must_encrypt(&self) -> bool {
+        self.flags & TeleportCapabilityFlags::MustEncrypt as u8 != 0
+    }
+
+    pub fn overwrite(&self) -> bool {
+        self.flags & TeleportCapabilityFlags::Overwrite as u8 != 0
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TeleportMetaKind {
+    Times = 0x01,
+    Owner = 0x02,
+    Xattr = 0x03,
+}
+
+#[derive(Default, Clone, Debug, PartialEq, Eq)]
+pub struct TeleportTimes {
+    pub atime: i64,
+    pub atime_nsec: u32,
+    pub mtime: i64,
+    pub mtime_nsec: u32,
+}
+
+#[derive(Default, Clone, Debug, PartialEq, Eq)]
+pub struct TeleportOwner {
+    pub uid: u32,
+    pub gid: u32,
+    pub user: Vec<u8>,
+    pub group: Vec<u8>,
+}
+
+#[derive(Default, Clone, Debug, PartialEq, Eq)]
+pub struct TeleportXattr {
+    pub name: Vec<u8>,
+    pub value: Vec<u8>,
+}
+
+/// Optional file metadata carried at the end of a `TeleportInit`. Every item is
+/// prefixed with its kind and length so that unknown kinds can be skipped.
+#[derive(Default, Clone, Debug, PartialEq, Eq)]
+pub struct TeleportMetadata {
+    pub times: Option<TeleportTimes>,
+    pub owner: Option<TeleportOwner>,
+    pub xattrs: Vec<TeleportXattr>,
+}
+
+impl TeleportMetadata {
+    fn item(kind: TeleportMetaKind, mut body: Vec<u8>) -> Result<Vec<u8>, TeleportError> {
+        let mut out = vec![kind as u8];
+        let len = u32::try_from(body.len())?;
+        out.append(&mut len.to_le_bytes().to_vec());
+        out.append(&mut body);
+        Ok(out)
+    }
+
+    fn bytes(input: &[u8]) -> Result<Vec<u8>, TeleportError> {
+        let mut out = Vec::<u8>::new();
+        let len = u16::try_from(input.len())?;
+        out.append(&mut len.to_le_bytes().to_vec());
+        out.append(&mut input.to_vec());
+        Ok(out)
+    }
+
+    fn read_bytes(buf: &mut &[u8]) -> Result<Vec<u8>, TeleportError> {
+        let len = buf.read_u16::<LittleEndian>()? as usize;
+        if buf.len() < len {
+            return Err(TeleportError::InvalidMetadata);
+        }
+        let (out, rest) = buf.split_at(len);
+        *buf = rest;
+        Ok(out.to_vec())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.times.is_none() && self.owner.is_none() && self.xattrs.is_empty()
+    }
+
+    pub fn serialize(&self) -> Result<Vec<u8>, TeleportError> {
+        let mut items = Vec::<Vec<u8>>::new();
+
+        // Add access and modification times
+        if let Some(t) = &self.times {
+            let mut body = Vec::<u8>::new();
+            body.append(&mut t.atime.to_le_bytes().to_vec());
+            body.append(&mut t.atime_nsec.to_le_bytes().to_vec());
+            body.append(&mut t.mtime.to_le_bytes().to_vec());
+            body.append(&mut t.mtime_nsec.to_le_bytes().to_vec());
+            items.push(Self::item(TeleportMetaKind::Times, body)?);
+        }
+
+        // Add ownership
+        if let Some(o) = &self.owner {
+            let mut body = Vec::<u8>::new();
+            body.append(&mut o.uid.to_le_bytes().to_vec());
+            body.append(&mut o.gid.to_le_bytes().to_vec());
+            body.append(&mut Self::bytes(&o.user)?);
+            body.append(&mut Self::bytes(&o.group)?);
+            items.push(Self::item(TeleportMetaKind::Owner, body)?);
+        }
+
+        // Add extended attributes
+        for x in &self.xattrs {
+            let mut body = Self::bytes(&x.name)?;
+            body.append(&mut x.value.to_vec());
+            items.push(Self::item(TeleportMetaKind::Xattr, body)?);
+        }
+
+        let mut out = Vec::<u8>::new();
+        let count = u16::try_from(items.len())?;
+        out.append(&mut count.to_le_bytes().to_vec());
+        for mut i in items {
+            out.append(&mut i);
+        }
+
+        Ok(out)
+    }
+
+    /// Returns the length of the metadata block, as more fields may follow
+    pub fn deserialize(&mut self, input: &[u8]) -> Result<usize, TeleportError> {
+        let mut buf: &[u8] = input;
+
+        // Extract item count
+        let count = buf.read_u16::<LittleEndian>()?;
+
+        for _ in 0..count {
+            // Extract item kind and length
+            let kind = buf.read_u8()?;
+            let len = buf.read_u32::<LittleEndian>()? as usize;
+            if buf.len() < len {
+                return Err(TeleportError::InvalidMetadata);
+            }
+            let (mut body, rest) = buf.split_at(len);
+            buf = rest;
+
+            match kind {
+                x if x == TeleportMetaKind::Times as u8 => {
+                    self.times = Some(TeleportTimes {
+                        atime: body.read_i64::<LittleEndian>()?,
+                        atime_nsec: body.read_u32::<LittleEndian>()?,
+                        mtime: body.read_i64::<LittleEndian>()?,
+                        mtime_nsec: body.read_u32::<LittleEndian>()?,
+                    });
+                }
+                x if x == TeleportMetaKind::Owner as u8 => {
+                    self.owner = Some(TeleportOwner {
+                        uid: body.read_u32::<LittleEndian>()?,
+                        gid: body.read_u32::<LittleEndian>()?,
+                        user: Self::read_bytes(&mut body)?,
+                        group: Self::read_bytes(&mut body)?,
+                    });
+                }
+                x if x == TeleportMetaKind::Xattr as u8 => {
+                    let name = Self::read_bytes(&mut body)?;
+                    self.xattrs.push(TeleportXattr {
+                        name,
+                        value: body.to_vec(),
+                    });
+                }
+                // Skip unknown metadata kinds
+                _ => (),
+            }
+        }
+
+        Ok(input.len() - buf.len())
+    }
+}
+
+#[derive(Default, Clone, Debug, PartialEq, Eq)]
+pub struct TeleportExtension {
+    pub kind: u16,
+    pub value: Vec<u8>,
+}
+
+/// Optional extension area at the end of `TeleportInit` and `TeleportInitAck`.
+/// Every item is prefixed with its kind and length, so new fields can be added
+/// without changing the fixed layouts and readers skip the kinds they do not
+/// know. Readers that predate the area ignore it as trailing data.
+#[derive(Default, Clone, Debug, PartialEq, Eq)]
+pub struct TeleportExtensions {
+    pub items: Vec<TeleportExtension>,
+}
+
+impl TeleportExtensions {
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    pub fn serialize(&self) -> Result<Vec<u8>, TeleportError> {
+        let mut out = Vec::<u8>::new();
+
+        // Add item count
+        let count = u16::try_from(self.items.len())?;
+        out.append(&mut count.to_le_bytes().to_vec());
+
+        for item in &self.items {
+            // Add item kind and length
+            out.append(&mut item.kind.to_le_bytes().to_vec());
+            let len = u32::try_from(item.value.len())?;
+            out.append(&mut len.to_le_bytes().to_vec());
+
+            // Add item value
+            out.append(&mut item.value.to_vec());
+        }
+
+        Ok(out)
+    }
+
+    pub fn deserialize(&mut self, input: &[u8]) -> Result<(), TeleportError> {
+        let mut buf: &[u8] = input;
+
+        // Extract item count
+        let count = buf.read_u16::<LittleEndian>()?;
+
+        self.items.clear();
+        for _ in 0..count {
+            // Extract item kind and length
+            let kind = buf.read_u16::<LittleEndian>()?;
+            let len = buf.read_u32::<LittleEndian>()? as usize;
+            if buf.len() < len {
+                return Err(TeleportError::InvalidExtension);
+            }
+
+            // Extract item value
+            let (value, rest) = buf.split_at(len);
+            self.items.push(TeleportExtension {
+                kind,
+                value: value.to_vec(),
+            });
+            buf = rest;
+        }
 
         Ok(())
     }
@@ -315,6 +962,10 @@ pub struct TeleportInitAck {
     pub version: TeleportVersion,
     pub features: Option<u32>,
     pub delta: Option<TeleportDelta>,
+    pub path: Option<Vec<u8>>,
+    pub revision: Option<u16>,
+    pub capabilities: Option<TeleportCapabilities>,
+    pub extensions: TeleportExtensions,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -329,6 +980,16 @@ pub enum TeleportStatus {
     EncryptionError = 0x06,
     BadFileName = 0x07,
     Pong = 0x08,
+    UnknownShare = 0x09,
+    AccessDenied = 0x0a,
+    PathNotAllowed = 0x0b,
+    TooLarge = 0x0c,
+    FeatureDenied = 0x0d,
+    QuotaExceeded = 0x0e,
+    Busy = 0x0f,
+    Pending = 0x10,
+    Rejected = 0x11,
+    NoBackup = 0x12,
     UnknownAction = 0xff,
 }
 
@@ -348,6 +1009,16 @@ impl TryFrom<u8> for TeleportStatus {
             x if x == TeleportStatus::EncryptionError as u8 => Ok(TeleportStatus::EncryptionError),
             x if x == TeleportStatus::BadFileName as u8 => Ok(TeleportStatus::BadFileName),
             x if x == TeleportStatus::Pong as u8 => Ok(TeleportStatus::Pong),
+            x if x == TeleportStatus::UnknownShare as u8 => Ok(TeleportStatus::UnknownShare),
+            x if x == TeleportStatus::AccessDenied as u8 => Ok(TeleportStatus::AccessDenied),
+            x if x == TeleportStatus::PathNotAllowed as u8 => Ok(TeleportStatus::PathNotAllowed),
+            x if x == TeleportStatus::TooLarge as u8 => Ok(TeleportStatus::TooLarge),
+            x if x == TeleportStatus::FeatureDenied as u8 => Ok(TeleportStatus::FeatureDenied),
+            x if x == TeleportStatus::QuotaExceeded as u8 => Ok(TeleportStatus::QuotaExceeded),
+            x if x == TeleportStatus::Busy as u8 => Ok(TeleportStatus::Busy),
+            x if x == TeleportStatus::Pending as u8 => Ok(TeleportStatus::Pending),
+            x if x == TeleportStatus::Rejected as u8 => Ok(TeleportStatus::Rejected),
+            x if x == TeleportStatus::NoBackup as u8 => Ok(TeleportStatus::NoBackup),
             x if x == TeleportStatus::UnknownAction as u8 => Ok(TeleportStatus::UnknownAction),
             _ => Err(TeleportError::InvalidStatusCode),
         }
@@ -367,6 +1038,10 @@ impl TeleportInitAck {
             },
             features: None,
             delta: None,
+            path: None,
+            revision: None,
+            capabilities: None,
+            extensions: TeleportExtensions::default(),
         }
     }
 
@@ -380,6 +1055,22 @@ impl TeleportInitAck {
         // Add version
         out.append(&mut self.version.serialize());
 
+        // Add optional capabilities of the listener
+        if status == TeleportStatus::Pong as u8 {
+            if let Some(caps) = self.capabilities {
+                out.append(&mut caps.serialize()?);
+            }
+            return Ok(out);
+        }
+
+        // Add the highest revision we speak when no common one was found
+        if status == TeleportStatus::WrongVersion as u8 {
+            if let Some(revision) = self.revision {
+                out.append(&mut revision.to_le_bytes().to_vec());
+            }
+            return Ok(out);
+        }
+
         // If no features, return early
         if status != TeleportStatus::Proceed as u8 || self.features.is_none() {
             return Ok(out);
@@ -395,6 +1086,25 @@ impl TeleportInitAck {
                     out.append(&mut delta.serialize()?);
                 }
             }
+
+            if TeleportFeatures::Path.check_u32(feat) {
+                // Add optional final path of the file
+                let path = self.path.unwrap_or_default();
+                let plen = u16::try_from(path.len())?;
+                out.append(&mut plen.to_le_bytes().to_vec());
+                out.append(&mut path.to_vec());
+            }
+
+            if TeleportFeatures::Revision.check_u32(feat) {
+                // Add the negotiated wire format revision
+                let revision = self.revision.unwrap_or(PROTOCOL_REVISION);
+                out.append(&mut revision.to_le_bytes().to_vec());
+            }
+
+            // Add optional extension area
+            if !self.extensions.is_empty() {
+                out.append(&mut self.extensions.serialize()?);
+            }
         }
 
         Ok(out)
@@ -411,6 +1121,19 @@ impl TeleportInitAck {
 
         let mut buf: &[u8] = &input[7..];
 
+        // Extract optional capabilities, older listeners send none
+        if self.status == TeleportStatus::Pong as u8 && !buf.is_empty() {
+            let mut caps = TeleportCapabilities::default();
+            caps.deserialize(buf)?;
+            self.capabilities = Some(caps);
+        }
+
+        // Extract the revision of a listener without a common one, older
+        // listeners send none
+        if self.status == TeleportStatus::WrongVersion as u8 && buf.len() >= 2 {
+            self.revision = Some(buf.read_u16::<LittleEndian>()?);
+        }
+
         // If no features, return early
         if self.status != TeleportStatus::Proceed as u8 {
             return Ok(());
@@ -420,15 +1143,39 @@ impl TeleportInitAck {
         let features = buf.read_u32::<LittleEndian>()?;
         self.features = Some(features);
 
-        // If no delta, return early
-        if !TeleportFeatures::Delta.check_u32(features) {
-            return Ok(());
+        // Extract optional TeleportDelta data
+        if TeleportFeatures::Delta.check_u32(features) {
+            // The length of the delta vector tells where the delta data ends
+            let chunks = match buf.get(20..22) {
+                Some(l) => u16::from_le_bytes([l[0], l[1]]) as usize,
+                None => return Err(TeleportError::InvalidLength),
+            };
+            let end = (22 + chunks * 8).min(buf.len());
+            let mut delta = TeleportDelta::new();
+            delta.deserialize(&buf[..end])?;
+            self.delta = Some(delta);
+            buf = &buf[end..];
         }
 
-        // Extract optional TeleportDelta data
-        let mut delta = TeleportDelta::new();
-        delta.deserialize(&input[11..])?;
-        self.delta = Some(delta);
+        // Extract optional final path of the file
+        if TeleportFeatures::Path.check_u32(features) {
+            let plen = buf.read_u16::<LittleEndian>()? as usize;
+            if buf.len() < plen {
+                return Err(TeleportError::InvalidFileName);
+            }
+            self.path = Some(buf[..plen].to_vec());
+            buf = &buf[plen..];
+        }
+
+        // Extract the negotiated wire format revision
+        if TeleportFeatures::Revision.check_u32(features) {
+            self.revision = Some(buf.read_u16::<LittleEndian>()?);
+        }
+
+        // Extract optional extension area, older listeners send none
+        if !buf.is_empty() {
+            self.extensions.deserialize(buf)?;
+        }
 
         Ok(())
     }
@@ -532,8 +1279,7 @@ impl TeleportDelta {
         let file_size = meta.len();
 
         file.rewind()?;
-        let mut buf = Vec::<u8>::new();
-        buf.resize(Self::chunk_size(meta.len()), 0);
+        let mut buf = vec![0; Self::chunk_size(meta.len())];
         let mut whole_hasher = xxh3::Xxh3::new();
         let mut chunk_hash = Vec::<u64>::new();
 
@@ -634,6 +1380,132 @@ impl TeleportData {
     }
 }
 
+/// A range of the file that contains no data and should be left as a hole
+#[derive(Debug, PartialEq, Eq)]
+pub struct TeleportHole {
+    pub offset: u64,
+    pub length: u64,
+}
+
+impl TeleportHole {
+    pub fn new() -> TeleportHole {
+        TeleportHole {
+            offset: 0,
+            length: 0,
+        }
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut out = Vec::<u8>::new();
+
+        // Add offset
+        out.append(&mut self.offset.to_le_bytes().to_vec());
+
+        // Add length
+        out.append(&mut self.length.to_le_bytes().to_vec());
+
+        out
+    }
+
+    pub fn deserialize(&mut self, input: &[u8]) -> Result<(), TeleportError> {
+        let mut buf: &[u8] = input;
+
+        // Extract offset
+        self.offset = buf.read_u64::<LittleEndian>()?;
+
+        // Extract length
+        self.length = buf.read_u64::<LittleEndian>()?;
+
+        Ok(())
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TeleportAbortCode {
+    NoSpace = 0x01,
+    NoPermission = 0x02,
+    Cancelled = 0x03,
+    Io = 0x04,
+    Protocol = 0x05,
+}
+
+/// Tells the peer that a transfer was aborted, and why. Either side may send
+/// it at any point once both agreed on at least `ABORT_REVISION`.
+#[derive(Default, Clone, Debug, PartialEq, Eq)]
+pub struct TeleportAbort {
+    pub code: u8,
+    pub reason: Vec<u8>,
+}
+
+impl TeleportAbort {
+    pub fn new(code: TeleportAbortCode, reason: &str) -> TeleportAbort {
+        TeleportAbort {
+            code: code as u8,
+            reason: reason.as_bytes().to_vec(),
+        }
+    }
+
+    /// Describe a local error to the peer
+    pub fn from_error(e: &TeleportError) -> TeleportAbort {
+        let code = match e {
+            TeleportError::Io(io) => match io.raw_os_error() {
+                Some(libc::ENOSPC) | Some(libc::EDQUOT) => TeleportAbortCode::NoSpace,
+                _ if io.kind() == io::ErrorKind::PermissionDenied => {
+                    TeleportAbortCode::NoPermission
+                }
+                _ => TeleportAbortCode::Io,
+            },
+            _ => TeleportAbortCode::Io,
+        };
+        TeleportAbort::new(code, &e.to_string())
+    }
+
+    /// The error the receiving side reports for this abort
+    pub fn error(&self) -> TeleportError {
+        let reason = String::from_utf8_lossy(&self.reason).to_string();
+        match self.code {
+            x if x == TeleportAbortCode::NoSpace as u8 => TeleportError::RemoteNoSpace(reason),
+            x if x == TeleportAbortCode::NoPermission as u8 => {
+                TeleportError::RemoteNoPermission(reason)
+            }
+            x if x == TeleportAbortCode::Cancelled as u8 => TeleportError::RemoteCancelled(reason),
+            _ => TeleportError::RemoteAborted(reason),
+        }
+    }
+
+    pub fn serialize(&self) -> Result<Vec<u8>, TeleportError> {
+        let mut out = Vec::<u8>::new();
+
+        // Add code
+        out.push(self.code);
+
+        // Add reason_len
+        let rlen = u16::try_from(self.reason.len())?;
+        out.append(&mut rlen.to_le_bytes().to_vec());
+
+        // Add reason
+        out.append(&mut self.reason.to_vec());
+
+        Ok(out)
+    }
+
+    pub fn deserialize(&mut self, input: &[u8]) -> Result<(), TeleportError> {
+        let mut buf: &[u8] = input;
+
+        // Extract code
+        self.code = buf.read_u8()?;
+
+        // Extract reason
+        let rlen = buf.read_u16::<LittleEndian>()? as usize;
+        if buf.len() < rlen {
+            return Err(TeleportError::InvalidAbort);
+        }
+        self.reason = buf[..rlen].to_vec();
+
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -654,6 +1526,33 @@ mod tests {
     ];
     const TESTDATAPKT: &[u8] = &[49, 212, 0, 0, 0, 0, 0, 0, 5, 0, 0, 0, 1, 2, 3, 4, 5];
     const TESTINITACK: &[u8] = &[0, 0, 0, 6, 0, 0, 0, 5, 0, 0, 0];
+    const TESTHOLE: &[u8] = &[0, 16, 0, 0, 0, 0, 0, 0, 0, 0, 0, 64, 0, 0, 0, 0];
+    const TESTMETADATA: &[u8] = &[
+        3, 0, 1, 24, 0, 0, 0, 210, 2, 150, 73, 0, 0, 0, 0, 1, 0, 0, 0, 211, 2, 150, 73, 0, 0, 0, 0,
+        2, 0, 0, 0, 2, 20, 0, 0, 0, 232, 3, 0, 0, 100, 0, 0, 0, 3, 0, 98, 111, 98, 5, 0, 117, 115,
+        101, 114, 115, 3, 11, 0, 0, 0, 6, 0, 117, 115, 101, 114, 46, 97, 1, 2, 3,
+    ];
+
+    fn test_metadata() -> TeleportMetadata {
+        TeleportMetadata {
+            times: Some(TeleportTimes {
+                atime: 1234567890,
+                atime_nsec: 1,
+                mtime: 1234567891,
+                mtime_nsec: 2,
+            }),
+            owner: Some(TeleportOwner {
+                uid: 1000,
+                gid: 100,
+                user: b"bob".to_vec(),
+                group: b"users".to_vec(),
+            }),
+            xattrs: vec![TeleportXattr {
+                name: b"user.a".to_vec(),
+                value: vec![1, 2, 3],
+            }],
+        }
+    }
 
     #[test]
     fn test_teleportheader_serialize() {
@@ -853,4 +1752,410 @@ mod tests {
 
         assert_eq!(test, t);
     }
+
+    #[test]
+    fn test_teleportinitack_path() {
+        let mut test = TeleportInitAck::new(TeleportStatus::Proceed);
+        let feat = TeleportFeatures::NewFile as u32
+            | TeleportFeatures::Delta as u32
+            | TeleportFeatures::Path as u32;
+        test.features = Some(feat);
+        test.delta = Some(TeleportDelta {
+            filesize: 4096,
+            hash: 0x0123456789abcdef,
+            chunk_size: 2048,
+            chunk_hash_len: 2,
+            chunk_hash: vec![1, 2],
+        });
+        test.path = Some(b"10.0.0.1/2024-01-31/file".to_vec());
+
+        let out = test.clone().serialize().expect("Test should never fail");
+        let mut t = TeleportInitAck::new(TeleportStatus::Proceed);
+        t.deserialize(&out).expect("Test should never fail");
+
+        assert_eq!(test, t);
+    }
+
+    #[test]
+    fn test_teleportinitack_capabilities() {
+        let mut test = TeleportInitAck::new(TeleportStatus::Pong);
+        test.capabilities = Some(TeleportCapabilities {
+            features: TeleportFeatures::NewFile as u32 | TeleportFeatures::Share as u32,
+            flags: TeleportCapabilityFlags::Overwrite as u8,
+            free_space: 1 << 40,
+            name: b"lab-station".to_vec(),
+            shares: vec![b"photos".to_vec(), b"logs".to_vec()],
+        });
+
+        let out = test.clone().serialize().expect("Test should never fail");
+        let mut t = TeleportInitAck::default();
+        t.deserialize(&out).expect("Test should never fail");
+        assert_eq!(test, t);
+
+        // Older listeners answer with a bare Pong
+        let out = TeleportInitAck::new(TeleportStatus::Pong)
+            .serialize()
+            .expect("Test should never fail");
+        let mut t = TeleportInitAck::default();
+        t.deserialize(&out).expect("Test should never fail");
+        assert_eq!(t.capabilities, None);
+    }
+
+    #[test]
+    fn test_teleportinit_revision() {
+        let mut test = TeleportInit::new(TeleportFeatures::NewFile);
+        test.filename = b"file".to_vec();
+        test.share = Some(b"photos".to_vec());
+        TeleportFeatures::Share.add_u32(&mut test.features);
+        test.advertise_revision();
+
+        let out = test.serialize().expect("Test should never fail");
+        let mut t = TeleportInit::default();
+        t.deserialize(&out).expect("Test should never fail");
+        assert_eq!(t.revision, Some(PROTOCOL_REVISION));
+        assert_eq!(t.share, test.share);
+        assert_eq!(t.negotiate(), Some(PROTOCOL_REVISION));
+
+        // Newer clients settle on our highest revision
+        t.revision = Some(PROTOCOL_REVISION + 5);
+        assert_eq!(t.negotiate(), Some(PROTOCOL_REVISION));
+        t.revision = Some(0);
+        assert_eq!(t.negotiate(), None);
+
+        // Requests to a share cannot be expressed in the legacy format
+        assert!(!t.downgrade());
+        t.share = None;
+        t.features = TeleportFeatures::NewFile as u32
+            | TeleportFeatures::Overwrite as u32
+            | TeleportFeatures::Path as u32;
+        assert!(t.downgrade());
+        assert_eq!(
+            t.features,
+            TeleportFeatures::NewFile as u32 | TeleportFeatures::Overwrite as u32
+        );
+
+        // Legacy clients advertise no revision
+        let out = t.serialize().expect("Test should never fail");
+        let mut t = TeleportInit::default();
+        t.deserialize(&out).expect("Test should never fail");
+        assert_eq!(t.version, LEGACY_VERSION);
+        assert_eq!(t.revision, None);
+        assert_eq!(t.negotiate(), Some(LEGACY_REVISION));
+        t.version.minor += 1;
+        assert_eq!(t.negotiate(), None);
+    }
+
+    #[test]
+    fn test_teleportinitack_revision() {
+        let mut test = TeleportInitAck::new(TeleportStatus::Proceed);
+        test.features = Some(TeleportFeatures::Path as u32 | TeleportFeatures::Revision as u32);
+        test.path = Some(b"file".to_vec());
+        test.revision = Some(LEGACY_REVISION);
+
+        let out = test.clone().serialize().expect("Test should never fail");
+        let mut t = TeleportInitAck::default();
+        t.deserialize(&out).expect("Test should never fail");
+        assert_eq!(test, t);
+
+        // A mismatch tells the client which revision we speak
+        let mut test = TeleportInitAck::new(TeleportStatus::WrongVersion);
+        test.revision = Some(PROTOCOL_REVISION);
+        let out = test.clone().serialize().expect("Test should never fail");
+        let mut t = TeleportInitAck::default();
+        t.deserialize(&out).expect("Test should never fail");
+        assert_eq!(test, t);
+    }
+
+    #[test]
+    fn test_teleportmetadata_serialize() {
+        let out = test_metadata().serialize().expect("Test should never fail");
+
+        assert_eq!(out, TESTMETADATA);
+    }
+
+    #[test]
+    fn test_teleportmetadata_deserialize() {
+        let mut t = TeleportMetadata::default();
+        t.deserialize(TESTMETADATA).expect("Test should never fail");
+
+        assert_eq!(test_metadata(), t);
+    }
+
+    #[test]
+    fn test_teleportmetadata_skip_unknown() {
+        let mut input = vec![2, 0, 0x7f, 2, 0, 0, 0, 9, 9];
+        input.append(&mut TESTMETADATA[2..31].to_vec());
+
+        let mut t = TeleportMetadata::default();
+        t.deserialize(&input).expect("Test should never fail");
+
+        assert_eq!(t.times, test_metadata().times);
+        assert_eq!(t.owner, None);
+    }
+
+    #[test]
+    fn test_teleportinit_metadata() {
+        let mut test = TeleportInit::new(TeleportFeatures::NewFile);
+        test.filename = vec![b'f', b'i', b'l', b'e'];
+        test.filename_len = test.filename.len() as u16;
+        TeleportFeatures::Metadata.add_u32(&mut test.features);
+        test.metadata = Some(test_metadata());
+
+        let out = test.serialize().expect("Test should never fail");
+        let mut t = TeleportInit::default();
+        t.deserialize(&out).expect("Test should never fail");
+
+        assert_eq!(test, t);
+    }
+
+    #[test]
+    fn test_teleportinit_share() {
+        let mut test = TeleportInit::new(TeleportFeatures::NewFile);
+        test.filename = vec![b'f', b'i', b'l', b'e'];
+        test.filename_len = test.filename.len() as u16;
+        TeleportFeatures::Share.add_u32(&mut test.features);
+        test.share = Some(vec![b'b', b'u', b'i', b'l', b'd', b's']);
+        TeleportFeatures::Metadata.add_u32(&mut test.features);
+        test.metadata = Some(test_metadata());
+
+        let out = test.serialize().expect("Test should never fail");
+        let mut t = TeleportInit::default();
+        t.deserialize(&out).expect("Test should never fail");
+
+        assert_eq!(test, t);
+    }
+
+    fn test_extensions() -> TeleportExtensions {
+        TeleportExtensions {
+            items: vec![
+                TeleportExtension {
+                    kind: 0x7f01,
+                    value: vec![1, 2, 3, 4, 5, 6, 7, 8],
+                },
+                TeleportExtension {
+                    kind: 0x7f02,
+                    value: Vec::<u8>::new(),
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn test_teleportinit_extensions() {
+        let mut test = TeleportInit::new(TeleportFeatures::NewFile);
+        test.filename = vec![b'f', b'i', b'l', b'e'];
+        test.filename_len = test.filename.len() as u16;
+        test.advertise_revision();
+        TeleportFeatures::Share.add_u32(&mut test.features);
+        test.share = Some(vec![b'b', b'u', b'i', b'l', b'd', b's']);
+        TeleportFeatures::Metadata.add_u32(&mut test.features);
+        test.metadata = Some(test_metadata());
+
+        // Without extensions the layout is unchanged
+        let plain = test.serialize().expect("Test should never fail");
+        test.extensions = test_extensions();
+        let out = test.serialize().expect("Test should never fail");
+        assert_eq!(out[..plain.len()], plain[..]);
+
+        let mut t = TeleportInit::default();
+        t.deserialize(&out).expect("Test should never fail");
+        assert_eq!(test, t);
+
+        // Readers that predate the extension area see the same fixed fields
+        let mut t = TeleportInit::default();
+        t.deserialize(&plain).expect("Test should never fail");
+        assert!(t.extensions.is_empty());
+        assert_eq!(t.metadata, test.metadata);
+
+        // A truncated extension item is refused
+        let mut t = TeleportInit::default();
+        let err = t.deserialize(&out[..out.len() - 10]);
+        assert!(matches!(err, Err(TeleportError::InvalidExtension)));
+    }
+
+    #[test]
+    fn test_teleportinitack_extensions() {
+        let mut test = TeleportInitAck::new(TeleportStatus::Proceed);
+        let feat = TeleportFeatures::NewFile as u32
+            | TeleportFeatures::Delta as u32
+            | TeleportFeatures::Path as u32
+            | TeleportFeatures::Revision as u32;
+        test.features = Some(feat);
+        test.delta = Some(TeleportDelta {
+            filesize: 4096,
+            hash: 0x0123456789abcdef,
+            chunk_size: 2048,
+            chunk_hash_len: 2,
+            chunk_hash: vec![1, 2],
+        });
+        test.path = Some(b"file".to_vec());
+        test.revision = Some(PROTOCOL_REVISION);
+        test.extensions = test_extensions();
+
+        let out = test.clone().serialize().expect("Test should never fail");
+        let mut t = TeleportInitAck::default();
+        t.deserialize(&out).expect("Test should never fail");
+        assert_eq!(test, t);
+
+        // A later release appends an extension this reader does not know.
+        // It is kept as is and the known fields read the same.
+        test.extensions = TeleportExtensions::default();
+        let mut future = test.clone().serialize().expect("Test should never fail");
+        future.extend_from_slice(&1u16.to_le_bytes());
+        future.extend_from_slice(&0x7fffu16.to_le_bytes());
+        future.extend_from_slice(&3u32.to_le_bytes());
+        future.extend_from_slice(&[9, 9, 9]);
+        let mut t = TeleportInitAck::default();
+        t.deserialize(&future).expect("Test should never fail");
+        assert_eq!(
+            t.extensions.items,
+            [TeleportExtension {
+                kind: 0x7fff,
+                value: vec![9, 9, 9],
+            }]
+        );
+        t.extensions = TeleportExtensions::default();
+        assert_eq!(t, test);
+    }
+
+    #[test]
+    fn test_teleportlink_serialize() {
+        let mut test = TeleportLink {
+            target: vec![b'o', b'l', b'd'],
+            ..Default::default()
+        };
+        test.init.version = TeleportVersion {
+            major: 0,
+            minor: 5,
+            patch: 5,
+        };
+        test.init.filename = vec![b'f', b'i', b'l', b'e'];
+        test.init.filesize = 12345;
+        test.init.chmod = 0o755;
+        test.init.features = TeleportFeatures::NewFile as u32 | TeleportFeatures::Overwrite as u32;
+
+        let out = test.serialize().expect("Test should never fail");
+
+        assert_eq!(out[..5], [3, 0, b'o', b'l', b'd']);
+        assert_eq!(out[5..], *TESTINIT);
+    }
+
+    #[test]
+    fn test_teleportlink_deserialize() {
+        let mut input = vec![3, 0, b'o', b'l', b'd'];
+        input.append(&mut TESTINIT.to_vec());
+
+        let mut t = TeleportLink::default();
+        t.deserialize(&input).expect("Test should never fail");
+
+        assert_eq!(t.target_len, 3);
+        assert_eq!(t.target, b"old");
+        assert_eq!(t.init.filename, b"file");
+        assert_eq!(t.init.chmod, 0o755);
+    }
+
+    #[test]
+    fn test_teleportrestore_deserialize() {
+        let mut input = vec![2, 0];
+        input.append(&mut TESTINIT.to_vec());
+
+        let mut t = TeleportRestore::default();
+        t.deserialize(&input).expect("Test should never fail");
+
+        assert_eq!(t.generation, 2);
+        assert_eq!(t.init.filename, b"file");
+        assert_eq!(t.serialize().expect("Test should never fail"), input);
+    }
+
+    #[test]
+    fn test_teleportbeacon_roundtrip() {
+        let test = TeleportBeacon {
+            version: TeleportVersion {
+                major: 0,
+                minor: 10,
+                patch: 8,
+            },
+            port: 9001,
+            flags: TeleportBeaconFlags::MustEncrypt as u8,
+            fingerprint: 0x0123456789abcdef,
+            name: b"lab-station".to_vec(),
+        };
+        let out = test.serialize().expect("Test should never fail");
+
+        let mut t = TeleportBeacon::default();
+        t.deserialize(&out).expect("Test should never fail");
+        assert_eq!(test, t);
+        assert!(t.must_encrypt());
+
+        // Anything else sent to the beacon port is ignored
+        let mut t = TeleportBeacon::default();
+        assert!(t.deserialize(&out[1..]).is_err());
+        assert!(t.deserialize(&out[..out.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_teleporthole_serialize() {
+        let test = TeleportHole {
+            offset: 4096,
+            length: 1 << 30,
+        };
+
+        assert_eq!(test.serialize(), TESTHOLE);
+    }
+
+    #[test]
+    fn test_teleporthole_deserialize() {
+        let test = TeleportHole {
+            offset: 4096,
+            length: 1 << 30,
+        };
+
+        let mut t = TeleportHole::new();
+        t.deserialize(TESTHOLE).expect("Test should never fail");
+
+        assert_eq!(test, t);
+    }
+
+    #[test]
+    fn test_teleportabort_roundtrip() {
+        let test = TeleportAbort::new(TeleportAbortCode::Cancelled, "The server is shutting down");
+
+        let out = test.serialize().expect("Test should never fail");
+        let mut t = TeleportAbort::default();
+        t.deserialize(&out).expect("Test should never fail");
+        assert_eq!(test, t);
+        assert!(
+            matches!(t.error(), TeleportError::RemoteCancelled(r) if r == "The server is shutting down")
+        );
+
+        // A truncated reason is refused
+        let mut t = TeleportAbort::default();
+        let err = t.deserialize(&out[..out.len() - 1]);
+        assert!(matches!(err, Err(TeleportError::InvalidAbort)));
+    }
+
+    #[test]
+    fn test_teleportabort_codes() {
+        let full = TeleportError::Io(io::Error::from_raw_os_error(libc::ENOSPC));
+        let abort = TeleportAbort::from_error(&full);
+        assert_eq!(abort.code, TeleportAbortCode::NoSpace as u8);
+        assert!(matches!(abort.error(), TeleportError::RemoteNoSpace(_)));
+
+        let denied = TeleportError::Io(io::Error::from(io::ErrorKind::PermissionDenied));
+        let abort = TeleportAbort::from_error(&denied);
+        assert!(matches!(
+            abort.error(),
+            TeleportError::RemoteNoPermission(_)
+        ));
+
+        // Codes from newer peers are still reported as aborts
+        let abort = TeleportAbort {
+            code: 0x7f,
+            reason: b"checksum mismatch".to_vec(),
+        };
+        assert!(
+            matches!(abort.error(), TeleportError::RemoteAborted(r) if r == "checksum mismatch")
+        );
+        assert!(abort.error().is_abort());
+    }
 }
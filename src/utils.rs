@@ -1,11 +1,18 @@
 use crate::errors::TeleportError;
-use crate::teleport::{TeleportAction, TeleportEnc, TeleportHeader, TeleportInit};
+use crate::teleport::TeleportInit;
+use crate::teleport::{TeleportAbort, TeleportAction, TeleportData, TeleportEnc, TeleportHeader};
 use crate::PROTOCOL;
 use byteorder::{LittleEndian, ReadBytesExt};
 use rand::prelude::*;
+use std::fs::File;
+use std::hash::Hasher;
 use std::io;
-use std::io::{Read, Write};
-use std::net::TcpStream;
+use std::io::{Read, Seek, Write};
+use std::net::{IpAddr, Shutdown, TcpStream};
+use std::os::unix::io::AsRawFd;
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use xxhash_rust::xxh3;
 
 pub fn print_updates(received: f64, header: &TeleportInit) {
     let units = UpdateUnit::update(received, header.filesize as f64);
@@ -65,6 +72,186 @@ impl SizeUnit {
     }
 }
 
+/// Format a size in bytes for display, e.g. `1.500M`
+pub fn format_size(size: u64) -> String {
+    let unit = SizeUnit::identify(size as f64);
+    format!("{:.03}{}", unit.value, unit.unit)
+}
+
+/// Check that resolving the relative `target` from directory `base` never
+/// leaves the directory `base` is relative to
+pub fn stays_within(base: &Path, target: &Path) -> bool {
+    join_within(base, target).is_some()
+}
+
+/// Resolve the relative `target` from directory `base` without touching the
+/// filesystem, or None if it leaves the directory `base` is relative to
+pub fn join_within(base: &Path, target: &Path) -> Option<PathBuf> {
+    if target.is_absolute() {
+        return None;
+    }
+
+    let mut out = PathBuf::new();
+    for c in base.components().chain(target.components()) {
+        match c {
+            Component::Normal(n) => out.push(n),
+            Component::ParentDir if !out.pop() => return None,
+            _ => (),
+        }
+    }
+
+    Some(out)
+}
+
+/// Report IPv4 clients of an IPv6 socket by their IPv4 address
+pub fn canonical_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        v4 => v4,
+    }
+}
+
+/// Hash the contents of a file with xxh3
+pub fn hash_file(mut file: &File) -> io::Result<u64> {
+    file.rewind()?;
+    let mut hasher = xxh3::Xxh3::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let len = file.read(&mut buf)?;
+        if len == 0 {
+            break;
+        }
+        hasher.write(&buf[..len]);
+    }
+
+    Ok(hasher.finish())
+}
+
+/// Format seconds since the epoch as a UTC date and time
+pub fn format_time(secs: u64) -> String {
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let time = secs % 86400;
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        time / 3600,
+        time / 60 % 60,
+        time % 60
+    )
+}
+
+/// Format seconds since the epoch as a compact UTC timestamp that sorts in
+/// time order, e.g. `20240131-235959`
+pub fn format_timestamp(secs: u64) -> String {
+    format_time(secs)
+        .chars()
+        .filter(|c| *c != '-' && *c != ':')
+        .map(|c| if c == ' ' { '-' } else { c })
+        .collect()
+}
+
+/// Parse a timestamp created by `format_timestamp`
+pub fn parse_timestamp(stamp: &str) -> Option<u64> {
+    let b = stamp.as_bytes();
+    if b.len() != 15 || b[8] != b'-' || !stamp.is_char_boundary(8) {
+        return None;
+    }
+    let num = |range: std::ops::Range<usize>| -> Option<i64> {
+        let s = &stamp[range];
+        match s.bytes().all(|c| c.is_ascii_digit()) {
+            true => s.parse().ok(),
+            false => None,
+        }
+    };
+
+    let (year, month, day) = (num(0..4)?, num(4..6)?, num(6..8)?);
+    let (hour, min, sec) = (num(9..11)?, num(11..13)?, num(13..15)?);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || min > 59 || sec > 59 {
+        return None;
+    }
+
+    let days = days_from_civil(year, month, day);
+    u64::try_from(days * 86400 + hour * 3600 + min * 60 + sec).ok()
+}
+
+// Conversions between days since the epoch and civil dates, see
+// http://howardhinnant.github.io/date_algorithms.html
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let doe = days - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+
+    (yoe + era * 400 + i64::from(month <= 2), month, day)
+}
+
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+
+    era * 146097 + doe - 719468
+}
+
+/// Turn the return value of a libc call into an io::Result
+pub fn cvt(ret: libc::c_int) -> io::Result<libc::c_int> {
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+/// Name of this machine, used when a listener is not given one
+pub fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    if unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) } != 0 {
+        return "teleporter".to_string();
+    }
+    let len = buf.iter().position(|c| *c == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).to_string()
+}
+
+/// Seconds since the epoch
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// Parse a size such as `4096`, `64K`, `10M` or `2G` (powers of 1024)
+pub fn parse_size(size: &str) -> Option<u64> {
+    let size = size.trim();
+    let split = size
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(size.len());
+    let (num, unit) = size.split_at(split);
+
+    let shift = match unit
+        .trim()
+        .to_ascii_uppercase()
+        .trim_end_matches(['B', 'I'])
+    {
+        "" => 0,
+        "K" => 10,
+        "M" => 20,
+        "G" => 30,
+        "T" => 40,
+        _ => return None,
+    };
+
+    num.parse::<u64>().ok()?.checked_mul(1 << shift)
+}
+
 pub fn send_packet(
     sock: &mut TcpStream,
     action: TeleportAction,
@@ -111,6 +298,10 @@ pub fn recv_packet(
         if len == 13 {
             break;
         }
+        // The peer closed the connection (or it was shut down locally)
+        if len == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
     }
 
     let mut init: &[u8] = &initbuf;
@@ -129,8 +320,7 @@ pub fn recv_packet(
         total_len += 12;
     }
 
-    let mut buf = Vec::<u8>::new();
-    buf.resize(total_len, 0);
+    let mut buf = vec![0; total_len];
 
     sock.read_exact(&mut buf)?;
 
@@ -144,5 +334,125 @@ pub fn recv_packet(
         }
     }
 
+    // The peer gave up on the transfer
+    if out.action == TeleportAction::Abort as u8 {
+        let mut abort = TeleportAbort::default();
+        abort.deserialize(&out.data)?;
+        return Err(abort.error());
+    }
+
     Ok(out)
 }
+
+/// How long to wait for the peer to hang up after aborting a transfer
+const ABORT_LINGER: Duration = Duration::from_secs(2);
+
+/// Tell the peer why the transfer is aborted, then wait for it to hang up so
+/// that unread data does not reset the connection before it reads the reason
+pub fn send_abort(sock: &mut TcpStream, enc: &Option<TeleportEnc>, abort: TeleportAbort) {
+    let sent = abort
+        .serialize()
+        .and_then(|data| send_packet(sock, TeleportAction::Abort, enc, data));
+    if sent.is_err() || sock.shutdown(Shutdown::Write).is_err() {
+        return;
+    }
+
+    let deadline = Instant::now() + ABORT_LINGER;
+    let mut buf = [0u8; 4096];
+    while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+        if sock.set_read_timeout(Some(left)).is_err() {
+            return;
+        }
+        match sock.read(&mut buf) {
+            Ok(0) | Err(_) => return,
+            Ok(_) => (),
+        }
+    }
+}
+
+/// Signal the end of the file data with an empty chunk at the end of the
+/// file. The server answers the same way once it stored the file.
+pub fn send_data_complete(
+    sock: &mut TcpStream,
+    enc: &Option<TeleportEnc>,
+    filesize: u64,
+) -> Result<(), TeleportError> {
+    let mut chunk = TeleportData {
+        offset: filesize,
+        data_len: 0,
+        data: Vec::<u8>::new(),
+    };
+
+    // Send the data chunk
+    send_packet(sock, TeleportAction::Data, enc, chunk.serialize()?)
+}
+
+/// Check, without blocking, whether the peer aborted the transfer or hung up
+pub fn check_abort(sock: &mut TcpStream, dec: &Option<TeleportEnc>) -> Result<(), TeleportError> {
+    let mut byte = [0u8; 1];
+    let pending = unsafe {
+        libc::recv(
+            sock.as_raw_fd(),
+            byte.as_mut_ptr() as *mut libc::c_void,
+            1,
+            libc::MSG_PEEK | libc::MSG_DONTWAIT,
+        )
+    };
+    if pending < 0 {
+        return Ok(());
+    }
+
+    // Nothing else is sent while a transfer is in progress
+    recv_packet(sock, dec).map(|_| ())
+}
+
+/// A directory for tests under the system temporary directory, removed with
+/// everything in it when dropped, so that failing tests clean up as well
+#[cfg(test)]
+pub struct TestDir(PathBuf);
+
+#[cfg(test)]
+impl TestDir {
+    pub fn create() -> TestDir {
+        let path = std::env::temp_dir().join(format!("teleporter-{:08x}", rand::random::<u32>()));
+        std::fs::create_dir_all(&path).expect("Test should never fail");
+        TestDir(path)
+    }
+}
+
+#[cfg(test)]
+impl std::ops::Deref for TestDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl AsRef<Path> for TestDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_time() {
+        // 2024-01-31 23:59:59 UTC
+        let secs = 1706745599;
+        assert_eq!(format_time(secs), "2024-01-31 23:59:59");
+        assert_eq!(format_timestamp(secs), "20240131-235959");
+        assert_eq!(parse_timestamp(&format_timestamp(secs)), Some(secs));
+    }
+}